@@ -1,7 +1,14 @@
-use std::env;
-use std::path::PathBuf;
-
 fn main() {
+    // The ioctl backend needs neither libevdi nor its bindings
+    #[cfg(all(feature = "libevdi", not(feature = "ioctl-backend")))]
+    generate_bindings();
+}
+
+#[cfg(all(feature = "libevdi", not(feature = "ioctl-backend")))]
+fn generate_bindings() {
+    use std::env;
+    use std::path::PathBuf;
+
     let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
     let evdi_root = manifest_dir.join("..").join("evdi_source");
     let library_dir = evdi_root.join("library");
@@ -11,6 +18,12 @@ fn main() {
     println!("cargo:rustc-link-lib=evdi");
     println!("cargo:rerun-if-changed=wrapper.h");
 
+    // libevdi logs through a C variadic callback
+    println!("cargo:rerun-if-changed=csrc/evdi_log.c");
+    cc::Build::new()
+        .file(manifest_dir.join("csrc").join("evdi_log.c"))
+        .compile("evdi_log");
+
     let bindings = bindgen::Builder::default()
         .header(header_path.to_string_lossy())
         .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()))
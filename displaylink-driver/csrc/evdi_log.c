@@ -0,0 +1,19 @@
+// Formats libevdi's printf-style log messages for the Rust logger; Rust
+// can't define C variadic functions.
+
+#include <stdarg.h>
+#include <stdio.h>
+
+void displaylink_evdi_log_message(void *user_data, const char *message);
+
+void displaylink_evdi_log(void *user_data, const char *fmt, ...)
+{
+	char message[1024];
+	va_list args;
+
+	va_start(args, fmt);
+	vsnprintf(message, sizeof(message), fmt, args);
+	va_end(args);
+
+	displaylink_evdi_log_message(user_data, message);
+}
@@ -0,0 +1,501 @@
+// Parallel band compression
+//
+// Splits a frame into horizontal bands and encodes each band on its own
+// worker thread. Every band carries its own damage rectangle so the device
+// places it at the right rows, and the band streams are concatenated in
+// top-to-bottom order.
+//
+// The workers are started once with the compressor and fed one band per
+// frame through bounded channels, so a frame costs no thread spawns and no
+// allocations. The calling thread encodes the first band itself. Regions
+// shorter than two bands are encoded on the calling thread alone, since
+// handing out a few rows costs more than it saves.
+
+use crate::color::ColorLut;
+use crate::dither::DitherMode;
+use crate::encoder::{Encoder, EncoderInput, EncoderKind, Rect};
+use crate::pixel_format::PixelFormat;
+use std::ptr;
+use std::slice;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Fewest rows worth giving a band of its own
+pub const MIN_BAND_ROWS: usize = 32;
+
+/// Per-thread encoder state, reused across frames
+struct BandWorker {
+    encoder: Box<dyn Encoder>,
+    stream: Vec<u8>,
+}
+
+impl BandWorker {
+    fn new(kind: EncoderKind) -> Self {
+        BandWorker {
+            encoder: kind.create(),
+            stream: Vec::new(),
+        }
+    }
+
+    /// Encode one band into this worker's stream buffer
+    fn encode_band(&mut self, input: &EncoderInput) {
+        self.stream.clear();
+        self.encoder.encode(input, &mut self.stream);
+    }
+}
+
+// An EncoderInput with its borrows erased, so it can cross to a pool thread.
+// compress() doesn't return until every job it handed out is done.
+struct BandJob {
+    data: *const u8,
+    len: usize,
+    stride: usize,
+    format: PixelFormat,
+    region: Rect,
+    dither: DitherMode,
+    color: *const ColorLut,
+}
+
+unsafe impl Send for BandJob {}
+
+impl BandJob {
+    fn new(input: &EncoderInput) -> Self {
+        BandJob {
+            data: input.data.as_ptr(),
+            len: input.data.len(),
+            stride: input.stride,
+            format: input.format,
+            region: input.region,
+            dither: input.dither,
+            color: input.color.map_or(ptr::null(), |color| color as *const _),
+        }
+    }
+
+    // Safety: the buffers the job was made from must still be borrowed
+    unsafe fn input(&self) -> EncoderInput<'_> {
+        EncoderInput {
+            data: slice::from_raw_parts(self.data, self.len),
+            stride: self.stride,
+            format: self.format,
+            region: self.region,
+            dither: self.dither,
+            color: self.color.as_ref(),
+        }
+    }
+}
+
+// Reports a job as done even if encoding panics, so compress() never
+// returns while a pool thread still reads the frame
+struct JobDone<'a>(&'a SyncSender<()>);
+
+impl Drop for JobDone<'_> {
+    fn drop(&mut self) {
+        let _ = self.0.send(());
+    }
+}
+
+struct PoolThread {
+    jobs: Option<SyncSender<BandJob>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+fn run_pool_thread(worker: Arc<Mutex<BandWorker>>, jobs: Receiver<BandJob>, done: SyncSender<()>) {
+    for job in jobs {
+        let _done = JobDone(&done);
+        let input = unsafe { job.input() };
+        worker.lock().unwrap().encode_band(&input);
+    }
+}
+
+/// Multi-threaded frame compressor
+pub struct BandCompressor {
+    kind: EncoderKind,
+    dither: DitherMode,
+    color: Option<Arc<ColorLut>>,
+    // Band i is encoded by workers[i]: the first on the calling thread, the
+    // rest on pool[i - 1]
+    workers: Vec<Arc<Mutex<BandWorker>>>,
+    pool: Vec<PoolThread>,
+    done: Receiver<()>,
+    output: Vec<u8>,
+}
+
+impl BandCompressor {
+    /// Create a compressor that uses up to `threads` worker threads
+    pub fn new(threads: usize, kind: EncoderKind) -> Self {
+        let threads = threads.max(1);
+        let workers: Vec<_> = (0..threads)
+            .map(|_| Arc::new(Mutex::new(BandWorker::new(kind))))
+            .collect();
+        let (done_tx, done) = sync_channel(threads);
+        let pool = workers[1..]
+            .iter()
+            .map(|worker| {
+                let (jobs, jobs_rx) = sync_channel(1);
+                let worker = worker.clone();
+                let done = done_tx.clone();
+                PoolThread {
+                    jobs: Some(jobs),
+                    handle: Some(thread::spawn(move || {
+                        run_pool_thread(worker, jobs_rx, done)
+                    })),
+                }
+            })
+            .collect();
+        BandCompressor {
+            kind,
+            dither: DitherMode::None,
+            color: None,
+            workers,
+            pool,
+            done,
+            output: Vec::new(),
+        }
+    }
+
+    /// Number of threads that encode a frame, the calling one included
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Codec used by the workers
+    pub fn kind(&self) -> EncoderKind {
+        self.kind
+    }
+
+    /// Dithering applied by 16bpp codecs
+    pub fn set_dither(&mut self, dither: DitherMode) {
+        self.dither = dither;
+    }
+
+    /// Color correction applied by all codecs
+    pub fn set_color(&mut self, color: Option<Arc<ColorLut>>) {
+        self.color = color;
+    }
+
+    /// Switch all workers to a different codec
+    pub fn set_encoder(&mut self, kind: EncoderKind) {
+        if kind == self.kind {
+            return;
+        }
+        self.kind = kind;
+        for worker in &self.workers {
+            worker.lock().unwrap().encoder = kind.create();
+        }
+    }
+
+    /// Encode a region of a frame into a command stream of per-band updates
+    pub fn compress(
+        &mut self,
+        framebuffer: &[u8],
+        stride: usize,
+        format: PixelFormat,
+        region: Rect,
+    ) -> &[u8] {
+        self.output.clear();
+        if region.is_empty() {
+            return &self.output;
+        }
+
+        let height = region.height;
+        let bands = self.workers.len().min(height / MIN_BAND_ROWS).max(1);
+        let band_rows = height.div_ceil(bands);
+        let color = self.color.as_deref();
+        let band_input = |index: usize| {
+            let offset = (index * band_rows).min(height);
+            EncoderInput {
+                data: framebuffer,
+                stride,
+                format,
+                region: Rect::new(
+                    region.x,
+                    region.y + offset,
+                    region.width,
+                    band_rows.min(height - offset),
+                ),
+                dither: self.dither,
+                color,
+            }
+        };
+
+        let mut handed_out = 0;
+        for (index, thread) in self.pool[..bands - 1].iter().enumerate() {
+            let input = band_input(index + 1);
+            if input.region.is_empty() {
+                self.workers[index + 1].lock().unwrap().stream.clear();
+                continue;
+            }
+            let jobs = thread.jobs.as_ref().unwrap();
+            if jobs.send(BandJob::new(&input)).is_ok() {
+                handed_out += 1;
+            } else {
+                // Pool thread is gone; encode the band here instead
+                self.workers[index + 1].lock().unwrap().encode_band(&input);
+            }
+        }
+        self.workers[0].lock().unwrap().encode_band(&band_input(0));
+        // The frame stays borrowed until every pool thread is done with it
+        for _ in 0..handed_out {
+            let _ = self.done.recv();
+        }
+
+        for worker in &self.workers[..bands] {
+            self.output
+                .extend_from_slice(&worker.lock().unwrap().stream);
+        }
+
+        &self.output
+    }
+
+    /// Append a command to the stream produced by the last `compress`
+    pub fn append(&mut self, command: &[u8]) {
+        self.output.extend_from_slice(command);
+    }
+
+    /// Stream produced by the last `compress`, including appended commands
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+}
+
+impl Drop for BandCompressor {
+    fn drop(&mut self) {
+        // Closing the job channels ends the pool threads
+        for thread in &mut self.pool {
+            thread.jobs.take();
+        }
+        for thread in &mut self.pool {
+            if let Some(handle) = thread.handle.take() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::displaylink_protocol::{
+        CommandBuilder, DL_CODEC_RAW16, DL_CODEC_RLE, DL_REG_DAMAGE_CODEC, DL_REG_DAMAGE_HEIGHT,
+        DL_REG_DAMAGE_WIDTH, DL_REG_DAMAGE_X, DL_REG_DAMAGE_Y,
+    };
+    use crate::snapshot::decode_stream;
+
+    fn solid_frame(width: usize, height: usize, bgra: [u8; 4]) -> Vec<u8> {
+        bgra.iter()
+            .copied()
+            .cycle()
+            .take(width * height * 4)
+            .collect()
+    }
+
+    #[test]
+    fn test_bands_are_addressed_in_order() {
+        let mut compressor = BandCompressor::new(2, EncoderKind::Rle);
+        let height = 2 * MIN_BAND_ROWS;
+        let frame = solid_frame(4, height, [0, 0, 255, 255]);
+        let stream = compressor
+            .compress(
+                &frame,
+                16,
+                PixelFormat::Xrgb8888,
+                Rect::new(0, 0, 4, height),
+            )
+            .to_vec();
+
+        let mut builder = CommandBuilder::new();
+        let first = builder
+            .damage_rect(DL_CODEC_RLE, 0, 0, 4, MIN_BAND_ROWS as u16)
+            .to_vec();
+        let second = builder
+            .damage_rect(
+                DL_CODEC_RLE,
+                0,
+                MIN_BAND_ROWS as u16,
+                4,
+                MIN_BAND_ROWS as u16,
+            )
+            .to_vec();
+        // Each band row is one 4-pixel run of red
+        let payload = [4, 0x00, 0xF8].repeat(MIN_BAND_ROWS);
+
+        let mut expected = first;
+        expected.extend_from_slice(&payload);
+        expected.extend_from_slice(&second);
+        expected.extend_from_slice(&payload);
+        assert_eq!(stream, expected);
+    }
+
+    #[test]
+    fn test_short_region_is_one_band() {
+        let mut compressor = BandCompressor::new(4, EncoderKind::Rle);
+        let frame = solid_frame(4, 4, [0, 0, 255, 255]);
+        let stream = compressor
+            .compress(&frame, 16, PixelFormat::Xrgb8888, Rect::new(0, 0, 4, 4))
+            .to_vec();
+
+        let mut expected = CommandBuilder::new()
+            .damage_rect(DL_CODEC_RLE, 0, 0, 4, 4)
+            .to_vec();
+        expected.extend_from_slice(&[4, 0x00, 0xF8].repeat(4));
+        assert_eq!(stream, expected);
+    }
+
+    #[test]
+    fn test_parallel_matches_single_thread_payload() {
+        let width = 16;
+        let height = 3 * MIN_BAND_ROWS + 5;
+        let frame: Vec<u8> = (0..width * height * 4).map(|i| (i / 12) as u8).collect();
+
+        let mut single = BandCompressor::new(1, EncoderKind::Rle);
+        let mut parallel = BandCompressor::new(3, EncoderKind::Rle);
+        let single_stream = single
+            .compress(
+                &frame,
+                width * 4,
+                PixelFormat::Xrgb8888,
+                Rect::new(0, 0, width, height),
+            )
+            .to_vec();
+        let parallel_stream = parallel
+            .compress(
+                &frame,
+                width * 4,
+                PixelFormat::Xrgb8888,
+                Rect::new(0, 0, width, height),
+            )
+            .to_vec();
+
+        // Band boundaries restart runs and add damage rects, never lose data
+        assert_eq!(parallel.threads(), 3);
+        let single_image = decode_stream(&single_stream, width, height).unwrap();
+        let parallel_image = decode_stream(&parallel_stream, width, height).unwrap();
+        assert!(single_image.pixels.iter().all(|px| px[3] == 0xFF));
+        assert_eq!(parallel_image, single_image);
+
+        let band_rows = height.div_ceil(3);
+        assert_eq!(damage_rows(&single_stream, width), vec![(0, height)]);
+        assert_eq!(
+            damage_rows(&parallel_stream, width),
+            vec![
+                (0, band_rows),
+                (band_rows, band_rows),
+                (2 * band_rows, height - 2 * band_rows)
+            ]
+        );
+    }
+
+    // (y, height) of every full-width RLE damage rectangle in a stream
+    fn damage_rows(stream: &[u8], width: usize) -> Vec<(usize, usize)> {
+        let reg = |address: u16, value: usize| {
+            let mut bytes = vec![0xAF, 0x20];
+            bytes.extend_from_slice(&address.to_le_bytes());
+            bytes.extend_from_slice(&(value as u16).to_le_bytes());
+            bytes
+        };
+        let prefix = [
+            reg(DL_REG_DAMAGE_CODEC, DL_CODEC_RLE as usize),
+            reg(DL_REG_DAMAGE_X, 0),
+        ]
+        .concat();
+        let mut rows = Vec::new();
+        for (pos, window) in stream.windows(prefix.len() + 18).enumerate() {
+            if !window.starts_with(&prefix) {
+                continue;
+            }
+            let value = |i: usize| u16::from_le_bytes([window[i], window[i + 1]]) as usize;
+            let y = value(prefix.len() + 4);
+            let height = value(prefix.len() + 16);
+            let expected = [
+                prefix.clone(),
+                reg(DL_REG_DAMAGE_Y, y),
+                reg(DL_REG_DAMAGE_WIDTH, width),
+                reg(DL_REG_DAMAGE_HEIGHT, height),
+            ]
+            .concat();
+            assert_eq!(
+                window,
+                &expected[..],
+                "malformed damage rect at byte {}",
+                pos
+            );
+            rows.push((y, height));
+        }
+        rows
+    }
+
+    #[test]
+    fn test_more_threads_than_rows() {
+        let mut compressor = BandCompressor::new(8, EncoderKind::Rle);
+        let frame = solid_frame(4, 2, [255, 255, 255, 255]);
+        let stream = compressor.compress(&frame, 16, PixelFormat::Xrgb8888, Rect::new(0, 0, 4, 2));
+        assert!(!stream.is_empty());
+    }
+
+    #[test]
+    fn test_set_encoder_switches_codec() {
+        let mut compressor = BandCompressor::new(2, EncoderKind::Rle);
+        compressor.set_encoder(EncoderKind::Raw16);
+        assert_eq!(compressor.kind(), EncoderKind::Raw16);
+
+        let height = 2 * MIN_BAND_ROWS;
+        let frame = solid_frame(4, height, [0, 0, 255, 255]);
+        let header = CommandBuilder::new().damage_rect(0, 0, 0, 0, 0).len();
+        let stream = compressor.compress(
+            &frame,
+            16,
+            PixelFormat::Xrgb8888,
+            Rect::new(0, 0, 4, height),
+        );
+        assert_eq!(stream.len(), 2 * header + 4 * height * 2);
+    }
+
+    #[test]
+    fn test_damaged_region_only() {
+        let mut compressor = BandCompressor::new(2, EncoderKind::Raw16);
+        let height = 2 * MIN_BAND_ROWS + 8;
+        let frame = solid_frame(8, height, [0, 0, 255, 255]);
+        let stream = compressor
+            .compress(
+                &frame,
+                32,
+                PixelFormat::Xrgb8888,
+                Rect::new(2, 3, 4, 2 * MIN_BAND_ROWS),
+            )
+            .to_vec();
+
+        let rows = MIN_BAND_ROWS as u16;
+        let mut builder = CommandBuilder::new();
+        let first = builder.damage_rect(DL_CODEC_RAW16, 2, 3, 4, rows).to_vec();
+        let second = builder
+            .damage_rect(DL_CODEC_RAW16, 2, 3 + rows, 4, rows)
+            .to_vec();
+        let band = 4 * MIN_BAND_ROWS * 2;
+        assert_eq!(&stream[..first.len()], &first[..]);
+        let offset = first.len() + band;
+        assert_eq!(&stream[offset..offset + second.len()], &second[..]);
+        assert_eq!(stream.len(), 2 * (first.len() + band));
+    }
+
+    #[test]
+    fn test_output_buffer_is_reused_across_frames() {
+        let mut compressor = BandCompressor::new(2, EncoderKind::Rle);
+        let height = 2 * MIN_BAND_ROWS;
+        let frame = solid_frame(8, height, [0, 0, 255, 255]);
+        let region = Rect::new(0, 0, 8, height);
+        let sync = CommandBuilder::new().sync().to_vec();
+
+        compressor.compress(&frame, 32, PixelFormat::Xrgb8888, region);
+        compressor.append(&sync);
+        let first = compressor.output().to_vec();
+        let first_ptr = compressor.output().as_ptr();
+        assert!(first.ends_with(&sync));
+
+        // Same-sized frame encodes into the same allocation
+        compressor.compress(&frame, 32, PixelFormat::Xrgb8888, region);
+        compressor.append(&sync);
+        assert_eq!(compressor.output().as_ptr(), first_ptr);
+        assert_eq!(compressor.output(), &first[..]);
+    }
+}
@@ -0,0 +1,137 @@
+// DisplayLink device capabilities
+//
+// Describes what a given DisplayLink chip can do, so codec selection and
+// mode limits can be derived per device instead of being hardwired.
+
+use rusb::Speed;
+
+/// StarTech USB35DOCK (DL-3xxx series)
+pub const PID_DL3XXX: u16 = 0x4307;
+
+/// Highest refresh rate the chips scan out
+const MAX_REFRESH_RATE: u64 = 60;
+
+/// Share of raw pixel bytes left after compression on typical desktop
+/// content
+const TYPICAL_COMPRESSION_RATIO: u64 = 12;
+
+/// Bulk payload the link sustains in practice, in bytes per second
+fn link_bytes_per_second(speed: Speed) -> u64 {
+    match speed {
+        Speed::Low => 150_000,
+        Speed::Full => 1_000_000,
+        // Also assumed when the kernel doesn't report a speed
+        Speed::High | Speed::Unknown => 35_000_000,
+        Speed::Super => 400_000_000,
+        _ => 800_000_000,
+    }
+}
+
+/// Largest modes the kernel should offer the compositor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModeLimits {
+    /// Maximum width × height
+    pub pixel_area: u32,
+    /// Maximum width × height × refresh rate
+    pub pixels_per_second: u32,
+}
+
+/// Feature set of a DisplayLink device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceCapabilities {
+    pub product_id: u16,
+    /// Maximum horizontal resolution
+    pub max_width: u32,
+    /// Maximum vertical resolution
+    pub max_height: u32,
+    /// RLE/RLX compressed pixel runs
+    pub supports_rle: bool,
+    /// Huffman-coded pixel deltas
+    pub supports_huffman: bool,
+    /// 24 bits per pixel output
+    pub supports_24bpp: bool,
+    /// Side of the square hardware cursor plane, if the chip has one
+    pub hw_cursor_size: Option<u32>,
+}
+
+impl DeviceCapabilities {
+    /// Look up capabilities for a DisplayLink product ID
+    pub fn for_product(product_id: u16) -> Self {
+        match product_id {
+            // Huffman and 24bpp output are unconfirmed on this chip, so it
+            // stays on the RLE framing udlfb uses
+            PID_DL3XXX => DeviceCapabilities {
+                product_id,
+                max_width: 2560,
+                max_height: 1600,
+                supports_rle: true,
+                supports_huffman: false,
+                supports_24bpp: false,
+                hw_cursor_size: Some(64),
+            },
+            // Unknown chips: stick to the baseline 16bpp feature set
+            _ => DeviceCapabilities {
+                product_id,
+                max_width: 1920,
+                max_height: 1200,
+                supports_rle: true,
+                supports_huffman: false,
+                supports_24bpp: false,
+                hw_cursor_size: None,
+            },
+        }
+    }
+
+    /// Mode limits for this chip on a link of the given speed
+    ///
+    /// The area limit comes from the chip; the rate limit is whichever is
+    /// lower of what the chip scans out and what the link carries at the
+    /// deepest color depth the chip supports.
+    pub fn mode_limits(&self, speed: Speed) -> ModeLimits {
+        let pixel_area = self.max_width as u64 * self.max_height as u64;
+        let bytes_per_pixel = if self.supports_24bpp { 3 } else { 2 };
+        let link_rate = link_bytes_per_second(speed) * TYPICAL_COMPRESSION_RATIO / bytes_per_pixel;
+        let pixels_per_second = link_rate.min(pixel_area * MAX_REFRESH_RATE);
+        ModeLimits {
+            pixel_area: pixel_area.min(u32::MAX as u64) as u32,
+            pixels_per_second: pixels_per_second.min(u32::MAX as u64) as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_and_unknown_products() {
+        let dl3 = DeviceCapabilities::for_product(PID_DL3XXX);
+        assert!(dl3.supports_rle);
+        assert!(!dl3.supports_huffman);
+        assert!(!dl3.supports_24bpp);
+        assert_eq!(dl3.hw_cursor_size, Some(64));
+
+        let unknown = DeviceCapabilities::for_product(0x0001);
+        assert!(unknown.supports_rle);
+        assert!(!unknown.supports_huffman);
+        assert!(!unknown.supports_24bpp);
+        assert_eq!(unknown.hw_cursor_size, None);
+    }
+
+    #[test]
+    fn test_mode_limits_follow_link_speed() {
+        let dl3 = DeviceCapabilities::for_product(PID_DL3XXX);
+
+        // SuperSpeed carries the chip's largest mode at full rate
+        let superspeed = dl3.mode_limits(Speed::Super);
+        assert_eq!(superspeed.pixel_area, 2560 * 1600);
+        assert_eq!(superspeed.pixels_per_second, 2560 * 1600 * 60);
+
+        // High speed fits 1080p60 but not 2560x1600 at 60Hz
+        let high = dl3.mode_limits(Speed::High);
+        assert_eq!(high.pixel_area, 2560 * 1600);
+        assert!(high.pixels_per_second >= 1920 * 1080 * 60);
+        assert!(high.pixels_per_second < 2560 * 1600 * 60);
+        assert_eq!(dl3.mode_limits(Speed::Unknown), high);
+    }
+}
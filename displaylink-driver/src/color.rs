@@ -0,0 +1,466 @@
+// Per-output color correction
+//
+// EVDI has no gamma LUT path, so desktops can't color-manage DisplayLink
+// outputs. Instead each device can have a 3×1D lookup table that the encoders
+// apply while converting pixels to the device format. Tables are built from
+// gamma, brightness, contrast and white-point settings, loaded from a 1D
+// `.cube` file or the VCGT tag of an ICC profile, or both: the settings are
+// then applied on top of the loaded curves.
+
+use std::fs;
+use std::path::Path;
+
+/// Number of entries in each channel's table
+pub const LUT_SIZE: usize = 256;
+
+/// Accepted color temperatures in Kelvin
+pub const TEMPERATURE_RANGE: std::ops::RangeInclusive<u32> = 1000..=40000;
+
+/// White point of sRGB; no correction at this temperature
+pub const NEUTRAL_TEMPERATURE: u32 = 6500;
+
+/// Red, green and blue lookup tables
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColorLut {
+    channels: [[u8; LUT_SIZE]; 3],
+}
+
+impl ColorLut {
+    pub fn identity() -> Self {
+        Self::from_fn(|_, x| x)
+    }
+
+    /// Build a table from a curve mapping each channel's input level to its
+    /// output level, both in 0.0..=1.0
+    pub fn from_fn(curve: impl Fn(usize, f64) -> f64) -> Self {
+        let mut channels = [[0u8; LUT_SIZE]; 3];
+        for (channel, table) in channels.iter_mut().enumerate() {
+            for (level, entry) in table.iter_mut().enumerate() {
+                let x = level as f64 / (LUT_SIZE - 1) as f64;
+                *entry = to_level(curve(channel, x));
+            }
+        }
+        ColorLut { channels }
+    }
+
+    /// Resample per-channel curves of any length
+    pub fn from_curves(curves: [&[f64]; 3]) -> Self {
+        Self::from_fn(|channel, x| interpolate(curves[channel], x))
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Self::identity()
+    }
+
+    pub fn apply(&self, rgb: [u8; 3]) -> [u8; 3] {
+        [0, 1, 2].map(|c| self.channels[c][rgb[c] as usize])
+    }
+
+    /// This table followed by `next`
+    pub fn then(&self, next: &ColorLut) -> ColorLut {
+        let mut channels = self.channels;
+        for (table, next) in channels.iter_mut().zip(&next.channels) {
+            for entry in table.iter_mut() {
+                *entry = next[*entry as usize];
+            }
+        }
+        ColorLut { channels }
+    }
+
+    /// Build a table from a correction spec, as used by DISPLAYLINK_COLOR and
+    /// the control socket
+    ///
+    /// The spec is a list of `key=value` settings separated by spaces or
+    /// commas: `gamma`, `brightness`, `contrast`, `temperature`, and `lut`
+    /// naming a `.cube` file or ICC profile to start from.
+    pub fn parse_spec(spec: &str) -> Result<Self, String> {
+        let mut settings = ColorSettings::default();
+        let mut base = None;
+        for token in spec
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|token| !token.is_empty())
+        {
+            let (key, value) = token
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got '{}'", token))?;
+            let number = || {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|v| v.is_finite())
+                    .ok_or_else(|| format!("invalid {} '{}'", key, value))
+            };
+            match key.to_ascii_lowercase().as_str() {
+                "gamma" => settings.gamma = number()?,
+                "brightness" => settings.brightness = number()?,
+                "contrast" => settings.contrast = number()?,
+                "temperature" => {
+                    settings.temperature = value
+                        .trim_end_matches(['K', 'k'])
+                        .parse()
+                        .map_err(|_| format!("invalid temperature '{}'", value))?
+                }
+                "lut" => base = Some(Self::load(Path::new(value))?),
+                _ => return Err(format!("unknown color setting '{}'", key)),
+            }
+        }
+        let adjust = settings.lut()?;
+        Ok(match base {
+            Some(base) => base.then(&adjust),
+            None => adjust,
+        })
+    }
+
+    /// Load a `.cube` file or an ICC profile's VCGT tag
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes =
+            fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let lut = if bytes.get(36..40) == Some(b"acsp") {
+            Self::from_icc_vcgt(&bytes)
+        } else {
+            String::from_utf8(bytes)
+                .map_err(|_| "not a .cube file or ICC profile".to_string())
+                .and_then(|text| Self::from_cube(&text))
+        };
+        lut.map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Parse a 1D `.cube` LUT
+    pub fn from_cube(text: &str) -> Result<Self, String> {
+        let mut size = None;
+        let mut domain = ([0.0f64; 3], [1.0f64; 3]);
+        let mut rows: Vec<[f64; 3]> = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let mut fields = line.split_whitespace();
+            let Some(first) = fields.next() else {
+                continue;
+            };
+            let values = |fields: std::str::SplitWhitespace| -> Result<Vec<f64>, String> {
+                fields
+                    .map(|f| f.parse().map_err(|_| format!("invalid number '{}'", f)))
+                    .collect()
+            };
+            match first {
+                "TITLE" => {}
+                "LUT_3D_SIZE" => return Err("3D LUTs are not supported".to_string()),
+                "LUT_1D_SIZE" => {
+                    size = match fields.next().and_then(|n| n.parse::<usize>().ok()) {
+                        Some(n) if n >= 2 => Some(n),
+                        _ => return Err(format!("invalid LUT_1D_SIZE in '{}'", line)),
+                    };
+                }
+                "DOMAIN_MIN" | "DOMAIN_MAX" => {
+                    let bound: [f64; 3] = values(fields)?
+                        .try_into()
+                        .map_err(|_| format!("{} needs three values", first))?;
+                    if first == "DOMAIN_MIN" {
+                        domain.0 = bound;
+                    } else {
+                        domain.1 = bound;
+                    }
+                }
+                "LUT_1D_INPUT_RANGE" => {
+                    let [min, max]: [f64; 2] = values(fields)?
+                        .try_into()
+                        .map_err(|_| "LUT_1D_INPUT_RANGE needs two values".to_string())?;
+                    domain = ([min; 3], [max; 3]);
+                }
+                _ if first.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                    return Err(format!("unsupported keyword {}", first));
+                }
+                _ => {
+                    let row: [f64; 3] = values(line.split_whitespace())?
+                        .try_into()
+                        .map_err(|_| format!("expected three values in '{}'", line))?;
+                    rows.push(row);
+                }
+            }
+        }
+
+        let size = size.ok_or("missing LUT_1D_SIZE")?;
+        if rows.len() != size {
+            return Err(format!("expected {} entries, found {}", size, rows.len()));
+        }
+        let (min, max) = domain;
+        if (0..3).any(|c| max[c] <= min[c]) {
+            return Err("empty domain".to_string());
+        }
+        let curves: Vec<Vec<f64>> = (0..3)
+            .map(|c| rows.iter().map(|row| row[c]).collect())
+            .collect();
+        // Inputs outside the domain clamp to its ends
+        Ok(Self::from_fn(|c, x| {
+            let position = ((x - min[c]) / (max[c] - min[c])).clamp(0.0, 1.0);
+            interpolate(&curves[c], position)
+        }))
+    }
+
+    /// Read the video card gamma table (VCGT tag) of an ICC profile
+    pub fn from_icc_vcgt(profile: &[u8]) -> Result<Self, String> {
+        let u16_at = |data: &[u8], at: usize| -> Result<u16, String> {
+            data.get(at..at + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .ok_or_else(|| "truncated profile".to_string())
+        };
+        let u32_at = |data: &[u8], at: usize| -> Result<u32, String> {
+            data.get(at..at + 4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or_else(|| "truncated profile".to_string())
+        };
+
+        // Each tag table entry takes 12 bytes after the count
+        let tag_count = u32_at(profile, ICC_HEADER_SIZE)? as usize;
+        let max_tags = (profile.len() - ICC_HEADER_SIZE - 4) / 12;
+        if tag_count > max_tags {
+            return Err(format!(
+                "profile claims {} tags but has room for {}",
+                tag_count, max_tags
+            ));
+        }
+        let tag = (0..tag_count)
+            .map(|i| ICC_HEADER_SIZE + 4 + i * 12)
+            .find(|&entry| profile.get(entry..entry + 4) == Some(b"vcgt"))
+            .ok_or("profile has no VCGT tag")?;
+        let offset = u32_at(profile, tag + 4)? as usize;
+        let length = u32_at(profile, tag + 8)? as usize;
+        let vcgt = profile
+            .get(offset..offset.saturating_add(length))
+            .ok_or("truncated profile")?;
+
+        match u32_at(vcgt, 8)? {
+            VCGT_TABLE => {
+                let channels = u16_at(vcgt, 12)? as usize;
+                let entries = u16_at(vcgt, 14)? as usize;
+                let entry_size = u16_at(vcgt, 16)? as usize;
+                if !matches!(channels, 1 | 3) || entries < 2 || !matches!(entry_size, 1 | 2) {
+                    return Err("unsupported VCGT table layout".to_string());
+                }
+                let data = vcgt
+                    .get(18..18 + channels * entries * entry_size)
+                    .ok_or("truncated VCGT table")?;
+                let full_scale = if entry_size == 1 { 255.0 } else { 65535.0 };
+                let curves: Vec<Vec<f64>> = data
+                    .chunks_exact(entries * entry_size)
+                    .map(|channel| {
+                        channel
+                            .chunks_exact(entry_size)
+                            .map(|v| match *v {
+                                [v] => v as f64 / full_scale,
+                                [hi, lo] => u16::from_be_bytes([hi, lo]) as f64 / full_scale,
+                                _ => unreachable!(),
+                            })
+                            .collect()
+                    })
+                    .collect();
+                let curve = |c: usize| curves[c.min(channels - 1)].as_slice();
+                Ok(Self::from_curves([curve(0), curve(1), curve(2)]))
+            }
+            VCGT_FORMULA => {
+                // Gamma, minimum and maximum per channel, as s15Fixed16
+                let mut params = [[0.0f64; 3]; 3];
+                for (i, value) in params.iter_mut().flatten().enumerate() {
+                    *value = u32_at(vcgt, 12 + i * 4)? as i32 as f64 / 65536.0;
+                }
+                Ok(Self::from_fn(|c, x| {
+                    let [gamma, min, max] = params[c];
+                    min + (max - min) * x.powf(gamma)
+                }))
+            }
+            kind => Err(format!("unknown VCGT type {}", kind)),
+        }
+    }
+}
+
+const ICC_HEADER_SIZE: usize = 128;
+const VCGT_TABLE: u32 = 0;
+const VCGT_FORMULA: u32 = 1;
+
+/// Correction controls
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorSettings {
+    /// Like `xrandr --gamma`: above 1 brightens mid-tones
+    pub gamma: f64,
+    /// Offset added to every level, -1.0..=1.0
+    pub brightness: f64,
+    /// Slope around mid-grey; 1.0 leaves it alone
+    pub contrast: f64,
+    /// White point in Kelvin
+    pub temperature: u32,
+}
+
+impl Default for ColorSettings {
+    fn default() -> Self {
+        ColorSettings {
+            gamma: 1.0,
+            brightness: 0.0,
+            contrast: 1.0,
+            temperature: NEUTRAL_TEMPERATURE,
+        }
+    }
+}
+
+impl ColorSettings {
+    pub fn lut(&self) -> Result<ColorLut, String> {
+        if !(0.1..=10.0).contains(&self.gamma) {
+            return Err(format!("gamma {} out of range 0.1-10", self.gamma));
+        }
+        if !(-1.0..=1.0).contains(&self.brightness) {
+            return Err(format!("brightness {} out of range -1-1", self.brightness));
+        }
+        if !(0.0..=10.0).contains(&self.contrast) {
+            return Err(format!("contrast {} out of range 0-10", self.contrast));
+        }
+        if !TEMPERATURE_RANGE.contains(&self.temperature) {
+            return Err(format!(
+                "temperature {}K out of range {}-{}K",
+                self.temperature,
+                TEMPERATURE_RANGE.start(),
+                TEMPERATURE_RANGE.end()
+            ));
+        }
+        let gains = white_point_gains(self.temperature);
+        Ok(ColorLut::from_fn(|c, x| {
+            let x = ((x - 0.5) * self.contrast + 0.5 + self.brightness).clamp(0.0, 1.0);
+            x.powf(1.0 / self.gamma) * gains[c]
+        }))
+    }
+}
+
+/// Channel gains that move white to a blackbody color, relative to 6500K
+///
+/// Uses Tanner Helland's fit of the blackbody curve. The brightest channel
+/// stays at full scale so whites never clip.
+pub fn white_point_gains(temperature: u32) -> [f64; 3] {
+    let blackbody = |kelvin: u32| -> [f64; 3] {
+        let t = kelvin as f64 / 100.0;
+        let red = if t <= 66.0 {
+            255.0
+        } else {
+            329.698727446 * (t - 60.0).powf(-0.1332047592)
+        };
+        let green = if t <= 66.0 {
+            99.4708025861 * t.ln() - 161.1195681661
+        } else {
+            288.1221695283 * (t - 60.0).powf(-0.0755148492)
+        };
+        let blue = if t >= 66.0 {
+            255.0
+        } else if t <= 19.0 {
+            0.0
+        } else {
+            138.5177312231 * (t - 10.0).ln() - 305.0447927307
+        };
+        [red, green, blue].map(|v| v.clamp(0.0, 255.0))
+    };
+    let target = blackbody(temperature);
+    let neutral = blackbody(NEUTRAL_TEMPERATURE);
+    let gains = [0, 1, 2].map(|c| target[c] / neutral[c]);
+    let peak = gains.iter().cloned().fold(f64::MIN, f64::max);
+    gains.map(|gain| gain / peak)
+}
+
+// Linearly interpolated value of `curve` at `x` in 0.0..=1.0
+fn interpolate(curve: &[f64], x: f64) -> f64 {
+    let position = x.clamp(0.0, 1.0) * (curve.len() - 1) as f64;
+    let index = (position as usize).min(curve.len() - 2);
+    let weight = position - index as f64;
+    curve[index] * (1.0 - weight) + curve[index + 1] * weight
+}
+
+fn to_level(value: f64) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_settings_are_identity() {
+        assert!(ColorSettings::default().lut().unwrap().is_identity());
+        assert!(ColorLut::parse_spec("").unwrap().is_identity());
+        assert_eq!(white_point_gains(NEUTRAL_TEMPERATURE), [1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_settings_shape_the_curves() {
+        let lut = ColorLut::parse_spec("gamma=2.2").unwrap();
+        assert_eq!(lut.apply([0, 128, 255]), [0, 186, 255]);
+
+        let lut = ColorLut::parse_spec("brightness=0.1, contrast=2").unwrap();
+        // (64/255 - 0.5) * 2 + 0.5 + 0.1 ≈ 0.1
+        assert_eq!(lut.apply([64, 128, 255]), [26, 154, 255]);
+
+        // Warm white keeps red and dims blue
+        let warm = ColorLut::parse_spec("temperature=3400K")
+            .unwrap()
+            .apply([255; 3]);
+        assert_eq!(warm[0], 255);
+        assert!(warm[1] < 255 && warm[2] < warm[1]);
+
+        assert!(ColorLut::parse_spec("gamma=0").is_err());
+        assert!(ColorLut::parse_spec("temperature=100").is_err());
+        assert!(ColorLut::parse_spec("saturation=2").is_err());
+        assert!(ColorLut::parse_spec("gamma").is_err());
+    }
+
+    #[test]
+    fn test_cube_file() {
+        let cube = "# Inverted red\nTITLE \"test\"\nLUT_1D_SIZE 3\n\
+                    1.0 0.0 0.0\n0.5 0.5 0.25\n0.0 1.0 1.0\n";
+        let lut = ColorLut::from_cube(cube).unwrap();
+        assert_eq!(lut.apply([0, 0, 0]), [255, 0, 0]);
+        assert_eq!(lut.apply([255, 255, 255]), [0, 255, 255]);
+        // Halfway along the blue curve's first segment
+        assert_eq!(lut.apply([64, 64, 64])[2], 32);
+
+        assert!(ColorLut::from_cube("LUT_3D_SIZE 17\n").is_err());
+        assert!(ColorLut::from_cube("LUT_1D_SIZE 3\n0 0 0\n1 1 1\n").is_err());
+
+        // Settings apply on top of a loaded table
+        let base = ColorLut::from_cube(cube).unwrap();
+        let dimmed = base.then(&ColorLut::parse_spec("brightness=-1").unwrap());
+        assert_eq!(dimmed.apply([0, 255, 255]), [0, 0, 0]);
+    }
+
+    fn icc_profile(vcgt: &[u8]) -> Vec<u8> {
+        let mut profile = vec![0u8; ICC_HEADER_SIZE];
+        profile[36..40].copy_from_slice(b"acsp");
+        profile.extend_from_slice(&1u32.to_be_bytes());
+        let offset = ICC_HEADER_SIZE + 4 + 12;
+        profile.extend_from_slice(b"vcgt");
+        profile.extend_from_slice(&(offset as u32).to_be_bytes());
+        profile.extend_from_slice(&(vcgt.len() as u32).to_be_bytes());
+        profile.extend_from_slice(vcgt);
+        profile
+    }
+
+    #[test]
+    fn test_icc_vcgt_table_and_formula() {
+        // One inverting 16-bit curve shared by all channels
+        let mut table = b"vcgt\0\0\0\0".to_vec();
+        table.extend_from_slice(&VCGT_TABLE.to_be_bytes());
+        for value in [1u16, 3, 2, 0xFFFF, 0x8000, 0] {
+            table.extend_from_slice(&value.to_be_bytes());
+        }
+        let lut = ColorLut::from_icc_vcgt(&icc_profile(&table)).unwrap();
+        assert_eq!(lut.apply([0, 64, 255]), [255, 191, 0]);
+
+        // Gamma 1, and red limited to 0..0.5
+        let mut formula = b"vcgt\0\0\0\0".to_vec();
+        formula.extend_from_slice(&VCGT_FORMULA.to_be_bytes());
+        for value in [1.0, 0.0, 0.5, 1.0, 0.0, 1.0, 1.0, 0.0, 1.0] {
+            formula.extend_from_slice(&((value * 65536.0) as u32).to_be_bytes());
+        }
+        let lut = ColorLut::from_icc_vcgt(&icc_profile(&formula)).unwrap();
+        assert_eq!(lut.apply([255, 255, 100]), [128, 255, 100]);
+
+        assert!(ColorLut::from_icc_vcgt(&icc_profile(b"")).is_err());
+
+        // A tag count the tag table can't hold
+        let mut profile = icc_profile(&table);
+        profile[ICC_HEADER_SIZE..ICC_HEADER_SIZE + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(ColorLut::from_icc_vcgt(&profile).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+// Driver runtime configuration
+//
+// Settings are read from DISPLAYLINK_* environment variables, the same way
+// DISPLAYLINK_DRIVER_VERBOSE enables verbose logging.
+
+use crate::color::ColorLut;
+use crate::control::DEFAULT_CONTROL_SOCKET;
+use crate::dither::DitherMode;
+use crate::encoder::EncoderKind;
+use crate::scaler::{NativeMode, ScalingMode};
+use crate::transform::Transform;
+use crate::usb_transfer::{DEFAULT_URB_DEPTH, DEFAULT_URB_SIZE};
+use std::env;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::thread;
+
+/// Upper bound for the default number of compression threads
+pub const MAX_DEFAULT_ENCODER_THREADS: usize = 8;
+
+/// Accepted bytes per bulk URB
+pub const URB_SIZE_RANGE: RangeInclusive<usize> = 512..=1024 * 1024;
+/// Accepted number of bulk URBs in flight
+pub const URB_DEPTH_RANGE: RangeInclusive<usize> = 1..=32;
+
+/// Runtime configuration for a DisplayLink driver instance
+#[derive(Debug, Clone)]
+pub struct DriverConfig {
+    /// Number of worker threads used for band compression
+    pub encoder_threads: usize,
+    /// Requested codec (None = pick from device capabilities)
+    pub encoder: Option<EncoderKind>,
+    /// Dithering when reducing to RGB565 (off unless requested)
+    pub dither: DitherMode,
+    /// Adapt codec, color depth and update rate to the measured link load
+    pub adaptive_quality: bool,
+    /// Bytes per bulk URB
+    pub urb_size: usize,
+    /// Bulk URBs kept in flight
+    pub urb_depth: usize,
+    /// Use the device cursor plane when the chip has one (off unless
+    /// requested, since its registers are unconfirmed)
+    pub hw_cursor: bool,
+    /// Keep the panel at its native mode and scale frames to it (off unless
+    /// requested)
+    pub scaling: Option<ScalingMode>,
+    /// Native mode to scale to (None = read the monitor's EDID)
+    pub native_mode: Option<NativeMode>,
+    /// Unix socket for runtime commands (None = disabled)
+    pub control_socket: Option<PathBuf>,
+    /// Where snapshots go unless the request names a directory
+    pub snapshot_dir: PathBuf,
+}
+
+impl DriverConfig {
+    /// Load configuration from the environment, falling back to defaults
+    pub fn from_env() -> Self {
+        let encoder_threads = env::var("DISPLAYLINK_ENCODER_THREADS")
+            .ok()
+            .and_then(|value| value.trim().parse::<usize>().ok())
+            .filter(|&threads| threads > 0)
+            .unwrap_or_else(default_encoder_threads);
+
+        let encoder = env::var("DISPLAYLINK_ENCODER").ok().and_then(|value| {
+            if value.trim().eq_ignore_ascii_case("auto") {
+                return None;
+            }
+            let kind = EncoderKind::parse(&value);
+            if kind.is_none() {
+                eprintln!("Unknown DISPLAYLINK_ENCODER '{}', using auto", value);
+            }
+            kind
+        });
+
+        let dither = env::var("DISPLAYLINK_DITHER")
+            .ok()
+            .and_then(|value| {
+                let mode = DitherMode::parse(&value);
+                if mode.is_none() {
+                    eprintln!("Unknown DISPLAYLINK_DITHER '{}', dithering disabled", value);
+                }
+                mode
+            })
+            .unwrap_or_default();
+
+        let adaptive_quality = env::var("DISPLAYLINK_ADAPTIVE_QUALITY")
+            .map(|value| !matches!(value.trim(), "0" | "off" | "false"))
+            .unwrap_or(true);
+
+        let urb_size = bounded(env::var("DISPLAYLINK_URB_SIZE").ok(), URB_SIZE_RANGE)
+            .unwrap_or(DEFAULT_URB_SIZE);
+        let urb_depth = bounded(env::var("DISPLAYLINK_URB_DEPTH").ok(), URB_DEPTH_RANGE)
+            .unwrap_or(DEFAULT_URB_DEPTH);
+
+        let hw_cursor = env::var("DISPLAYLINK_HW_CURSOR")
+            .map(|value| matches!(value.trim(), "1" | "on" | "true"))
+            .unwrap_or(false);
+
+        let scaling = env::var("DISPLAYLINK_SCALING").ok().and_then(|value| {
+            if matches!(value.trim(), "" | "0" | "off" | "false") {
+                return None;
+            }
+            let mode = ScalingMode::parse(&value);
+            if mode.is_none() {
+                eprintln!("Unknown DISPLAYLINK_SCALING '{}', scaling disabled", value);
+            }
+            mode
+        });
+
+        let native_mode = env::var("DISPLAYLINK_NATIVE_MODE").ok().and_then(|value| {
+            let mode = NativeMode::parse(&value);
+            if mode.is_none() {
+                eprintln!("Invalid DISPLAYLINK_NATIVE_MODE '{}', using EDID", value);
+            }
+            mode
+        });
+
+        let control_socket = match env::var("DISPLAYLINK_CONTROL_SOCKET") {
+            Ok(value) if matches!(value.trim(), "" | "0" | "off" | "false") => None,
+            Ok(value) => Some(PathBuf::from(value.trim())),
+            Err(_) => Some(PathBuf::from(DEFAULT_CONTROL_SOCKET)),
+        };
+
+        let snapshot_dir = env::var("DISPLAYLINK_SNAPSHOT_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| env::temp_dir());
+
+        DriverConfig {
+            encoder_threads,
+            encoder,
+            dither,
+            adaptive_quality,
+            urb_size,
+            urb_depth,
+            hw_cursor,
+            scaling,
+            native_mode,
+            control_socket,
+            snapshot_dir,
+        }
+    }
+}
+
+impl Default for DriverConfig {
+    fn default() -> Self {
+        DriverConfig {
+            encoder_threads: default_encoder_threads(),
+            encoder: None,
+            dither: DitherMode::None,
+            adaptive_quality: true,
+            urb_size: DEFAULT_URB_SIZE,
+            urb_depth: DEFAULT_URB_DEPTH,
+            hw_cursor: false,
+            scaling: None,
+            native_mode: None,
+            control_socket: Some(PathBuf::from(DEFAULT_CONTROL_SOCKET)),
+            snapshot_dir: env::temp_dir(),
+        }
+    }
+}
+
+/// Rotation and reflection for a device ("bus:address")
+///
+/// Read from DISPLAYLINK_ROTATION_<bus>_<address>, falling back to
+/// DISPLAYLINK_ROTATION for all devices.
+pub fn transform_for(device_id: &str) -> Transform {
+    let device_var = format!("DISPLAYLINK_ROTATION_{}", device_id.replace(':', "_"));
+    let Some((name, value)) = [device_var.as_str(), "DISPLAYLINK_ROTATION"]
+        .into_iter()
+        .find_map(|name| env::var(name).ok().map(|value| (name, value)))
+    else {
+        return Transform::default();
+    };
+    Transform::parse(&value).unwrap_or_else(|| {
+        eprintln!("Unknown {} '{}', not rotating", name, value);
+        Transform::default()
+    })
+}
+
+/// Color correction for a device ("bus:address"), if any
+///
+/// Read from DISPLAYLINK_COLOR_<bus>_<address>, falling back to
+/// DISPLAYLINK_COLOR for all devices.
+pub fn color_for(device_id: &str) -> Option<ColorLut> {
+    let device_var = format!("DISPLAYLINK_COLOR_{}", device_id.replace(':', "_"));
+    let (name, value) = [device_var.as_str(), "DISPLAYLINK_COLOR"]
+        .into_iter()
+        .find_map(|name| env::var(name).ok().map(|value| (name, value)))?;
+    match ColorLut::parse_spec(&value) {
+        Ok(lut) => Some(lut).filter(|lut| !lut.is_identity()),
+        Err(e) => {
+            eprintln!("Invalid {} '{}', not correcting: {}", name, value, e);
+            None
+        }
+    }
+}
+
+/// Default compression thread count derived from available parallelism
+pub fn default_encoder_threads() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(MAX_DEFAULT_ENCODER_THREADS)
+}
+
+/// Parse a count, clamping it into `range`
+fn bounded(value: Option<String>, range: RangeInclusive<usize>) -> Option<usize> {
+    let count = value?.trim().parse::<usize>().ok()?;
+    Some(count.clamp(*range.start(), *range.end()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_encoder_threads() {
+        let threads = default_encoder_threads();
+        assert!(threads >= 1);
+        assert!(threads <= MAX_DEFAULT_ENCODER_THREADS);
+    }
+
+    #[test]
+    fn test_bounded_clamps_urb_settings() {
+        assert_eq!(bounded(Some("4096".into()), URB_SIZE_RANGE), Some(4096));
+        assert_eq!(bounded(Some("1".into()), URB_SIZE_RANGE), Some(512));
+        assert_eq!(bounded(Some("1000".into()), URB_DEPTH_RANGE), Some(32));
+        assert_eq!(bounded(Some("lots".into()), URB_DEPTH_RANGE), None);
+        assert_eq!(bounded(None, URB_DEPTH_RANGE), None);
+    }
+}
@@ -0,0 +1,245 @@
+// Runtime control socket
+//
+// The manager listens on a Unix socket for one-line commands, so output
+// settings can change without restarting the driver:
+//
+//   list                          docks, their EVDI cards and link stats
+//   color <device|all> <spec>     color correction, see ColorLut::parse_spec
+//   color <device|all> reset      back to uncorrected output
+//   snapshot <device|all> [dir]   dump source and sent frames to PNG files
+//
+// Devices are named "bus:address" as in the logs. Each command gets a
+// one-line reply starting with "ok" or "error:". Device commands are
+// forwarded to the driver threads, which pick them up between EVDI events.
+
+use crate::color::ColorLut;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Socket path unless DISPLAYLINK_CONTROL_SOCKET says otherwise
+pub const DEFAULT_CONTROL_SOCKET: &str = "/run/displaylink-driver.sock";
+
+/// How long the manager waits for a driver to answer a snapshot request
+pub const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `list` waits for the drivers' statistics
+pub const STATS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Work handed to a driver thread
+#[derive(Debug, Clone)]
+pub enum DriverCommand {
+    /// Replace the output's color correction; None turns it off
+    SetColor(Option<Arc<ColorLut>>),
+    /// Save a snapshot to `dir` (None = the configured directory) and
+    /// reply with the files written
+    Snapshot {
+        dir: Option<PathBuf>,
+        reply: Sender<Result<String, String>>,
+    },
+    /// Reply with the device id and a line of statistics
+    Stats { reply: Sender<(String, String)> },
+}
+
+/// Which docks a command is for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    All,
+    Device(String),
+}
+
+impl Target {
+    pub fn matches(&self, device_id: &str) -> bool {
+        match self {
+            Target::All => true,
+            Target::Device(id) => id == device_id,
+        }
+    }
+}
+
+/// A parsed control command
+#[derive(Debug)]
+pub enum Request {
+    List,
+    Device(Target, DriverCommand),
+    /// Needs an answer from each driver, so the manager sets up the reply
+    Snapshot(Target, Option<PathBuf>),
+}
+
+/// Parse one command line
+pub fn parse_request(line: &str) -> Result<Request, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next();
+    let mut target = |usage: &str| match words.next() {
+        Some("all") => Ok(Target::All),
+        Some(id) if id.contains(':') => Ok(Target::Device(id.to_string())),
+        Some(id) => Err(format!("invalid device '{}'", id)),
+        None => Err(format!("usage: {}", usage)),
+    };
+    match command {
+        Some("list") => Ok(Request::List),
+        Some("color") => {
+            let target = target("color <device|all> <settings|reset>")?;
+            let spec = words.collect::<Vec<_>>().join(" ");
+            let color = match spec.as_str() {
+                "reset" | "off" => None,
+                "" => return Err("missing color settings".to_string()),
+                spec => Some(ColorLut::parse_spec(spec)?)
+                    .filter(|lut| !lut.is_identity())
+                    .map(Arc::new),
+            };
+            Ok(Request::Device(target, DriverCommand::SetColor(color)))
+        }
+        Some("snapshot") => {
+            let target = target("snapshot <device|all> [directory]")?;
+            let dir = words.next().map(PathBuf::from);
+            if words.next().is_some() {
+                return Err("usage: snapshot <device|all> [directory]".to_string());
+            }
+            Ok(Request::Snapshot(target, dir))
+        }
+        Some(command) => Err(format!("unknown command '{}'", command)),
+        None => Err("empty command".to_string()),
+    }
+}
+
+/// Listen on `path` and answer commands with `handler` on a background
+/// thread
+///
+/// A socket left behind by an earlier run is replaced. Only the owner may
+/// connect.
+pub fn spawn<H>(path: &Path, handler: H) -> Result<(), String>
+where
+    H: Fn(Request) -> Result<String, String> + Send + Sync + 'static,
+{
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        let _ = fs::remove_file(path);
+    }
+    // Create the socket owner-only, so nobody can connect before the chmod
+    let old_umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(old_umask) };
+    let listener = listener.map_err(|e| format!("failed to bind {}: {}", path.display(), e))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("failed to restrict {}: {}", path.display(), e))?;
+
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let handler = handler.clone();
+                    thread::spawn(move || serve(stream, &*handler));
+                }
+                Err(e) => eprintln!("Control socket accept failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+// Answer commands from one client until it hangs up
+fn serve(stream: UnixStream, handler: &dyn Fn(Request) -> Result<String, String>) {
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match parse_request(&line).and_then(handler) {
+            Ok(message) if message.is_empty() => "ok".to_string(),
+            Ok(message) => format!("ok {}", message),
+            Err(e) => format!("error: {}", e),
+        };
+        if writeln!(writer, "{}", reply).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_requests() {
+        assert!(matches!(parse_request("list"), Ok(Request::List)));
+        match parse_request("color 1:5 gamma=2.2 temperature=5000") {
+            Ok(Request::Device(Target::Device(id), DriverCommand::SetColor(Some(_)))) => {
+                assert_eq!(id, "1:5")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        // Neutral settings don't need a table
+        assert!(matches!(
+            parse_request("color all gamma=1"),
+            Ok(Request::Device(Target::All, DriverCommand::SetColor(None)))
+        ));
+        assert!(matches!(
+            parse_request("color all reset"),
+            Ok(Request::Device(Target::All, DriverCommand::SetColor(None)))
+        ));
+
+        match parse_request("snapshot 1:5 /var/tmp") {
+            Ok(Request::Snapshot(Target::Device(id), Some(dir))) => {
+                assert_eq!((id.as_str(), dir), ("1:5", PathBuf::from("/var/tmp")))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            parse_request("snapshot all"),
+            Ok(Request::Snapshot(Target::All, None))
+        ));
+
+        assert!(parse_request("snapshot").is_err());
+        assert!(parse_request("color 1:5").is_err());
+        assert!(parse_request("color hdmi gamma=2").is_err());
+        assert!(parse_request("color 1:5 gamma=-1").is_err());
+        assert!(parse_request("reboot").is_err());
+    }
+
+    #[test]
+    fn test_socket_round_trip() {
+        let path = std::env::temp_dir().join(format!("displaylink-control-{}", std::process::id()));
+        spawn(&path, |request| match request {
+            Request::List => Ok("1:5 card1".to_string()),
+            Request::Device(Target::Device(id), _) if id != "1:5" => {
+                Err(format!("no device {}", id))
+            }
+            Request::Device(..) | Request::Snapshot(..) => Ok(String::new()),
+        })
+        .unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream
+            .write_all(b"list\n\ncolor 2:3 reset\ncolor 1:5 brightness=0.1\nbogus\n")
+            .unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let replies: Vec<String> = BufReader::new(stream)
+            .lines()
+            .map_while(Result::ok)
+            .collect();
+        assert_eq!(
+            replies,
+            [
+                "ok 1:5 card1",
+                "error: no device 2:3",
+                "ok",
+                "error: unknown command 'bogus'"
+            ]
+        );
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,399 @@
+// Software cursor
+//
+// Compositors put the pointer on EVDI's cursor plane, which never reaches
+// the framebuffer we grab. The driver keeps the image and position from
+// cursor events and alpha-blends the cursor into each outgoing frame; a move
+// only damages the rectangles the cursor left and entered.
+//
+// Chips with a cursor plane skip all of that: the image is uploaded once per
+// cursor_set and a move only rewrites the position registers. Images larger
+// than the plane fall back to compositing.
+
+use crate::displaylink_protocol::CommandBuilder;
+use crate::encoder::Rect;
+use crate::evdi::CursorSet;
+use crate::pipeline::FrameLayout;
+use crate::pixel_format::PixelFormat;
+use crate::transform::Transform;
+
+/// Pointer image and position as last reported by EVDI
+#[derive(Debug, Default)]
+pub struct SoftwareCursor {
+    /// Non-premultiplied ARGB, `width` pixels per row
+    pixels: Vec<u32>,
+    width: usize,
+    height: usize,
+    hot_x: i32,
+    hot_y: i32,
+    /// Top-left corner of the image on screen
+    x: i32,
+    y: i32,
+    enabled: bool,
+}
+
+impl SoftwareCursor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take a new image from a cursor event
+    ///
+    /// Returns the area to repaint: where the old image was and the new one
+    /// is. Formats other than 32-bit RGB hide the cursor.
+    pub fn set(&mut self, cursor: &CursorSet) -> Option<Rect> {
+        let old = self.rect();
+
+        self.hot_x = cursor.hot_x;
+        self.hot_y = cursor.hot_y;
+        self.width = cursor.width as usize;
+        self.height = cursor.height as usize;
+        self.pixels.clear();
+        self.enabled = cursor.enabled;
+
+        let row_pixels = (cursor.stride as usize / 4).max(self.width);
+        let format = PixelFormat::from_fourcc(cursor.pixel_format);
+        let complete =
+            cursor.pixels.len() >= row_pixels * self.height.saturating_sub(1) + self.width;
+        match format {
+            Some(format) if format.bytes_per_pixel() == 4 && complete => {
+                for row in cursor.pixels.chunks(row_pixels).take(self.height) {
+                    self.pixels
+                        .extend(row[..self.width].iter().map(|&px| to_argb(format, px)));
+                }
+            }
+            _ => {
+                if cursor.enabled {
+                    eprintln!(
+                        "Unsupported cursor image ({}x{}, format {:#x}); hiding cursor",
+                        cursor.width, cursor.height, cursor.pixel_format
+                    );
+                }
+                self.enabled = false;
+            }
+        }
+
+        union(old, self.rect())
+    }
+
+    /// Move the image's top-left corner to (`x`, `y`)
+    ///
+    /// Returns the old and new cursor rectangles, which are all that need
+    /// repainting.
+    pub fn move_to(&mut self, x: i32, y: i32) -> [Option<Rect>; 2] {
+        if (x, y) == (self.x, self.y) {
+            return [None, None];
+        }
+        let old = self.rect();
+        self.x = x;
+        self.y = y;
+        [old, self.rect()]
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.enabled && !self.pixels.is_empty()
+    }
+
+    /// Hotspot within the image
+    pub fn hotspot(&self) -> (i32, i32) {
+        (self.hot_x, self.hot_y)
+    }
+
+    /// On-screen bounds of the visible cursor, cut off at the top and left
+    /// screen edges
+    pub fn rect(&self) -> Option<Rect> {
+        if !self.is_visible() {
+            return None;
+        }
+        let left = self.x.max(0);
+        let top = self.y.max(0);
+        let right = self.x + self.width as i32;
+        let bottom = self.y + self.height as i32;
+        if right <= left || bottom <= top {
+            return None;
+        }
+        Some(Rect::new(
+            left as usize,
+            top as usize,
+            (right - left) as usize,
+            (bottom - top) as usize,
+        ))
+    }
+
+    /// Blend the cursor into the part of `frame` covered by `region`
+    ///
+    /// `frame` and `region` are in the monitor's orientation; `transform`
+    /// maps the `source_size` image the cursor position refers to onto it.
+    pub fn blend(
+        &self,
+        frame: &mut [u8],
+        layout: &FrameLayout,
+        region: Rect,
+        transform: Transform,
+        (source_width, source_height): (usize, usize),
+    ) {
+        let Some(rect) = self.rect() else {
+            return;
+        };
+        let area = transform
+            .map_rect(&rect, source_width, source_height)
+            .intersect(&region)
+            .clamp_to(layout.width, layout.height);
+        let bpp = layout.format.bytes_per_pixel();
+
+        for y in area.y..area.y + area.height {
+            for x in area.x..area.x + area.width {
+                let (sx, sy) = transform.source_point(x, y, source_width, source_height);
+                let image_x = (sx as i32 - self.x) as usize;
+                let image_y = (sy as i32 - self.y) as usize;
+                let argb = self.pixels[image_y * self.width + image_x];
+                let alpha = argb >> 24;
+                if alpha == 0 {
+                    continue;
+                }
+                let offset = y * layout.stride + x * bpp;
+                let px = &mut frame[offset..offset + bpp];
+                let cursor = [(argb >> 16) as u8, (argb >> 8) as u8, argb as u8];
+                let rgb = if alpha == 255 {
+                    cursor
+                } else {
+                    let under = layout.format.read_rgb(px);
+                    [0, 1, 2].map(|i| blend_channel(under[i], cursor[i], alpha))
+                };
+                layout.format.write_rgb(px, rgb);
+            }
+        }
+    }
+}
+
+/// Device cursor plane
+pub struct HardwareCursor {
+    /// Largest image side the plane holds
+    size: usize,
+    /// Whether the plane currently shows the cursor
+    shown: bool,
+    cmd_builder: CommandBuilder,
+}
+
+impl HardwareCursor {
+    pub fn new(size: u32) -> Self {
+        HardwareCursor {
+            size: size as usize,
+            shown: false,
+            cmd_builder: CommandBuilder::new(),
+        }
+    }
+
+    /// Whether the plane draws the cursor, so frames must not
+    pub fn is_shown(&self) -> bool {
+        self.shown
+    }
+
+    /// Commands that put `cursor` on the plane, or None if it can't be shown
+    /// there and has to be composited instead
+    pub fn show(&mut self, cursor: &SoftwareCursor) -> Option<Vec<u8>> {
+        if !cursor.is_visible() || cursor.width > self.size || cursor.height > self.size {
+            return None;
+        }
+        let mut commands = self
+            .cmd_builder
+            .cursor_image(cursor.width as u16, cursor.height as u16, &cursor.pixels)
+            .to_vec();
+        commands.extend_from_slice(self.position(cursor));
+        commands.extend_from_slice(self.cmd_builder.cursor_enable(true));
+        self.shown = true;
+        Some(commands)
+    }
+
+    /// Commands that take the cursor off the plane, if it is on it
+    pub fn hide(&mut self) -> Option<Vec<u8>> {
+        if !self.shown {
+            return None;
+        }
+        self.shown = false;
+        Some(self.cmd_builder.cursor_enable(false).to_vec())
+    }
+
+    /// Commands that move the plane to the cursor's position, if it is shown
+    pub fn move_to(&mut self, cursor: &SoftwareCursor) -> Option<Vec<u8>> {
+        if !self.shown {
+            return None;
+        }
+        Some(self.position(cursor).to_vec())
+    }
+
+    fn position(&mut self, cursor: &SoftwareCursor) -> &[u8] {
+        let clamp = |v: i32| v.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        self.cmd_builder
+            .cursor_position(clamp(cursor.x), clamp(cursor.y))
+    }
+}
+
+// Normalize a 32-bit cursor pixel to ARGB; formats without alpha are opaque
+fn to_argb(format: PixelFormat, px: u32) -> u32 {
+    let [r, g, b] = format.read_rgb(&px.to_le_bytes());
+    let alpha = match format {
+        PixelFormat::Argb8888 | PixelFormat::Abgr8888 => px >> 24,
+        _ => 0xFF,
+    };
+    (alpha << 24) | (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+#[inline]
+fn blend_channel(under: u8, over: u8, alpha: u32) -> u8 {
+    ((under as u32 * (255 - alpha) + over as u32 * alpha + 127) / 255) as u8
+}
+
+fn union(a: Option<Rect>, b: Option<Rect>) -> Option<Rect> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.union(&b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel_format::{DRM_FORMAT_ARGB8888, DRM_FORMAT_RGB565};
+
+    fn cursor_set(width: u32, height: u32, argb: u32) -> CursorSet {
+        CursorSet {
+            hot_x: 1,
+            hot_y: 1,
+            width,
+            height,
+            enabled: true,
+            pixel_format: DRM_FORMAT_ARGB8888,
+            stride: width * 4,
+            pixels: vec![argb; (width * height) as usize],
+        }
+    }
+
+    fn layout(width: usize, height: usize) -> FrameLayout {
+        FrameLayout {
+            width,
+            height,
+            stride: width * 4,
+            format: PixelFormat::Xrgb8888,
+        }
+    }
+
+    #[test]
+    fn test_move_damages_old_and_new_rects() {
+        let mut cursor = SoftwareCursor::new();
+        assert_eq!(
+            cursor.set(&cursor_set(4, 4, 0xFF00_0000)),
+            Some(Rect::new(0, 0, 4, 4))
+        );
+        assert_eq!(
+            cursor.move_to(10, 20),
+            [Some(Rect::new(0, 0, 4, 4)), Some(Rect::new(10, 20, 4, 4))]
+        );
+        assert_eq!(cursor.move_to(10, 20), [None, None]);
+        // Partly off the top-left edge
+        assert_eq!(cursor.move_to(-2, -3)[1], Some(Rect::new(0, 0, 2, 1)));
+        assert_eq!(cursor.hotspot(), (1, 1));
+    }
+
+    #[test]
+    fn test_blend_alpha_within_region() {
+        let mut cursor = SoftwareCursor::new();
+        // Half-transparent white
+        cursor.set(&cursor_set(2, 2, 0x80FF_FFFF));
+        cursor.move_to(1, 1);
+
+        let layout = layout(4, 4);
+        let mut frame = vec![0u8; layout.stride * layout.height];
+        // Only the top row of the cursor is inside the region
+        cursor.blend(
+            &mut frame,
+            &layout,
+            Rect::new(0, 0, 4, 2),
+            Transform::default(),
+            (4, 4),
+        );
+
+        let pixel = |x: usize, y: usize| layout.format.read_rgb(&frame[y * 16 + x * 4..]);
+        assert_eq!(pixel(1, 1), [128, 128, 128]);
+        assert_eq!(pixel(2, 1), [128, 128, 128]);
+        assert_eq!(pixel(1, 2), [0, 0, 0]);
+        assert_eq!(pixel(0, 0), [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_blend_follows_rotation() {
+        let mut cursor = SoftwareCursor::new();
+        // Opaque white, 2 pixels wide, in the top-left corner of a 3x2 image
+        cursor.set(&cursor_set(2, 1, 0xFFFF_FFFF));
+
+        // Rotated 90° clockwise, the image's top row is the right column
+        let layout = layout(2, 3);
+        let mut frame = vec![0u8; layout.stride * layout.height];
+        let transform = Transform::parse("90").unwrap();
+        cursor.blend(
+            &mut frame,
+            &layout,
+            Rect::new(0, 0, 2, 3),
+            transform,
+            (3, 2),
+        );
+
+        let white: Vec<bool> = frame.chunks(4).map(|px| px[0] == 0xFF).collect();
+        assert_eq!(white, vec![false, true, false, true, false, false]);
+    }
+
+    #[test]
+    fn test_hidden_and_unsupported_cursors_draw_nothing() {
+        let mut cursor = SoftwareCursor::new();
+        let mut set = cursor_set(2, 2, 0xFFFF_FFFF);
+        set.pixel_format = DRM_FORMAT_RGB565;
+        assert_eq!(cursor.set(&set), None);
+        assert!(!cursor.is_visible());
+
+        cursor.set(&cursor_set(2, 2, 0xFFFF_FFFF));
+        let mut disabled = cursor_set(2, 2, 0xFFFF_FFFF);
+        disabled.enabled = false;
+        // The old image still needs repainting
+        assert_eq!(cursor.set(&disabled), Some(Rect::new(0, 0, 2, 2)));
+
+        let layout = layout(2, 2);
+        let mut frame = vec![0u8; layout.stride * layout.height];
+        cursor.blend(
+            &mut frame,
+            &layout,
+            Rect::new(0, 0, 2, 2),
+            Transform::default(),
+            (2, 2),
+        );
+        assert!(frame.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_hardware_cursor_uploads_once_and_moves_by_register() {
+        let mut cursor = SoftwareCursor::new();
+        let mut plane = HardwareCursor::new(4);
+        cursor.set(&cursor_set(2, 2, 0xFF12_3456));
+
+        let upload = plane.show(&cursor).unwrap();
+        assert!(plane.is_shown());
+        // Width/height registers, upload header, 4 ARGB pixels, position
+        // and enable registers
+        assert_eq!(upload.len(), 2 * 6 + 4 + 4 * 4 + 2 * 6 + 6);
+        assert_eq!(&upload[12..16], &[0xAF, 0x68, 4, 0]);
+        assert_eq!(&upload[16..20], &0xFF12_3456u32.to_le_bytes());
+
+        cursor.move_to(-1, 300);
+        let moved = plane.move_to(&cursor).unwrap();
+        assert_eq!(moved.len(), 2 * 6);
+        assert_eq!(&moved[4..6], &(-1i16).to_le_bytes());
+        assert_eq!(&moved[10..12], &300u16.to_le_bytes());
+
+        assert!(plane.hide().is_some());
+        assert!(plane.hide().is_none());
+        assert!(plane.move_to(&cursor).is_none());
+
+        // Too large for the plane
+        cursor.set(&cursor_set(8, 8, 0xFFFF_FFFF));
+        assert!(plane.show(&cursor).is_none());
+        assert!(!plane.is_shown());
+    }
+}
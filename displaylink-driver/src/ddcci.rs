@@ -0,0 +1,286 @@
+// DDC/CI passthrough
+//
+// EVDI exposes an I2C adapter for the virtual connector and hands every
+// transfer on the DDC/CI address to the driver, which has to answer before
+// the kernel gives up on it. Requests are forwarded to the monitor over the
+// dock's DDC channel with vendor control transfers: writes go out as-is, reads
+// return what the monitor sent back. Anything the dock can't serve is NAKed
+// so ddcutil and brightness sliders fail fast instead of hanging.
+
+use crate::displaylink_protocol::{
+    DL_USB_REQUEST_DDC_READ, DL_USB_REQUEST_DDC_WRITE, USB_DIR_IN, USB_DIR_OUT, USB_RECIP_DEVICE,
+    USB_TYPE_VENDOR,
+};
+use crate::evdi::DdcciData;
+use rusb::DeviceHandle;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// 7-bit I2C address monitors answer DDC/CI on
+pub const DDCCI_ADDRESS: u16 = 0x37;
+
+/// 7-bit I2C address of the monitor's EDID EEPROM
+pub const EDID_ADDRESS: u16 = 0x50;
+
+/// Largest transfer EVDI passes through
+pub const DDCCI_MAX_LENGTH: usize = 64;
+
+/// EVDI stops waiting for a response after 50ms, so a slower answer is lost
+/// anyway
+pub const DDCCI_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// I2C message flag for reads (I2C_M_RD)
+const I2C_M_RD: u16 = 0x0001;
+
+/// Why a DDC/CI transfer got no answer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DdcError {
+    /// The monitor didn't acknowledge, or the request isn't DDC/CI
+    Nak,
+    /// No answer within DDCCI_TIMEOUT
+    Timeout,
+    /// The dock has no DDC channel; reported once, later requests are NAKed
+    /// without asking it
+    Unsupported,
+    /// The USB transfer failed
+    Transfer(String),
+}
+
+impl fmt::Display for DdcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DdcError::Nak => write!(f, "not acknowledged"),
+            DdcError::Timeout => write!(f, "timed out"),
+            DdcError::Unsupported => write!(f, "dock has no DDC channel"),
+            DdcError::Transfer(e) => write!(f, "transfer failed: {}", e),
+        }
+    }
+}
+
+/// I2C bus to the monitor
+pub trait DdcChannel {
+    fn write(&mut self, address: u16, data: &[u8]) -> Result<(), DdcError>;
+
+    /// Read into `buffer`, returning the number of bytes received
+    fn read(&mut self, address: u16, buffer: &mut [u8]) -> Result<usize, DdcError>;
+}
+
+/// The dock's DDC channel, reached through vendor control transfers
+pub struct UsbDdcChannel {
+    usb_handle: Arc<Mutex<DeviceHandle<rusb::Context>>>,
+}
+
+impl UsbDdcChannel {
+    pub fn new(usb_handle: Arc<Mutex<DeviceHandle<rusb::Context>>>) -> Self {
+        UsbDdcChannel { usb_handle }
+    }
+}
+
+fn map_usb_error(e: rusb::Error) -> DdcError {
+    match e {
+        // The dock stalls requests the monitor didn't acknowledge
+        rusb::Error::Pipe => DdcError::Nak,
+        rusb::Error::Timeout => DdcError::Timeout,
+        rusb::Error::NotSupported => DdcError::Unsupported,
+        e => DdcError::Transfer(e.to_string()),
+    }
+}
+
+impl DdcChannel for UsbDdcChannel {
+    fn write(&mut self, address: u16, data: &[u8]) -> Result<(), DdcError> {
+        let handle = self.usb_handle.lock().unwrap();
+        let written = handle
+            .write_control(
+                USB_DIR_OUT | USB_TYPE_VENDOR | USB_RECIP_DEVICE,
+                DL_USB_REQUEST_DDC_WRITE,
+                address,
+                0,
+                data,
+                DDCCI_TIMEOUT,
+            )
+            .map_err(map_usb_error)?;
+        if written < data.len() {
+            return Err(DdcError::Nak);
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, address: u16, buffer: &mut [u8]) -> Result<usize, DdcError> {
+        let handle = self.usb_handle.lock().unwrap();
+        handle
+            .read_control(
+                USB_DIR_IN | USB_TYPE_VENDOR | USB_RECIP_DEVICE,
+                DL_USB_REQUEST_DDC_READ,
+                address,
+                0,
+                buffer,
+                DDCCI_TIMEOUT,
+            )
+            .map_err(map_usb_error)
+    }
+}
+
+/// Read the base EDID block of the monitor behind the dock
+pub fn read_edid<C: DdcChannel>(channel: &mut C) -> Result<Vec<u8>, DdcError> {
+    channel.write(EDID_ADDRESS, &[0])?;
+    let mut edid = vec![0u8; 128];
+    let received = channel.read(EDID_ADDRESS, &mut edid)?;
+    if received < edid.len() {
+        return Err(DdcError::Nak);
+    }
+    Ok(edid)
+}
+
+/// Answers EVDI's DDC/CI requests from a DDC channel
+pub struct DdcciBridge<C: DdcChannel> {
+    channel: C,
+    /// Set once the dock turned out to have no DDC channel
+    unsupported: bool,
+}
+
+impl<C: DdcChannel> DdcciBridge<C> {
+    pub fn new(channel: C) -> Self {
+        DdcciBridge {
+            channel,
+            unsupported: false,
+        }
+    }
+
+    /// Carry out one I2C transfer
+    ///
+    /// Returns the bytes read, or nothing for a write. Any error is answered
+    /// with a NAK.
+    ///
+    /// EVDI drops replies that aren't exactly the requested length, so a
+    /// short read is padded with zeros; the DDC/CI checksum lets the tool
+    /// tell how much of it the monitor sent.
+    pub fn transfer(&mut self, request: &DdcciData) -> Result<Vec<u8>, DdcError> {
+        if self.unsupported
+            || request.address != DDCCI_ADDRESS
+            || request.data.len() > DDCCI_MAX_LENGTH
+        {
+            return Err(DdcError::Nak);
+        }
+
+        let result = if request.flags & I2C_M_RD != 0 {
+            // A read carries only the requested length
+            let mut reply = vec![0u8; request.data.len()];
+            self.channel
+                .read(request.address, &mut reply)
+                .and_then(|received| match received {
+                    0 => Err(DdcError::Nak),
+                    _ => Ok(reply),
+                })
+        } else {
+            self.channel
+                .write(request.address, &request.data)
+                .map(|()| Vec::new())
+        };
+
+        if result == Err(DdcError::Unsupported) {
+            self.unsupported = true;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    #[derive(Default)]
+    struct FakeChannel {
+        written: Vec<Vec<u8>>,
+        replies: VecDeque<Result<Vec<u8>, DdcError>>,
+        reads: usize,
+    }
+
+    impl DdcChannel for FakeChannel {
+        fn write(&mut self, _address: u16, data: &[u8]) -> Result<(), DdcError> {
+            self.written.push(data.to_vec());
+            Ok(())
+        }
+
+        fn read(&mut self, _address: u16, buffer: &mut [u8]) -> Result<usize, DdcError> {
+            self.reads += 1;
+            let reply = self.replies.pop_front().unwrap_or(Err(DdcError::Timeout))?;
+            buffer[..reply.len()].copy_from_slice(&reply);
+            Ok(reply.len())
+        }
+    }
+
+    fn request(address: u16, flags: u16, data: &[u8]) -> DdcciData {
+        DdcciData {
+            address,
+            flags,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_forwards_writes_and_reads() {
+        let mut bridge = DdcciBridge::new(FakeChannel::default());
+        // Get VCP feature 0x10 (brightness)
+        let get_vcp = [0x51, 0x82, 0x01, 0x10, 0xAC];
+        assert_eq!(
+            bridge.transfer(&request(DDCCI_ADDRESS, 0, &get_vcp)),
+            Ok(Vec::new())
+        );
+        assert_eq!(bridge.channel.written, vec![get_vcp.to_vec()]);
+
+        let reply = vec![0x6E, 0x88, 0x02, 0x00, 0x10];
+        bridge.channel.replies.push_back(Ok(reply.clone()));
+        // Short replies are padded to the requested length, since EVDI
+        // discards anything else
+        let mut padded = reply;
+        padded.resize(11, 0);
+        assert_eq!(
+            bridge.transfer(&request(DDCCI_ADDRESS, I2C_M_RD, &[0; 11])),
+            Ok(padded)
+        );
+    }
+
+    #[test]
+    fn test_read_edid_needs_a_full_block() {
+        let mut channel = FakeChannel::default();
+        channel.replies.push_back(Ok(vec![0xAB; 128]));
+        channel.replies.push_back(Ok(vec![0xAB; 20]));
+        assert_eq!(read_edid(&mut channel), Ok(vec![0xAB; 128]));
+        assert_eq!(channel.written, vec![vec![0]]);
+        assert_eq!(read_edid(&mut channel), Err(DdcError::Nak));
+    }
+
+    #[test]
+    fn test_naks_requests_the_dock_cannot_serve() {
+        let mut bridge = DdcciBridge::new(FakeChannel::default());
+        assert_eq!(
+            bridge.transfer(&request(0x50, I2C_M_RD, &[0; 8])),
+            Err(DdcError::Nak)
+        );
+        assert_eq!(
+            bridge.transfer(&request(DDCCI_ADDRESS, 0, &[0; 65])),
+            Err(DdcError::Nak)
+        );
+        assert_eq!(bridge.channel.reads, 0);
+
+        // Silent monitor
+        assert_eq!(
+            bridge.transfer(&request(DDCCI_ADDRESS, I2C_M_RD, &[0; 4])),
+            Err(DdcError::Timeout)
+        );
+        bridge.channel.replies.push_back(Ok(Vec::new()));
+        assert_eq!(
+            bridge.transfer(&request(DDCCI_ADDRESS, I2C_M_RD, &[0; 4])),
+            Err(DdcError::Nak)
+        );
+
+        // No DDC channel: stop asking the dock
+        bridge.channel.replies.push_back(Err(DdcError::Unsupported));
+        let read = request(DDCCI_ADDRESS, I2C_M_RD, &[0; 4]);
+        assert_eq!(bridge.transfer(&read), Err(DdcError::Unsupported));
+        assert_eq!(bridge.transfer(&read), Err(DdcError::Nak));
+        assert_eq!(bridge.channel.reads, 3);
+    }
+}
@@ -18,10 +18,33 @@ pub const USB_RECIP_DEVICE: u8 = 0x00;
 pub const DL_USB_REQUEST_WRITE_REG: u8 = 0x01;
 pub const DL_USB_REQUEST_READ_REG: u8 = 0x02;
 pub const DL_USB_REQUEST_CHANNEL: u8 = 0x12;
+pub const DL_USB_REQUEST_DDC_WRITE: u8 = 0x06; // I2C write to the monitor, wValue = address
+pub const DL_USB_REQUEST_DDC_READ: u8 = 0x07; // I2C read from the monitor, wValue = address
 
 /// DisplayLink register addresses
 pub const DL_REG_SYNC: u16 = 0xFF00; // Sync register
 pub const DL_REG_BLANK: u16 = 0x1F00; // Blank screen register
+pub const DL_REG_COLOR_DEPTH: u16 = 0x1F02; // Color depth register (0 = 16bpp, 1 = 24bpp)
+pub const DL_REG_POWER: u16 = 0x1F04; // Output power (0 = on, 1 = standby, 2 = suspend, 3 = off)
+pub const DL_REG_DAMAGE_X: u16 = 0x2000; // Damage rectangle left edge
+pub const DL_REG_DAMAGE_Y: u16 = 0x2002; // Damage rectangle top edge
+pub const DL_REG_DAMAGE_WIDTH: u16 = 0x2004; // Damage rectangle width
+pub const DL_REG_DAMAGE_HEIGHT: u16 = 0x2006; // Damage rectangle height; pixel data follows
+pub const DL_REG_DAMAGE_CODEC: u16 = 0x2008; // Codec of the next damage rectangle's pixel data
+pub const DL_REG_CURSOR_X: u16 = 0x3000; // Cursor plane left edge (signed)
+pub const DL_REG_CURSOR_Y: u16 = 0x3002; // Cursor plane top edge (signed)
+pub const DL_REG_CURSOR_WIDTH: u16 = 0x3004; // Cursor image width
+pub const DL_REG_CURSOR_HEIGHT: u16 = 0x3006; // Cursor image height
+pub const DL_REG_CURSOR_ENABLE: u16 = 0x3008; // Cursor plane on/off
+
+/// Pixel data codecs for DL_REG_DAMAGE_CODEC
+pub const DL_CODEC_RAW16: u16 = 0x0000;
+pub const DL_CODEC_RLE: u16 = 0x0001;
+pub const DL_CODEC_HUFFMAN: u16 = 0x0002;
+pub const DL_CODEC_RAW24: u16 = 0x0003;
+
+/// Cursor image upload: [0xAF, 0x68, pixel count (u16 LE)] [ARGB8888 LE...]
+pub const DL_CMD_CURSOR_UPLOAD: u8 = 0x68;
 
 /// DisplayLink channel commands
 pub const DL_CHAN_CMD_INIT: u16 = 0x0000;
@@ -244,16 +267,71 @@ impl CommandBuilder {
         &self.buffer
     }
 
+    /// Output power level command
+    pub fn set_power(&mut self, level: u16) -> &[u8] {
+        self.buffer.clear();
+        self.write_reg16(DL_REG_POWER, level);
+        &self.buffer
+    }
+
+    /// Color depth command (16 or 24 bits per pixel)
+    pub fn set_color_depth(&mut self, bits_per_pixel: u32) -> &[u8] {
+        self.buffer.clear();
+        self.write_reg16(
+            DL_REG_COLOR_DEPTH,
+            if bits_per_pixel == 24 { 0x0001 } else { 0x0000 },
+        );
+        &self.buffer
+    }
+
     /// Damage rectangle command (update specific area)
-    pub fn damage_rect(&mut self, x: u16, y: u16, width: u16, height: u16) -> &[u8] {
+    ///
+    /// `codec` (a DL_CODEC_* value) tells the device how the pixel data that
+    /// follows the rectangle is encoded.
+    pub fn damage_rect(&mut self, codec: u16, x: u16, y: u16, width: u16, height: u16) -> &[u8] {
         self.buffer.clear();
 
-        // Set damage rectangle registers
-        self.write_reg16(0x2000, x);
-        self.write_reg16(0x2002, y);
-        self.write_reg16(0x2004, width);
-        self.write_reg16(0x2006, height);
+        // Select the codec, then set damage rectangle registers
+        self.write_reg16(DL_REG_DAMAGE_CODEC, codec);
+        self.write_reg16(DL_REG_DAMAGE_X, x);
+        self.write_reg16(DL_REG_DAMAGE_Y, y);
+        self.write_reg16(DL_REG_DAMAGE_WIDTH, width);
+        self.write_reg16(DL_REG_DAMAGE_HEIGHT, height);
+
+        &self.buffer
+    }
 
+    /// Cursor image upload into the device cursor plane
+    ///
+    /// `argb` holds `width` × `height` non-premultiplied ARGB pixels.
+    pub fn cursor_image(&mut self, width: u16, height: u16, argb: &[u32]) -> &[u8] {
+        self.buffer.clear();
+        self.write_reg16(DL_REG_CURSOR_WIDTH, width);
+        self.write_reg16(DL_REG_CURSOR_HEIGHT, height);
+
+        let count = width as usize * height as usize;
+        self.buffer.push(0xAF);
+        self.buffer.push(DL_CMD_CURSOR_UPLOAD);
+        self.buffer.extend_from_slice(&(count as u16).to_le_bytes());
+        for pixel in &argb[..count] {
+            self.buffer.extend_from_slice(&pixel.to_le_bytes());
+        }
+        &self.buffer
+    }
+
+    /// Cursor plane position; may be negative when the cursor hangs off the
+    /// top or left edge
+    pub fn cursor_position(&mut self, x: i16, y: i16) -> &[u8] {
+        self.buffer.clear();
+        self.write_reg16(DL_REG_CURSOR_X, x as u16);
+        self.write_reg16(DL_REG_CURSOR_Y, y as u16);
+        &self.buffer
+    }
+
+    /// Show or hide the cursor plane
+    pub fn cursor_enable(&mut self, enable: bool) -> &[u8] {
+        self.buffer.clear();
+        self.write_reg16(DL_REG_CURSOR_ENABLE, if enable { 0x0001 } else { 0x0000 });
         &self.buffer
     }
 
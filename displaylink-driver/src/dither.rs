@@ -0,0 +1,155 @@
+// Dithering for RGB565 reduction
+//
+// Truncating 8-bit channels to 5/6/5 bits bands visibly on gradients. Both
+// dithers here depend only on the source pixels and their absolute screen
+// position, never on previous frames or on where a dirty region starts, so
+// static content doesn't crawl and partial updates have no seams.
+
+/// Dithering applied when reducing to RGB565
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DitherMode {
+    /// Plain truncation
+    #[default]
+    None,
+    /// 8x8 Bayer ordered dither
+    Ordered,
+    /// Error diffusion confined to screen-aligned spans
+    ErrorDiffusion,
+}
+
+impl DitherMode {
+    /// Parse a mode name as used in DISPLAYLINK_DITHER
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "none" | "off" => Some(DitherMode::None),
+            "ordered" | "bayer" => Some(DitherMode::Ordered),
+            "diffusion" | "error-diffusion" => Some(DitherMode::ErrorDiffusion),
+            _ => None,
+        }
+    }
+}
+
+/// 8x8 Bayer threshold matrix (values 0..=63)
+pub const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Error diffusion never crosses multiples of this many pixels
+///
+/// A pixel's output depends only on the pixels to its left within the same
+/// span, so any dirty region reproduces exactly what a full-frame update
+/// would have produced.
+pub const DIFFUSION_SPAN: usize = 16;
+
+/// Quantization step per channel (R, G, B) for RGB565
+const STEP: [i16; 3] = [8, 4, 8];
+
+#[inline]
+fn bayer(x: usize, y: usize) -> i16 {
+    BAYER_8X8[y & 7][x & 7] as i16
+}
+
+#[inline]
+fn pack(r: i16, g: i16, b: i16) -> u16 {
+    ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3)
+}
+
+/// Ordered dither of one pixel at absolute position (x, y)
+#[inline]
+pub fn ordered_rgb565(rgb: [u8; 3], x: usize, y: usize) -> u16 {
+    let t = bayer(x, y);
+    let offset = |c: usize| (rgb[c] as i16 + t * STEP[c] / 64).min(255);
+    pack(offset(0), offset(1), offset(2))
+}
+
+/// Running state of the span-confined error diffusion
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiffusionState {
+    error: [i16; 3],
+}
+
+impl DiffusionState {
+    /// Start a new span at absolute position (x, y)
+    ///
+    /// The initial error is seeded from the Bayer matrix so neighbouring rows
+    /// don't line up into vertical streaks.
+    pub fn reset(&mut self, x: usize, y: usize) {
+        let t = bayer(x / DIFFUSION_SPAN, y) - 32;
+        for (error, step) in self.error.iter_mut().zip(STEP) {
+            *error = t * step / 64;
+        }
+    }
+
+    /// Quantize one pixel, carrying the error to the next
+    #[inline]
+    pub fn push(&mut self, rgb: [u8; 3]) -> u16 {
+        let mut q = [0i16; 3];
+        for c in 0..3 {
+            let step = STEP[c];
+            let value = (rgb[c] as i16 + self.error[c]).clamp(0, 255);
+            // Round to the nearest representable level
+            let level = ((value + step / 2) / step * step).min(256 - step);
+            self.error[c] = value - level;
+            q[c] = level;
+        }
+        pack(q[0], q[1], q[2])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel_format::rgb888_to_rgb565;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(DitherMode::parse("Bayer"), Some(DitherMode::Ordered));
+        assert_eq!(
+            DitherMode::parse("diffusion"),
+            Some(DitherMode::ErrorDiffusion)
+        );
+        assert_eq!(DitherMode::parse("fancy"), None);
+    }
+
+    #[test]
+    fn test_ordered_mixes_neighbouring_levels() {
+        // Midway between two 5-bit red levels
+        let gray = [0x84, 0x80, 0x80];
+        let levels: std::collections::HashSet<u16> = (0..8)
+            .flat_map(|y| (0..8).map(move |x| ordered_rgb565(gray, x, y)))
+            .collect();
+        assert!(levels.len() > 1);
+    }
+
+    #[test]
+    fn test_ordered_keeps_exact_levels() {
+        // Values that are exactly representable never change
+        let exact = [0xF8, 0xFC, 0xF8];
+        for y in 0..8 {
+            for x in 0..8 {
+                assert_eq!(
+                    ordered_rgb565(exact, x, y),
+                    rgb888_to_rgb565(0xF8, 0xFC, 0xF8)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_diffusion_preserves_average() {
+        let mut state = DiffusionState::default();
+        state.reset(0, 0);
+        let total: u32 = (0..DIFFUSION_SPAN)
+            .map(|_| ((state.push([100, 100, 100]) >> 11) as u32) << 3)
+            .sum();
+        let average = total / DIFFUSION_SPAN as u32;
+        assert!((96..=104).contains(&average), "average {}", average);
+    }
+}
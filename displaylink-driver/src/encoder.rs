@@ -0,0 +1,843 @@
+// Pluggable frame encoders
+//
+// An encoder turns a rectangular region of the framebuffer into the device
+// command stream for that region: a damage rectangle, which names the codec,
+// followed by the encoded pixel payload. New codecs implement `Encoder` and get an `EncoderKind`
+// variant; the driver only deals with the selected kind.
+
+use crate::capabilities::DeviceCapabilities;
+use crate::color::ColorLut;
+use crate::displaylink_protocol::{
+    CommandBuilder, DL_CODEC_HUFFMAN, DL_CODEC_RAW16, DL_CODEC_RAW24, DL_CODEC_RLE,
+};
+use crate::dither::{ordered_rgb565, DiffusionState, DitherMode, DIFFUSION_SPAN};
+use crate::pixel_format::{rgb888_to_rgb565, PixelFormat};
+
+/// Rectangular pixel region
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Smallest rectangle covering both
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Rect::new(x, y, right - x, bottom - y)
+    }
+
+    /// Overlap of both; empty if they don't touch
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        if right <= x || bottom <= y {
+            return Rect::default();
+        }
+        Rect::new(x, y, right - x, bottom - y)
+    }
+
+    /// Part of this rectangle inside a `width` x `height` area
+    pub fn clamp_to(&self, width: usize, height: usize) -> Rect {
+        let x = self.x.min(width);
+        let y = self.y.min(height);
+        let right = (self.x + self.width).min(width);
+        let bottom = (self.y + self.height).min(height);
+        Rect::new(x, y, right - x, bottom - y)
+    }
+}
+
+/// Pixel data handed to an encoder
+pub struct EncoderInput<'a> {
+    /// Whole framebuffer
+    pub data: &'a [u8],
+    /// Bytes per framebuffer row
+    pub stride: usize,
+    pub format: PixelFormat,
+    /// Region of the framebuffer to encode
+    pub region: Rect,
+    /// Dithering applied when reducing to RGB565
+    pub dither: DitherMode,
+    /// Color correction applied before conversion
+    pub color: Option<&'a ColorLut>,
+}
+
+impl<'a> EncoderInput<'a> {
+    /// Raw bytes of one row of the region, honoring stride and format
+    ///
+    /// `row` is relative to the top of the region.
+    fn row_bytes(&self, row: usize) -> &'a [u8] {
+        let bpp = self.format.bytes_per_pixel();
+        let start = (self.region.y + row) * self.stride + self.region.x * bpp;
+        let end = (start + self.region.width * bpp).min(self.data.len());
+        if start >= end {
+            return &[];
+        }
+        &self.data[start..end]
+    }
+
+    /// 8-bit R, G, B of one pixel after color correction
+    fn read_rgb(&self, px: &[u8]) -> [u8; 3] {
+        let rgb = self.format.read_rgb(px);
+        match self.color {
+            Some(lut) => lut.apply(rgb),
+            None => rgb,
+        }
+    }
+
+    /// Convert one row of the region to RGB565, applying the dither mode
+    pub fn rgb565_row(&self, row: usize, out: &mut Vec<u16>) {
+        out.clear();
+        let format = self.format;
+        let bpp = format.bytes_per_pixel();
+        let x0 = self.region.x;
+        let y = self.region.y + row;
+        let pixels = self.row_bytes(row).chunks_exact(bpp);
+
+        match self.dither {
+            _ if format == PixelFormat::Rgb565 && self.color.is_none() => {
+                out.extend(pixels.map(|px| format.read_rgb565(px)));
+            }
+            DitherMode::None if self.color.is_none() => {
+                out.extend(pixels.map(|px| format.read_rgb565(px)))
+            }
+            DitherMode::None => out.extend(pixels.map(|px| {
+                let [r, g, b] = self.read_rgb(px);
+                rgb888_to_rgb565(r, g, b)
+            })),
+            DitherMode::Ordered => out.extend(
+                pixels
+                    .enumerate()
+                    .map(|(i, px)| ordered_rgb565(self.read_rgb(px), x0 + i, y)),
+            ),
+            DitherMode::ErrorDiffusion => {
+                // Replay the part of the span left of the region so the
+                // region continues with the same error a full update has
+                let span_start = x0 / DIFFUSION_SPAN * DIFFUSION_SPAN;
+                let lead_start = y * self.stride + span_start * bpp;
+                let lead_end = (lead_start + (x0 - span_start) * bpp).min(self.data.len());
+                let lead = self.data.get(lead_start..lead_end).unwrap_or(&[]);
+                let mut state = DiffusionState::default();
+                for (x, px) in (span_start..).zip(lead.chunks_exact(bpp).chain(pixels)) {
+                    if x % DIFFUSION_SPAN == 0 {
+                        state.reset(x, y);
+                    }
+                    let pixel = state.push(self.read_rgb(px));
+                    if x >= x0 {
+                        out.push(pixel);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Convert one row of the region to 8-bit R, G, B triplets
+    pub fn rgb888_row(&self, row: usize, out: &mut Vec<[u8; 3]>) {
+        out.clear();
+        out.extend(
+            self.row_bytes(row)
+                .chunks_exact(self.format.bytes_per_pixel())
+                .map(|px| self.read_rgb(px)),
+        );
+    }
+}
+
+/// Available codecs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EncoderKind {
+    /// Uncompressed RGB565
+    Raw16,
+    /// RGB565 run-length encoding with raw (RLX) spans
+    Rle,
+    /// Huffman-coded RGB565 deltas
+    Huffman,
+    /// Uncompressed 24bpp RGB
+    Raw24,
+}
+
+impl EncoderKind {
+    /// Parse a codec name as used in DISPLAYLINK_ENCODER
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "raw16" | "raw" => Some(EncoderKind::Raw16),
+            "rle" | "rlx" => Some(EncoderKind::Rle),
+            "huffman" => Some(EncoderKind::Huffman),
+            "raw24" | "24bpp" => Some(EncoderKind::Raw24),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EncoderKind::Raw16 => "raw16",
+            EncoderKind::Rle => "rle",
+            EncoderKind::Huffman => "huffman",
+            EncoderKind::Raw24 => "raw24",
+        }
+    }
+
+    /// Value selecting this codec in the damage rectangle command
+    pub fn codec(self) -> u16 {
+        match self {
+            EncoderKind::Raw16 => DL_CODEC_RAW16,
+            EncoderKind::Rle => DL_CODEC_RLE,
+            EncoderKind::Huffman => DL_CODEC_HUFFMAN,
+            EncoderKind::Raw24 => DL_CODEC_RAW24,
+        }
+    }
+
+    /// Codec named by a damage rectangle command
+    pub fn from_codec(codec: u16) -> Option<Self> {
+        match codec {
+            DL_CODEC_RAW16 => Some(EncoderKind::Raw16),
+            DL_CODEC_RLE => Some(EncoderKind::Rle),
+            DL_CODEC_HUFFMAN => Some(EncoderKind::Huffman),
+            DL_CODEC_RAW24 => Some(EncoderKind::Raw24),
+            _ => None,
+        }
+    }
+
+    /// Output color depth
+    pub fn bits_per_pixel(self) -> u32 {
+        match self {
+            EncoderKind::Raw24 => 24,
+            _ => 16,
+        }
+    }
+
+    /// Whether a device can decode this codec
+    pub fn is_supported(self, caps: &DeviceCapabilities) -> bool {
+        match self {
+            EncoderKind::Raw16 => true,
+            EncoderKind::Rle => caps.supports_rle,
+            EncoderKind::Huffman => caps.supports_huffman,
+            EncoderKind::Raw24 => caps.supports_24bpp,
+        }
+    }
+
+    /// Instantiate an encoder of this kind
+    pub fn create(self) -> Box<dyn Encoder> {
+        match self {
+            EncoderKind::Raw16 => Box::new(Raw16Encoder::new()),
+            EncoderKind::Rle => Box::new(RleEncoder::new()),
+            EncoderKind::Huffman => Box::new(HuffmanEncoder::new()),
+            EncoderKind::Raw24 => Box::new(Raw24Encoder::new()),
+        }
+    }
+}
+
+/// Pick the codec for a device
+///
+/// `requested` is the configured codec (None = automatic). A requested codec
+/// the device can't decode falls back to the automatic choice.
+pub fn select_encoder(caps: &DeviceCapabilities, requested: Option<EncoderKind>) -> EncoderKind {
+    if let Some(kind) = requested {
+        if kind.is_supported(caps) {
+            return kind;
+        }
+        eprintln!(
+            "Encoder '{}' not supported by device {:04X}, using automatic selection",
+            kind.name(),
+            caps.product_id
+        );
+    }
+
+    if caps.supports_rle {
+        EncoderKind::Rle
+    } else {
+        EncoderKind::Raw16
+    }
+}
+
+/// Frame region encoder
+pub trait Encoder: Send {
+    fn kind(&self) -> EncoderKind;
+
+    /// Append the command stream for `input.region` to `out`
+    fn encode(&mut self, input: &EncoderInput, out: &mut Vec<u8>);
+}
+
+/// Append the damage rectangle that addresses a region on the device
+fn write_region_header(
+    cmd_builder: &mut CommandBuilder,
+    kind: EncoderKind,
+    region: &Rect,
+    out: &mut Vec<u8>,
+) {
+    out.extend_from_slice(cmd_builder.damage_rect(
+        kind.codec(),
+        region.x as u16,
+        region.y as u16,
+        region.width as u16,
+        region.height as u16,
+    ));
+}
+
+/// Uncompressed RGB565 encoder
+pub struct Raw16Encoder {
+    cmd_builder: CommandBuilder,
+    row: Vec<u16>,
+}
+
+impl Raw16Encoder {
+    pub fn new() -> Self {
+        Raw16Encoder {
+            cmd_builder: CommandBuilder::new(),
+            row: Vec::new(),
+        }
+    }
+}
+
+impl Encoder for Raw16Encoder {
+    fn kind(&self) -> EncoderKind {
+        EncoderKind::Raw16
+    }
+
+    fn encode(&mut self, input: &EncoderInput, out: &mut Vec<u8>) {
+        write_region_header(
+            &mut self.cmd_builder,
+            EncoderKind::Raw16,
+            &input.region,
+            out,
+        );
+        for row in 0..input.region.height {
+            input.rgb565_row(row, &mut self.row);
+            for pixel in &self.row {
+                out.extend_from_slice(&pixel.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Uncompressed 24bpp encoder (R, G, B byte order)
+pub struct Raw24Encoder {
+    cmd_builder: CommandBuilder,
+    row: Vec<[u8; 3]>,
+}
+
+impl Raw24Encoder {
+    pub fn new() -> Self {
+        Raw24Encoder {
+            cmd_builder: CommandBuilder::new(),
+            row: Vec::new(),
+        }
+    }
+}
+
+impl Encoder for Raw24Encoder {
+    fn kind(&self) -> EncoderKind {
+        EncoderKind::Raw24
+    }
+
+    fn encode(&mut self, input: &EncoderInput, out: &mut Vec<u8>) {
+        write_region_header(
+            &mut self.cmd_builder,
+            EncoderKind::Raw24,
+            &input.region,
+            out,
+        );
+        for row in 0..input.region.height {
+            input.rgb888_row(row, &mut self.row);
+            for pixel in &self.row {
+                out.extend_from_slice(pixel);
+            }
+        }
+    }
+}
+
+/// Raw span marker in the RLE stream
+pub const RLE_RAW_MARKER: u8 = 0xAF;
+
+/// Shortest repeat that is emitted as a run instead of inside a raw span
+const RLE_MIN_RUN: usize = 3;
+
+/// RLE/RLX encoder
+///
+/// Repeated pixels become `[count] [pixel]` runs; everything else is grouped
+/// into `[0xAF] [count - 1] [pixels...]` raw spans of up to 256 pixels. Run
+/// counts never equal the raw span marker, so the stream is unambiguous.
+pub struct RleEncoder {
+    cmd_builder: CommandBuilder,
+    row: Vec<u16>,
+}
+
+impl RleEncoder {
+    pub fn new() -> Self {
+        RleEncoder {
+            cmd_builder: CommandBuilder::new(),
+            row: Vec::new(),
+        }
+    }
+
+    /// Encode one row of RGB565 pixels
+    pub fn encode_pixels(pixels: &[u16], out: &mut Vec<u8>) {
+        let mut i = 0;
+        let mut raw_start = 0;
+
+        while i < pixels.len() {
+            let pixel = pixels[i];
+            let mut run = 1;
+            while i + run < pixels.len() && run < 255 && pixels[i + run] == pixel {
+                run += 1;
+            }
+            if run == RLE_RAW_MARKER as usize {
+                run -= 1;
+            }
+
+            if run >= RLE_MIN_RUN {
+                Self::flush_raw(&pixels[raw_start..i], out);
+                out.push(run as u8);
+                out.extend_from_slice(&pixel.to_le_bytes());
+                i += run;
+                raw_start = i;
+            } else {
+                i += run;
+            }
+        }
+
+        Self::flush_raw(&pixels[raw_start..], out);
+    }
+
+    fn flush_raw(pixels: &[u16], out: &mut Vec<u8>) {
+        for span in pixels.chunks(256) {
+            out.push(RLE_RAW_MARKER);
+            out.push((span.len() - 1) as u8);
+            for pixel in span {
+                out.extend_from_slice(&pixel.to_le_bytes());
+            }
+        }
+    }
+}
+
+impl Encoder for RleEncoder {
+    fn kind(&self) -> EncoderKind {
+        EncoderKind::Rle
+    }
+
+    fn encode(&mut self, input: &EncoderInput, out: &mut Vec<u8>) {
+        write_region_header(&mut self.cmd_builder, EncoderKind::Rle, &input.region, out);
+        for row in 0..input.region.height {
+            input.rgb565_row(row, &mut self.row);
+            Self::encode_pixels(&self.row, out);
+        }
+    }
+}
+
+/// Huffman code lengths for delta magnitude classes 0..=16
+///
+/// Class 0 is "same as previous pixel"; class k (1..=16) is followed by k
+/// extra bits holding the signed delta, JPEG DC-coefficient style.
+pub const HUFFMAN_CODE_LENGTHS: [u8; 17] = [1, 3, 3, 4, 4, 5, 5, 6, 6, 7, 8, 9, 10, 11, 12, 13, 13];
+
+/// Canonical Huffman code table built from `HUFFMAN_CODE_LENGTHS`
+pub fn huffman_codes() -> [(u16, u8); 17] {
+    let mut symbols: Vec<usize> = (0..HUFFMAN_CODE_LENGTHS.len()).collect();
+    symbols.sort_by_key(|&s| (HUFFMAN_CODE_LENGTHS[s], s));
+
+    let mut codes = [(0u16, 0u8); 17];
+    let mut code = 0u16;
+    let mut prev_len = HUFFMAN_CODE_LENGTHS[symbols[0]];
+    for (i, &symbol) in symbols.iter().enumerate() {
+        let len = HUFFMAN_CODE_LENGTHS[symbol];
+        if i > 0 {
+            code = (code + 1) << (len - prev_len);
+        }
+        codes[symbol] = (code, len);
+        prev_len = len;
+    }
+    codes
+}
+
+/// MSB-first bit writer
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        BitWriter {
+            out,
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, count: u32) {
+        if count == 0 {
+            return;
+        }
+        self.acc = (self.acc << count) | (value as u64 & ((1u64 << count) - 1));
+        self.bits += count;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.out.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    /// Pad the final partial byte with zero bits
+    fn finish(mut self) {
+        if self.bits > 0 {
+            let pad = 8 - self.bits;
+            self.write(0, pad);
+        }
+    }
+}
+
+/// Huffman delta encoder
+///
+/// Each pixel is coded as the wrapping RGB565 difference from the previous
+/// pixel in the region (the first pixel of each region is relative to 0).
+/// The bitstream is padded to a byte boundary at the end of each region.
+pub struct HuffmanEncoder {
+    cmd_builder: CommandBuilder,
+    codes: [(u16, u8); 17],
+    row: Vec<u16>,
+}
+
+impl HuffmanEncoder {
+    pub fn new() -> Self {
+        HuffmanEncoder {
+            cmd_builder: CommandBuilder::new(),
+            codes: huffman_codes(),
+            row: Vec::new(),
+        }
+    }
+
+    /// Magnitude class and extra bits for a signed delta
+    pub fn delta_class(delta: i32) -> (usize, u32) {
+        if delta == 0 {
+            return (0, 0);
+        }
+        let class = 32 - delta.unsigned_abs().leading_zeros();
+        let bits = if delta < 0 {
+            (delta + (1 << class) - 1) as u32
+        } else {
+            delta as u32
+        };
+        (class as usize, bits)
+    }
+}
+
+impl Encoder for HuffmanEncoder {
+    fn kind(&self) -> EncoderKind {
+        EncoderKind::Huffman
+    }
+
+    fn encode(&mut self, input: &EncoderInput, out: &mut Vec<u8>) {
+        write_region_header(
+            &mut self.cmd_builder,
+            EncoderKind::Huffman,
+            &input.region,
+            out,
+        );
+
+        let mut writer = BitWriter::new(out);
+        let mut prev = 0u16;
+        for row in 0..input.region.height {
+            input.rgb565_row(row, &mut self.row);
+            for &pixel in &self.row {
+                let delta = pixel.wrapping_sub(prev) as i16 as i32;
+                let (class, bits) = Self::delta_class(delta);
+                let (code, len) = self.codes[class];
+                writer.write(code as u32, len as u32);
+                writer.write(bits, class as u32);
+                prev = pixel;
+            }
+        }
+        writer.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::PID_DL3XXX;
+
+    fn input(data: &[u8], width: usize, height: usize) -> EncoderInput<'_> {
+        EncoderInput {
+            data,
+            stride: width * 4,
+            format: PixelFormat::Xrgb8888,
+            region: Rect::new(0, 0, width, height),
+            dither: DitherMode::None,
+            color: None,
+        }
+    }
+
+    fn header_len() -> usize {
+        CommandBuilder::new().damage_rect(0, 0, 0, 0, 0).len()
+    }
+
+    #[test]
+    fn test_rect_union_and_clamp() {
+        let a = Rect::new(10, 10, 5, 5);
+        let b = Rect::new(0, 12, 4, 10);
+        assert_eq!(a.union(&b), Rect::new(0, 10, 15, 12));
+        assert_eq!(a.union(&Rect::default()), a);
+        assert_eq!(b.clamp_to(3, 15), Rect::new(0, 12, 3, 3));
+        assert!(a.clamp_to(8, 8).is_empty());
+        assert_eq!(a.intersect(&b), Rect::default());
+        assert_eq!(
+            a.intersect(&Rect::new(12, 0, 10, 12)),
+            Rect::new(12, 10, 3, 2)
+        );
+    }
+
+    #[test]
+    fn test_raw16_and_raw24_sizes() {
+        let frame = vec![0x80u8; 4 * 3 * 4];
+        let mut out = Vec::new();
+        Raw16Encoder::new().encode(&input(&frame, 4, 3), &mut out);
+        assert_eq!(out.len(), header_len() + 4 * 3 * 2);
+
+        out.clear();
+        Raw24Encoder::new().encode(&input(&frame, 4, 3), &mut out);
+        assert_eq!(out.len(), header_len() + 4 * 3 * 3);
+    }
+
+    #[test]
+    fn test_padded_stride_matches_packed() {
+        let width = 3;
+        let height = 2;
+        let packed: Vec<u8> = (0..width * height * 4).map(|i| (i * 7) as u8).collect();
+        let stride = 64;
+        let mut padded = vec![0xEEu8; stride * height];
+        for y in 0..height {
+            padded[y * stride..y * stride + width * 4]
+                .copy_from_slice(&packed[y * width * 4..(y + 1) * width * 4]);
+        }
+
+        let mut expected = Vec::new();
+        Raw16Encoder::new().encode(&input(&packed, width, height), &mut expected);
+        let mut out = Vec::new();
+        let padded_input = EncoderInput {
+            data: &padded,
+            stride,
+            format: PixelFormat::Xrgb8888,
+            region: Rect::new(0, 0, width, height),
+            dither: DitherMode::None,
+            color: None,
+        };
+        Raw16Encoder::new().encode(&padded_input, &mut out);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_subregion_with_rgb565_source() {
+        // 4x2 RGB565 frame, encode the 2x1 region at (1, 1)
+        let mut frame = vec![0u8; 4 * 2 * 2];
+        frame[(4 + 1) * 2..(4 + 1) * 2 + 2].copy_from_slice(&0xF800u16.to_le_bytes());
+        frame[(4 + 2) * 2..(4 + 2) * 2 + 2].copy_from_slice(&0x001Fu16.to_le_bytes());
+        let region_input = EncoderInput {
+            data: &frame,
+            stride: 8,
+            format: PixelFormat::Rgb565,
+            region: Rect::new(1, 1, 2, 1),
+            dither: DitherMode::None,
+            color: None,
+        };
+
+        let mut out = Vec::new();
+        Raw16Encoder::new().encode(&region_input, &mut out);
+        assert_eq!(&out[header_len()..], &[0x00, 0xF8, 0x1F, 0x00]);
+    }
+
+    #[test]
+    fn test_color_correction_applies_before_conversion() {
+        let invert = ColorLut::from_fn(|_, x| 1.0 - x);
+        let red = [0u8, 0, 0xFF, 0];
+        let corrected = EncoderInput {
+            color: Some(&invert),
+            ..input(&red, 1, 1)
+        };
+        let mut rgb = Vec::new();
+        corrected.rgb888_row(0, &mut rgb);
+        assert_eq!(rgb, vec![[0, 0xFF, 0xFF]]);
+
+        // RGB565 sources no longer pass straight through
+        let red565 = 0xF800u16.to_le_bytes();
+        let corrected = EncoderInput {
+            data: &red565,
+            stride: 2,
+            format: PixelFormat::Rgb565,
+            ..corrected
+        };
+        let mut row = Vec::new();
+        corrected.rgb565_row(0, &mut row);
+        assert_eq!(row, vec![0x07FF]);
+    }
+
+    fn gradient(width: usize, height: usize) -> Vec<u8> {
+        (0..width * height)
+            .flat_map(|i| {
+                let v = (i % width * 255 / width) as u8;
+                [v, v / 2, v, 0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dither_has_no_seams_at_region_edges() {
+        let width = 40;
+        let frame = gradient(width, 2);
+        for dither in [DitherMode::Ordered, DitherMode::ErrorDiffusion] {
+            let full = EncoderInput {
+                dither,
+                ..input(&frame, width, 2)
+            };
+            let mut expected = Vec::new();
+            full.rgb565_row(1, &mut expected);
+
+            // Split the row at an offset that isn't span-aligned
+            let mut pieces = Vec::new();
+            let mut row = Vec::new();
+            for region in [Rect::new(0, 1, 21, 1), Rect::new(21, 1, width - 21, 1)] {
+                let part = EncoderInput { region, ..full };
+                part.rgb565_row(0, &mut row);
+                pieces.extend_from_slice(&row);
+            }
+            assert_eq!(pieces, expected, "{:?}", dither);
+        }
+    }
+
+    #[test]
+    fn test_error_diffusion_tolerates_short_buffer() {
+        let width = 40;
+        let frame = gradient(width, 2);
+        // Second row cut off partway through the span left of the region
+        let short = &frame[..(width + 10) * 4];
+        let part = EncoderInput {
+            dither: DitherMode::ErrorDiffusion,
+            region: Rect::new(21, 1, width - 21, 1),
+            ..input(short, width, 2)
+        };
+        let mut row = vec![0x1234];
+        part.rgb565_row(0, &mut row);
+        assert!(row.is_empty());
+        // Entirely past the end
+        let part = EncoderInput {
+            region: Rect::new(21, 3, width - 21, 1),
+            ..part
+        };
+        part.rgb565_row(0, &mut row);
+        assert!(row.is_empty());
+    }
+
+    #[test]
+    fn test_dither_is_stable_across_frames() {
+        let frame = gradient(32, 4);
+        let dithered = EncoderInput {
+            dither: DitherMode::ErrorDiffusion,
+            ..input(&frame, 32, 4)
+        };
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+        RleEncoder::new().encode(&dithered, &mut first);
+        RleEncoder::new().encode(&dithered, &mut second);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_rle_runs_and_raw_spans() {
+        let mut out = Vec::new();
+        RleEncoder::encode_pixels(&[0xF800, 0xF800, 0xF800, 0x07E0, 0x001F], &mut out);
+        assert_eq!(
+            out,
+            vec![3, 0x00, 0xF8, RLE_RAW_MARKER, 1, 0xE0, 0x07, 0x1F, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_rle_never_emits_marker_as_run_length() {
+        let pixels = vec![0x1234u16; RLE_RAW_MARKER as usize];
+        let mut out = Vec::new();
+        RleEncoder::encode_pixels(&pixels, &mut out);
+        assert_eq!(out[0], RLE_RAW_MARKER - 1);
+        assert_eq!(out[3], RLE_RAW_MARKER);
+        assert_eq!(out[4], 0);
+    }
+
+    #[test]
+    fn test_huffman_codes_are_prefix_free() {
+        let codes = huffman_codes();
+        for (a, &(code_a, len_a)) in codes.iter().enumerate() {
+            for (b, &(code_b, len_b)) in codes.iter().enumerate() {
+                if a != b && len_a <= len_b {
+                    assert_ne!(code_b >> (len_b - len_a), code_a, "{} prefixes {}", a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_huffman_solid_region_is_small() {
+        let frame: Vec<u8> = [0u8, 0, 255, 255].repeat(64 * 8);
+        let mut out = Vec::new();
+        HuffmanEncoder::new().encode(&input(&frame, 64, 8), &mut out);
+        // One class-16 code for the first pixel, then 1 bit per pixel
+        assert!(out.len() - header_len() <= 70);
+    }
+
+    #[test]
+    fn test_delta_class() {
+        assert_eq!(HuffmanEncoder::delta_class(0), (0, 0));
+        assert_eq!(HuffmanEncoder::delta_class(1), (1, 1));
+        assert_eq!(HuffmanEncoder::delta_class(-1), (1, 0));
+        assert_eq!(HuffmanEncoder::delta_class(-3), (2, 0));
+        assert_eq!(HuffmanEncoder::delta_class(5), (3, 5));
+    }
+
+    #[test]
+    fn test_select_encoder() {
+        let dl3 = DeviceCapabilities::for_product(PID_DL3XXX);
+        let basic = DeviceCapabilities::for_product(0x0001);
+        let huffman = DeviceCapabilities {
+            supports_huffman: true,
+            ..dl3
+        };
+
+        assert_eq!(select_encoder(&dl3, None), EncoderKind::Rle);
+        assert_eq!(
+            select_encoder(&dl3, Some(EncoderKind::Huffman)),
+            EncoderKind::Rle
+        );
+        assert_eq!(
+            select_encoder(&huffman, Some(EncoderKind::Huffman)),
+            EncoderKind::Huffman
+        );
+        assert_eq!(
+            select_encoder(&basic, Some(EncoderKind::Raw24)),
+            EncoderKind::Rle
+        );
+        assert_eq!(EncoderKind::parse("RLX"), Some(EncoderKind::Rle));
+        assert_eq!(EncoderKind::parse("bogus"), None);
+    }
+}
@@ -0,0 +1,561 @@
+// Safe EVDI card wrapper
+//
+// `EvdiCard` owns an open EVDI card together with the framebuffers
+// registered on it, and undoes connect/register/open when dropped. Events are
+// copied into a queue and dispatched to an `EvdiEventHandler` after the
+// backend returns, so handlers are ordinary Rust methods that may call back
+// into the card.
+//
+// The card is driven through libevdi by default; the `ioctl-backend` feature
+// swaps in a pure-Rust backend that issues the kernel module's ioctls itself.
+// Both backends expose the same `Device` type and functions.
+
+use crate::encoder::Rect;
+use std::alloc::{self, Layout};
+use std::ffi::c_int;
+use std::ptr::NonNull;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(not(any(feature = "libevdi", feature = "ioctl-backend")))]
+compile_error!("enable the `libevdi` or `ioctl-backend` feature");
+
+// Always built for tests so the event parser is covered either way
+#[cfg(any(feature = "ioctl-backend", test))]
+#[cfg_attr(not(feature = "ioctl-backend"), allow(dead_code))]
+mod ioctl;
+#[cfg(all(feature = "libevdi", not(feature = "ioctl-backend")))]
+mod libevdi;
+
+#[cfg(feature = "ioctl-backend")]
+use ioctl as backend;
+#[cfg(all(feature = "libevdi", not(feature = "ioctl-backend")))]
+use libevdi as backend;
+
+/// Maximum number of dirty rectangles returned by a grab
+pub const MAX_DIRTY_RECTS: usize = 16;
+
+/// Card numbers EVDI can hand out (EVDI_USAGE_LEN in libevdi)
+pub const MAX_CARDS: i32 = 64;
+
+// How long a freshly added card gets to appear under /dev/dri
+const ADD_DEVICE_TIMEOUT: Duration = Duration::from_secs(2);
+const ADD_DEVICE_POLL: Duration = Duration::from_millis(50);
+
+/// Which backend drives the card, with its version
+pub fn backend_description() -> String {
+    backend::describe()
+}
+
+/// Send the backend's own messages to the driver log
+pub fn install_logging() {
+    backend::install_logging();
+}
+
+/// State of a DRM card number as seen by evdi_check_device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceStatus {
+    /// An EVDI card
+    Available,
+    /// A card that belongs to another DRM driver
+    Unrecognized,
+    NotPresent,
+}
+
+pub fn check_device(card_no: i32) -> DeviceStatus {
+    backend::check_device(card_no)
+}
+
+/// Lowest EVDI card number for which `in_use` is false
+fn first_free_card(
+    in_use: impl Fn(i32) -> bool,
+    status: impl Fn(i32) -> DeviceStatus,
+) -> Option<i32> {
+    (0..MAX_CARDS).find(|&card_no| !in_use(card_no) && status(card_no) == DeviceStatus::Available)
+}
+
+/// Pick an EVDI card for a new display
+///
+/// Cards left over from earlier runs or unplugged docks are reused; a new
+/// card is only added when every existing one is `in_use`.
+pub fn claim_card(in_use: impl Fn(i32) -> bool) -> Result<i32, String> {
+    if let Some(card_no) = first_free_card(&in_use, check_device) {
+        println!("  Reusing EVDI device: /dev/dri/card{}", card_no);
+        return Ok(card_no);
+    }
+
+    if !backend::add_device() {
+        return Err("Failed to add EVDI device".to_string());
+    }
+    let card_no = wait_for_card(|| first_free_card(&in_use, check_device))
+        .ok_or("Added EVDI device did not appear")?;
+    println!("  Created EVDI device: /dev/dri/card{}", card_no);
+    Ok(card_no)
+}
+
+// Poll `find` until a freshly added card shows up; udev creates the node
+// asynchronously
+fn wait_for_card(find: impl Fn() -> Option<i32>) -> Option<i32> {
+    let deadline = Instant::now() + ADD_DEVICE_TIMEOUT;
+    loop {
+        if let Some(card_no) = find() {
+            return Some(card_no);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        thread::sleep(ADD_DEVICE_POLL);
+    }
+}
+
+/// Parent device string EVDI expects for a USB device, e.g. "usb:1-2.3"
+///
+/// `ports` is the port chain from the root hub down; None for root hubs.
+pub fn usb_parent_path(bus: u8, ports: &[u8]) -> Option<String> {
+    if ports.is_empty() {
+        return None;
+    }
+    let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+    Some(format!("usb:{}-{}", bus, ports.join(".")))
+}
+
+// DRM primary nodes use the card number as their minor
+fn card_number(fd: c_int) -> Option<i32> {
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stat = unsafe { stat.assume_init() };
+    if stat.st_mode & libc::S_IFMT != libc::S_IFCHR {
+        return None;
+    }
+    Some(libc::minor(stat.st_rdev) as i32)
+}
+
+/// Display mode requested by the compositor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mode {
+    pub width: i32,
+    pub height: i32,
+    pub refresh_rate: i32,
+    pub bits_per_pixel: i32,
+    /// DRM fourcc code
+    pub pixel_format: u32,
+}
+
+/// Cursor image set by the compositor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CursorSet {
+    pub hot_x: i32,
+    pub hot_y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub enabled: bool,
+    /// DRM fourcc code of `pixels`
+    pub pixel_format: u32,
+    /// Bytes per cursor row
+    pub stride: u32,
+    pub pixels: Vec<u32>,
+}
+
+/// DDC/CI request from the compositor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DdcciData {
+    pub address: u16,
+    pub flags: u16,
+    pub data: Vec<u8>,
+}
+
+/// Event reported by the EVDI kernel module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EvdiEvent {
+    Dpms(i32),
+    ModeChanged(Mode),
+    UpdateReady(i32),
+    CrtcState(i32),
+    CursorSet(CursorSet),
+    CursorMove { x: i32, y: i32 },
+    Ddcci(DdcciData),
+}
+
+impl EvdiEvent {
+    /// Hand the event to the matching handler method
+    pub fn dispatch<H: EvdiEventHandler + ?Sized>(self, handler: &mut H) {
+        match self {
+            EvdiEvent::Dpms(mode) => handler.dpms(mode),
+            EvdiEvent::ModeChanged(mode) => handler.mode_changed(mode),
+            EvdiEvent::UpdateReady(buffer_id) => handler.update_ready(buffer_id),
+            EvdiEvent::CrtcState(state) => handler.crtc_state(state),
+            EvdiEvent::CursorSet(cursor) => handler.cursor_set(cursor),
+            EvdiEvent::CursorMove { x, y } => handler.cursor_move(x, y),
+            EvdiEvent::Ddcci(data) => handler.ddcci(data),
+        }
+    }
+}
+
+/// Receiver of EVDI events; unhandled events are ignored
+pub trait EvdiEventHandler {
+    fn dpms(&mut self, _mode: i32) {}
+    fn mode_changed(&mut self, _mode: Mode) {}
+    fn update_ready(&mut self, _buffer_id: i32) {}
+    fn crtc_state(&mut self, _state: i32) {}
+    fn cursor_set(&mut self, _cursor: CursorSet) {}
+    fn cursor_move(&mut self, _x: i32, _y: i32) {}
+    fn ddcci(&mut self, _data: DdcciData) {}
+}
+
+/// Size of a memory page
+pub fn page_size() -> usize {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 {
+        size as usize
+    } else {
+        4096
+    }
+}
+
+/// Zeroed, page-aligned memory whose address never changes
+struct PageBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+impl PageBuffer {
+    fn new(len: usize) -> Self {
+        let page = page_size();
+        // Whole pages, so the kernel never shares a page with other data
+        let size = len.max(1).div_ceil(page) * page;
+        let layout = Layout::from_size_align(size, page).expect("framebuffer too large");
+        let ptr = NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+            .unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        PageBuffer { ptr, len, layout }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+}
+
+impl Drop for PageBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+/// Lowest buffer ID not in `used`
+fn lowest_free_id(used: impl Iterator<Item = i32> + Clone) -> i32 {
+    (0..)
+        .find(|id| !used.clone().any(|used_id| used_id == *id))
+        .unwrap()
+}
+
+// Dirty rectangle from corner coordinates
+fn to_rect(x1: i32, y1: i32, x2: i32, y2: i32) -> Rect {
+    Rect::new(
+        x1.max(0) as usize,
+        y1.max(0) as usize,
+        (x2 - x1).max(0) as usize,
+        (y2 - y1).max(0) as usize,
+    )
+}
+
+/// Framebuffer memory registered with EVDI
+///
+/// Storage is page-aligned and owned by the buffer, so EVDI's pointer into it
+/// stays valid until the buffer is unregistered.
+pub struct EvdiBuffer {
+    id: i32,
+    width: usize,
+    height: usize,
+    stride: usize,
+    data: PageBuffer,
+}
+
+impl EvdiBuffer {
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Bytes per row
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Pixels as last grabbed from EVDI
+    pub fn data(&self) -> &[u8] {
+        self.data.as_slice()
+    }
+}
+
+/// Open EVDI card
+pub struct EvdiCard {
+    device: backend::Device,
+    card_no: i32,
+    connected: bool,
+    buffers: Vec<EvdiBuffer>,
+    // Buffer named in the last request_update, which the next grab fills
+    requested: Option<i32>,
+    parent: Option<String>,
+}
+
+impl EvdiCard {
+    /// Open an existing EVDI card
+    pub fn open(card_no: i32) -> Result<Self, String> {
+        Ok(EvdiCard {
+            device: backend::Device::open(card_no)?,
+            card_no,
+            connected: false,
+            buffers: Vec::new(),
+            requested: None,
+            parent: None,
+        })
+    }
+
+    /// Open a card attached to the USB device `usb_path` (see
+    /// `usb_parent_path`), adding one if none is free
+    ///
+    /// The card's sysfs `device` link then points at the dock, and the
+    /// kernel removes the card when the dock is unplugged. Cards for which
+    /// `in_use` is true are skipped.
+    pub fn open_attached_to(usb_path: &str, in_use: impl Fn(i32) -> bool) -> Result<Self, String> {
+        let device = backend::Device::open_attached_to(usb_path, &in_use)?;
+        let card_no = card_number(device.event_fd())
+            .ok_or_else(|| format!("EVDI device for {} is not a DRM node", usb_path))?;
+        Ok(EvdiCard {
+            device,
+            card_no,
+            connected: false,
+            buffers: Vec::new(),
+            requested: None,
+            parent: Some(usb_path.to_string()),
+        })
+    }
+
+    /// Number of /dev/dri/cardN
+    pub fn card_no(&self) -> i32 {
+        self.card_no
+    }
+
+    /// USB device the card is attached to, if any
+    pub fn parent(&self) -> Option<&str> {
+        self.parent.as_deref()
+    }
+
+    /// Plug in the virtual monitor described by `edid`
+    ///
+    /// Modes larger than `pixel_area_limit` or faster than
+    /// `pixel_per_second_limit` are not offered; a zero rate limit lifts both.
+    pub fn connect(&mut self, edid: &[u8], pixel_area_limit: u32, pixel_per_second_limit: u32) {
+        self.device
+            .connect(edid, pixel_area_limit, pixel_per_second_limit);
+        self.connected = true;
+    }
+
+    /// Unplug the virtual monitor
+    pub fn disconnect(&mut self) {
+        if self.connected {
+            self.device.disconnect();
+            self.connected = false;
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub fn enable_cursor_events(&mut self, enable: bool) {
+        self.device.enable_cursor_events(enable);
+    }
+
+    /// Allocate a framebuffer and register it with EVDI
+    ///
+    /// IDs of unregistered buffers are handed out again.
+    pub fn register_buffer(&mut self, width: usize, height: usize, stride: usize) -> i32 {
+        let id = lowest_free_id(self.buffers.iter().map(|b| b.id));
+
+        let mut buffer = EvdiBuffer {
+            id,
+            width,
+            height,
+            stride,
+            data: PageBuffer::new(stride * height),
+        };
+        self.device.register_buffer(&mut buffer);
+        self.buffers.push(buffer);
+        id
+    }
+
+    /// Unregister a framebuffer and free its memory
+    pub fn unregister_buffer(&mut self, id: i32) {
+        if let Some(index) = self.buffers.iter().position(|b| b.id == id) {
+            self.device.unregister_buffer(id);
+            self.buffers.remove(index);
+            if self.requested == Some(id) {
+                self.requested = None;
+            }
+        }
+    }
+
+    pub fn buffer(&self, id: i32) -> Option<&EvdiBuffer> {
+        self.buffers.iter().find(|b| b.id == id)
+    }
+
+    /// Ask for the next frame in `buffer_id`
+    ///
+    /// Returns true if an update is already pending and can be grabbed now;
+    /// otherwise an `UpdateReady` event follows.
+    pub fn request_update(&mut self, buffer_id: i32) -> bool {
+        if self.buffer(buffer_id).is_none() {
+            return false;
+        }
+        self.requested = Some(buffer_id);
+        self.device.request_update(buffer_id)
+    }
+
+    /// Copy the pending frame into the requested buffer
+    ///
+    /// `damage` is replaced with the changed rectangles.
+    pub fn grab_pixels(&mut self, damage: &mut Vec<Rect>) {
+        damage.clear();
+        let Some(id) = self.requested else {
+            return;
+        };
+        if let Some(buffer) = self.buffers.iter_mut().find(|b| b.id == id) {
+            self.device.grab_pixels(buffer, damage);
+        }
+    }
+
+    /// Answer a DDC/CI request
+    pub fn ddcci_response(&mut self, data: &[u8], success: bool) {
+        self.device.ddcci_response(data, success);
+    }
+
+    /// Wait up to `timeout` for events
+    ///
+    /// Returns Ok(false) on timeout and an error once the card is gone.
+    pub fn wait_for_event(&self, timeout: Duration) -> Result<bool, String> {
+        let mut pollfd = libc::pollfd {
+            fd: self.device.event_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().min(c_int::MAX as u128) as c_int;
+        let rc = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if rc < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(format!("Failed to poll EVDI events: {}", err));
+        }
+        if pollfd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+            return Err("EVDI card was closed".to_string());
+        }
+        Ok(pollfd.revents & libc::POLLIN != 0)
+    }
+
+    /// Read pending events into `events`
+    ///
+    /// Blocks until at least one event arrives; call `wait_for_event` first.
+    pub fn handle_events(&mut self, events: &mut Vec<EvdiEvent>) {
+        self.device.handle_events(events);
+    }
+}
+
+impl Drop for EvdiCard {
+    fn drop(&mut self) {
+        self.disconnect();
+        for buffer in self.buffers.drain(..) {
+            self.device.unregister_buffer(buffer.id);
+        }
+        // The device closes itself
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder {
+        updates: Vec<i32>,
+        moves: Vec<(i32, i32)>,
+    }
+
+    impl EvdiEventHandler for Recorder {
+        fn update_ready(&mut self, buffer_id: i32) {
+            self.updates.push(buffer_id);
+        }
+
+        fn cursor_move(&mut self, x: i32, y: i32) {
+            self.moves.push((x, y));
+        }
+    }
+
+    #[test]
+    fn test_page_buffer_is_aligned_and_zeroed() {
+        let buffer = PageBuffer::new(1920 * 4 * 3 + 7);
+        assert_eq!(buffer.ptr.as_ptr() as usize % page_size(), 0);
+        assert_eq!(buffer.layout.size() % page_size(), 0);
+        assert_eq!(buffer.as_slice().len(), 1920 * 4 * 3 + 7);
+        assert!(buffer.as_slice().iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_buffer_ids_are_reused() {
+        assert_eq!(lowest_free_id([].into_iter()), 0);
+        assert_eq!(lowest_free_id([0, 1, 2].into_iter()), 3);
+        // ID 1 was unregistered
+        assert_eq!(lowest_free_id([0, 2].into_iter()), 1);
+    }
+
+    #[test]
+    fn test_first_free_card_skips_claimed_and_foreign_cards() {
+        // card0 is the GPU, card1 is claimed by another dock
+        let status = |card_no| match card_no {
+            0 => DeviceStatus::Unrecognized,
+            1 | 2 => DeviceStatus::Available,
+            _ => DeviceStatus::NotPresent,
+        };
+        assert_eq!(first_free_card(|card_no| card_no == 1, status), Some(2));
+        assert_eq!(first_free_card(|card_no| card_no != 0, status), None);
+        assert_eq!(first_free_card(|_| false, status), Some(1));
+    }
+
+    #[test]
+    fn test_usb_parent_path() {
+        assert_eq!(usb_parent_path(1, &[2]), Some("usb:1-2".to_string()));
+        assert_eq!(
+            usb_parent_path(3, &[1, 4, 2]),
+            Some("usb:3-1.4.2".to_string())
+        );
+        assert_eq!(usb_parent_path(1, &[]), None);
+    }
+
+    #[test]
+    fn test_dispatch_skips_unhandled_events() {
+        let mut recorder = Recorder::default();
+        for event in [
+            EvdiEvent::Dpms(3),
+            EvdiEvent::UpdateReady(1),
+            EvdiEvent::CursorMove { x: 1, y: 2 },
+        ] {
+            event.dispatch(&mut recorder);
+        }
+        assert_eq!(recorder.updates, vec![1]);
+        assert_eq!(recorder.moves, vec![(1, 2)]);
+    }
+}
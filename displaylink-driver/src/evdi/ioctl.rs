@@ -0,0 +1,727 @@
+// Pure-Rust EVDI backend
+//
+// Talks to /dev/dri/cardN through the ioctls and events declared in
+// evdi_source/module/evdi_drm.h, so the driver builds without libevdi, bindgen
+// or a C toolchain. Structures mirror the kernel header field for field.
+// The request encoding is the generic _IOC layout used by x86, ARM and RISC-V.
+
+use super::{
+    to_rect, CursorSet, DdcciData, DeviceStatus, EvdiBuffer, EvdiEvent, Mode, MAX_DIRTY_RECTS,
+};
+use crate::encoder::Rect;
+use std::ffi::{c_char, c_int, c_ulong, c_void};
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::mem::size_of;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+
+// Oldest module release whose ioctls match this file (as libevdi checks)
+const MODULE_VERSION_MAJOR: c_int = 1;
+const MODULE_VERSION_MINOR: c_int = 9;
+
+const DRM_IOCTL_BASE: u32 = b'd' as u32;
+const DRM_COMMAND_BASE: u32 = 0x40;
+
+const fn drm_io(nr: u32) -> c_ulong {
+    ((DRM_IOCTL_BASE << 8) | nr) as c_ulong
+}
+
+const fn drm_iowr<T>(nr: u32) -> c_ulong {
+    ((3 << 30) | ((size_of::<T>() as u32) << 16) | (DRM_IOCTL_BASE << 8) | nr) as c_ulong
+}
+
+const DRM_IOCTL_VERSION: c_ulong = drm_iowr::<DrmVersion>(0x00);
+const DRM_IOCTL_DROP_MASTER: c_ulong = drm_io(0x1f);
+const DRM_IOCTL_MODE_MAP_DUMB: c_ulong = drm_iowr::<DrmModeMapDumb>(0xb3);
+
+const DRM_IOCTL_EVDI_CONNECT: c_ulong = drm_iowr::<DrmEvdiConnect>(DRM_COMMAND_BASE);
+const DRM_IOCTL_EVDI_REQUEST_UPDATE: c_ulong =
+    drm_iowr::<DrmEvdiRequestUpdate>(DRM_COMMAND_BASE + 0x01);
+const DRM_IOCTL_EVDI_GRABPIX: c_ulong = drm_iowr::<DrmEvdiGrabpix>(DRM_COMMAND_BASE + 0x02);
+const DRM_IOCTL_EVDI_DDCCI_RESPONSE: c_ulong =
+    drm_iowr::<DrmEvdiDdcciResponse>(DRM_COMMAND_BASE + 0x03);
+const DRM_IOCTL_EVDI_ENABLE_CURSOR_EVENTS: c_ulong =
+    drm_iowr::<DrmEvdiEnableCursorEvents>(DRM_COMMAND_BASE + 0x04);
+
+const DRM_EVDI_EVENT_UPDATE_READY: u32 = 0x8000_0000;
+const DRM_EVDI_EVENT_DPMS: u32 = 0x8000_0001;
+const DRM_EVDI_EVENT_MODE_CHANGED: u32 = 0x8000_0002;
+const DRM_EVDI_EVENT_CRTC_STATE: u32 = 0x8000_0003;
+const DRM_EVDI_EVENT_CURSOR_SET: u32 = 0x8000_0004;
+const DRM_EVDI_EVENT_CURSOR_MOVE: u32 = 0x8000_0005;
+const DRM_EVDI_EVENT_DDCCI_DATA: u32 = 0x8000_0006;
+
+const EVDI_GRABPIX_MODE_DIRTY: u32 = 1;
+const DDCCI_BUFFER_SIZE: usize = 64;
+
+#[repr(C)]
+struct DrmVersion {
+    version_major: c_int,
+    version_minor: c_int,
+    version_patchlevel: c_int,
+    name_len: usize,
+    name: *mut c_char,
+    date_len: usize,
+    date: *mut c_char,
+    desc_len: usize,
+    desc: *mut c_char,
+}
+
+#[repr(C)]
+struct DrmModeMapDumb {
+    handle: u32,
+    pad: u32,
+    offset: u64,
+}
+
+#[repr(C)]
+struct DrmEvdiConnect {
+    connected: i32,
+    dev_index: i32,
+    edid: *const u8,
+    edid_length: u32,
+    pixel_area_limit: u32,
+    pixel_per_second_limit: u32,
+}
+
+#[repr(C)]
+struct DrmEvdiRequestUpdate {
+    reserved: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct DrmClipRect {
+    x1: u16,
+    y1: u16,
+    x2: u16,
+    y2: u16,
+}
+
+#[repr(C)]
+struct DrmEvdiGrabpix {
+    mode: u32,
+    buf_width: i32,
+    buf_height: i32,
+    buf_byte_stride: i32,
+    buffer: *mut u8,
+    num_rects: i32,
+    rects: *mut DrmClipRect,
+}
+
+#[repr(C)]
+struct DrmEvdiDdcciResponse {
+    buffer: *const u8,
+    buffer_length: u32,
+    result: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DrmEvent {
+    kind: u32,
+    length: u32,
+}
+
+#[repr(C)]
+struct DrmEvdiEnableCursorEvents {
+    base: DrmEvent,
+    enable: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DrmEvdiEventDpms {
+    base: DrmEvent,
+    mode: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DrmEvdiEventModeChanged {
+    base: DrmEvent,
+    hdisplay: i32,
+    vdisplay: i32,
+    vrefresh: i32,
+    bits_per_pixel: i32,
+    pixel_format: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DrmEvdiEventCrtcState {
+    base: DrmEvent,
+    state: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DrmEvdiEventCursorSet {
+    base: DrmEvent,
+    hot_x: i32,
+    hot_y: i32,
+    width: u32,
+    height: u32,
+    enabled: u8,
+    buffer_handle: u32,
+    buffer_length: u32,
+    pixel_format: u32,
+    stride: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DrmEvdiEventCursorMove {
+    base: DrmEvent,
+    x: i32,
+    y: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DrmEvdiEventDdcciData {
+    base: DrmEvent,
+    buffer: [u8; DDCCI_BUFFER_SIZE],
+    buffer_length: u32,
+    flags: u16,
+    address: u16,
+}
+
+pub fn describe() -> String {
+    format!(
+        "kernel ioctls (evdi module {}.{}+)",
+        MODULE_VERSION_MAJOR, MODULE_VERSION_MINOR
+    )
+}
+
+// Errors are reported where the ioctls fail; there is no library log to hook
+pub fn install_logging() {}
+
+/// Ask the kernel module for a new card
+pub fn add_device() -> bool {
+    std::fs::write("/sys/devices/evdi/add", "1").is_ok()
+}
+
+/// Whether card `card_no` exists and belongs to the EVDI platform driver,
+/// decided from sysfs as evdi_check_device does
+pub fn check_device(card_no: i32) -> DeviceStatus {
+    if !Path::new(&format!("/dev/dri/card{}", card_no)).exists() {
+        return DeviceStatus::NotPresent;
+    }
+    let Ok(entries) = std::fs::read_dir("/sys/bus/platform/devices") else {
+        return DeviceStatus::NotPresent;
+    };
+    let card = format!("drm/card{}", card_no);
+    let is_evdi = entries.flatten().any(|entry| {
+        entry.file_name().to_string_lossy().starts_with("evdi") && entry.path().join(&card).exists()
+    });
+    if is_evdi {
+        DeviceStatus::Available
+    } else {
+        DeviceStatus::Unrecognized
+    }
+}
+
+// Card of an EVDI platform device whose parent is the USB device `parent`
+// ("1-2.3"), skipping cards that are `in_use`
+fn find_attached_card(parent: &str, in_use: &dyn Fn(i32) -> bool) -> Option<i32> {
+    let entries = std::fs::read_dir("/sys/bus/platform/devices").ok()?;
+    entries.flatten().find_map(|entry| {
+        if !entry.file_name().to_string_lossy().starts_with("evdi") {
+            return None;
+        }
+        let link = std::fs::read_link(entry.path().join("device")).ok()?;
+        if link.file_name()? != std::ffi::OsStr::new(parent) {
+            return None;
+        }
+        std::fs::read_dir(entry.path().join("drm"))
+            .ok()?
+            .flatten()
+            .filter_map(|card| {
+                card.file_name()
+                    .to_str()?
+                    .strip_prefix("card")?
+                    .parse()
+                    .ok()
+            })
+            .find(|&card_no| !in_use(card_no))
+    })
+}
+
+/// Open EVDI DRM node
+pub struct Device {
+    file: File,
+    card_no: i32,
+    // The kernel doesn't say which buffer an update is for
+    buffer_to_update: i32,
+}
+
+impl Device {
+    pub fn open(card_no: i32) -> Result<Self, String> {
+        let path = format!("/dev/dri/card{}", card_no);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(&path)
+            .map_err(|e| format!("Failed to open EVDI device {}: {}", path, e))?;
+        let device = Device {
+            file,
+            card_no,
+            buffer_to_update: 0,
+        };
+
+        let mut name = [0 as c_char; 64];
+        let mut version = DrmVersion {
+            version_major: 0,
+            version_minor: 0,
+            version_patchlevel: 0,
+            name_len: name.len(),
+            name: name.as_mut_ptr(),
+            date_len: 0,
+            date: ptr::null_mut(),
+            desc_len: 0,
+            desc: ptr::null_mut(),
+        };
+        device
+            .ioctl(DRM_IOCTL_VERSION, &mut version)
+            .map_err(|e| format!("Failed to query {}: {}", path, e))?;
+        let name_len = version.name_len.min(name.len());
+        let name: Vec<u8> = name[..name_len].iter().map(|&c| c as u8).collect();
+        if name != b"evdi" {
+            return Err(format!("{} is not an EVDI device", path));
+        }
+        if version.version_major != MODULE_VERSION_MAJOR
+            || version.version_minor < MODULE_VERSION_MINOR
+        {
+            return Err(format!(
+                "EVDI module {}.{}.{} is not compatible (need {}.{}+)",
+                version.version_major,
+                version.version_minor,
+                version.version_patchlevel,
+                MODULE_VERSION_MAJOR,
+                MODULE_VERSION_MINOR
+            ));
+        }
+
+        // Fails harmlessly if we were never master
+        let _ = device.ioctl(DRM_IOCTL_DROP_MASTER, ptr::null_mut::<c_void>());
+        Ok(device)
+    }
+
+    /// Same lookup as evdi_open_attached_to_fixed: reuse a card linked to
+    /// the USB device, else ask the module to create one under it
+    pub fn open_attached_to(usb_path: &str, in_use: &dyn Fn(i32) -> bool) -> Result<Self, String> {
+        let parent = usb_path
+            .strip_prefix("usb:")
+            .ok_or_else(|| format!("Not a USB parent device: {}", usb_path))?;
+        if let Some(card_no) = find_attached_card(parent, in_use) {
+            return Self::open(card_no);
+        }
+
+        std::fs::write("/sys/devices/evdi/add", usb_path)
+            .map_err(|e| format!("Failed to add EVDI device for {}: {}", usb_path, e))?;
+        let card_no = super::wait_for_card(|| find_attached_card(parent, in_use))
+            .ok_or_else(|| format!("EVDI device for {} did not appear", usb_path))?;
+        Self::open(card_no)
+    }
+
+    // Retries like libevdi's do_ioctl; returns the ioctl's result
+    fn ioctl<T>(&self, request: c_ulong, arg: *mut T) -> Result<c_int, std::io::Error> {
+        loop {
+            let rc = unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, arg) };
+            if rc >= 0 {
+                return Ok(rc);
+            }
+            let err = std::io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+                _ => return Err(err),
+            }
+        }
+    }
+
+    fn connect_ioctl(&mut self, mut cmd: DrmEvdiConnect) {
+        if let Err(e) = self.ioctl(DRM_IOCTL_EVDI_CONNECT, &mut cmd) {
+            eprintln!("EVDI connect ioctl failed: {}", e);
+        }
+    }
+
+    pub fn connect(&mut self, edid: &[u8], pixel_area_limit: u32, pixel_per_second_limit: u32) {
+        self.connect_ioctl(DrmEvdiConnect {
+            connected: 1,
+            dev_index: self.card_no,
+            edid: edid.as_ptr(),
+            edid_length: edid.len() as u32,
+            pixel_area_limit,
+            pixel_per_second_limit,
+        });
+    }
+
+    pub fn disconnect(&mut self) {
+        self.connect_ioctl(DrmEvdiConnect {
+            connected: 0,
+            dev_index: 0,
+            edid: ptr::null(),
+            edid_length: 0,
+            pixel_area_limit: 0,
+            pixel_per_second_limit: 0,
+        });
+    }
+
+    pub fn enable_cursor_events(&mut self, enable: bool) {
+        let mut cmd = DrmEvdiEnableCursorEvents {
+            base: DrmEvent { kind: 0, length: 0 },
+            enable: enable as u8,
+        };
+        if let Err(e) = self.ioctl(DRM_IOCTL_EVDI_ENABLE_CURSOR_EVENTS, &mut cmd) {
+            eprintln!("EVDI enable cursor events ioctl failed: {}", e);
+        }
+    }
+
+    // The kernel takes the destination with every grab, so buffers need no
+    // registration
+    pub fn register_buffer(&mut self, _buffer: &mut EvdiBuffer) {}
+
+    pub fn unregister_buffer(&mut self, _id: i32) {}
+
+    pub fn request_update(&mut self, buffer_id: i32) -> bool {
+        self.buffer_to_update = buffer_id;
+        let mut cmd = DrmEvdiRequestUpdate { reserved: 0 };
+        // 1 means an update is already pending
+        matches!(self.ioctl(DRM_IOCTL_EVDI_REQUEST_UPDATE, &mut cmd), Ok(1))
+    }
+
+    pub fn grab_pixels(&mut self, buffer: &mut EvdiBuffer, damage: &mut Vec<Rect>) {
+        let mut rects = [DrmClipRect::default(); MAX_DIRTY_RECTS];
+        let mut grab = DrmEvdiGrabpix {
+            mode: EVDI_GRABPIX_MODE_DIRTY,
+            buf_width: buffer.width as i32,
+            buf_height: buffer.height as i32,
+            buf_byte_stride: buffer.stride as i32,
+            buffer: buffer.data.as_mut_ptr(),
+            num_rects: MAX_DIRTY_RECTS as i32,
+            rects: rects.as_mut_ptr(),
+        };
+
+        damage.clear();
+        if let Err(e) = self.ioctl(DRM_IOCTL_EVDI_GRABPIX, &mut grab) {
+            // Expected while a mode change is in progress
+            eprintln!("Grabbing pixels for buffer {} failed: {}", buffer.id, e);
+            return;
+        }
+        let count = (grab.num_rects.max(0) as usize).min(MAX_DIRTY_RECTS);
+        damage.extend(rects[..count].iter().map(|rect| {
+            to_rect(
+                rect.x1 as i32,
+                rect.y1 as i32,
+                rect.x2 as i32,
+                rect.y2 as i32,
+            )
+        }));
+    }
+
+    pub fn ddcci_response(&mut self, data: &[u8], success: bool) {
+        let mut cmd = DrmEvdiDdcciResponse {
+            buffer: data.as_ptr(),
+            buffer_length: data.len() as u32,
+            result: success as u8,
+        };
+        if let Err(e) = self.ioctl(DRM_IOCTL_EVDI_DDCCI_RESPONSE, &mut cmd) {
+            eprintln!("EVDI DDC/CI response ioctl failed: {}", e);
+        }
+    }
+
+    pub fn event_fd(&self) -> c_int {
+        self.file.as_raw_fd()
+    }
+
+    pub fn handle_events(&mut self, events: &mut Vec<EvdiEvent>) {
+        let mut buffer = [0u8; 1024];
+        let read = match (&self.file).read(&mut buffer) {
+            Ok(read) => read,
+            Err(e) => {
+                eprintln!("Failed to read EVDI events: {}", e);
+                return;
+            }
+        };
+
+        let mut cursor_failed = false;
+        parse_events(
+            &buffer[..read],
+            self.buffer_to_update,
+            |handle, length| {
+                let pixels = self.read_cursor(handle, length);
+                cursor_failed |= pixels.is_none();
+                pixels
+            },
+            events,
+        );
+        if cursor_failed {
+            // As libevdi does, rather than fail on every cursor update
+            eprintln!("Cursor image unavailable; disabling cursor events");
+            self.enable_cursor_events(false);
+        }
+    }
+
+    // Copy a cursor image out of the dumb buffer the kernel handed us
+    fn read_cursor(&self, handle: u32, length: u32) -> Option<Vec<u32>> {
+        let mut map = DrmModeMapDumb {
+            handle,
+            pad: 0,
+            offset: 0,
+        };
+        if let Err(e) = self.ioctl(DRM_IOCTL_MODE_MAP_DUMB, &mut map) {
+            eprintln!("Failed to map cursor buffer: {}", e);
+            return None;
+        }
+
+        let length = length as usize;
+        let mapped = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                length,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                self.file.as_raw_fd(),
+                map.offset as libc::off_t,
+            )
+        };
+        if mapped == libc::MAP_FAILED {
+            eprintln!(
+                "Failed to mmap cursor buffer: {}",
+                std::io::Error::last_os_error()
+            );
+            return None;
+        }
+        let mut pixels = vec![0u32; length / size_of::<u32>()];
+        unsafe {
+            ptr::copy_nonoverlapping(
+                mapped as *const u8,
+                pixels.as_mut_ptr() as *mut u8,
+                pixels.len() * size_of::<u32>(),
+            );
+            libc::munmap(mapped, length);
+        }
+        Some(pixels)
+    }
+}
+
+// Read a T from the start of `bytes` if the event is long enough
+fn read_event<T: Copy>(bytes: &[u8]) -> Option<T> {
+    if bytes.len() < size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// Decode the DRM events in one read() of the card
+///
+/// `read_cursor(handle, length)` fetches the image of an enabled cursor.
+/// Truncated and unknown events are skipped.
+fn parse_events(
+    mut bytes: &[u8],
+    buffer_to_update: i32,
+    mut read_cursor: impl FnMut(u32, u32) -> Option<Vec<u32>>,
+    events: &mut Vec<EvdiEvent>,
+) {
+    while let Some(header) = read_event::<DrmEvent>(bytes) {
+        let length = header.length as usize;
+        if length < size_of::<DrmEvent>() || length > bytes.len() {
+            eprintln!("Malformed EVDI event (length {})", header.length);
+            return;
+        }
+        let event = &bytes[..length];
+        bytes = &bytes[length..];
+
+        let parsed = match header.kind {
+            DRM_EVDI_EVENT_UPDATE_READY => Some(EvdiEvent::UpdateReady(buffer_to_update)),
+            DRM_EVDI_EVENT_DPMS => {
+                read_event::<DrmEvdiEventDpms>(event).map(|e| EvdiEvent::Dpms(e.mode))
+            }
+            DRM_EVDI_EVENT_MODE_CHANGED => read_event::<DrmEvdiEventModeChanged>(event).map(|e| {
+                EvdiEvent::ModeChanged(Mode {
+                    width: e.hdisplay,
+                    height: e.vdisplay,
+                    refresh_rate: e.vrefresh,
+                    bits_per_pixel: e.bits_per_pixel,
+                    pixel_format: e.pixel_format,
+                })
+            }),
+            DRM_EVDI_EVENT_CRTC_STATE => {
+                read_event::<DrmEvdiEventCrtcState>(event).map(|e| EvdiEvent::CrtcState(e.state))
+            }
+            DRM_EVDI_EVENT_CURSOR_SET => read_event::<DrmEvdiEventCursorSet>(event).map(|e| {
+                let pixels = if e.enabled != 0 {
+                    read_cursor(e.buffer_handle, e.buffer_length)
+                } else {
+                    Some(Vec::new())
+                };
+                EvdiEvent::CursorSet(CursorSet {
+                    hot_x: e.hot_x,
+                    hot_y: e.hot_y,
+                    width: e.width,
+                    height: e.height,
+                    enabled: e.enabled != 0 && pixels.is_some(),
+                    pixel_format: e.pixel_format,
+                    stride: e.stride,
+                    pixels: pixels.unwrap_or_default(),
+                })
+            }),
+            DRM_EVDI_EVENT_CURSOR_MOVE => read_event::<DrmEvdiEventCursorMove>(event)
+                .map(|e| EvdiEvent::CursorMove { x: e.x, y: e.y }),
+            DRM_EVDI_EVENT_DDCCI_DATA => read_event::<DrmEvdiEventDdcciData>(event).map(|e| {
+                let length = (e.buffer_length as usize).min(DDCCI_BUFFER_SIZE);
+                EvdiEvent::Ddcci(DdcciData {
+                    address: e.address,
+                    flags: e.flags,
+                    data: e.buffer[..length].to_vec(),
+                })
+            }),
+            kind => {
+                eprintln!("Unhandled EVDI event {:#x}", kind);
+                None
+            }
+        };
+        events.extend(parsed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Build an event the way the kernel lays it out, field by field
+    fn event(kind: u32, fields: &[&[u8]]) -> Vec<u8> {
+        let payload: Vec<u8> = fields.concat();
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&kind.to_ne_bytes());
+        bytes.extend_from_slice(&(8 + payload.len() as u32).to_ne_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    fn parse(bytes: &[u8]) -> Vec<EvdiEvent> {
+        let mut events = Vec::new();
+        parse_events(
+            bytes,
+            2,
+            |handle, length| Some(vec![handle; length as usize / 4]),
+            &mut events,
+        );
+        events
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_ioctl_numbers_match_kernel_headers() {
+        assert_eq!(DRM_IOCTL_VERSION, 0xc040_6400);
+        assert_eq!(DRM_IOCTL_DROP_MASTER, 0x641f);
+        assert_eq!(DRM_IOCTL_MODE_MAP_DUMB, 0xc010_64b3);
+        assert_eq!(DRM_IOCTL_EVDI_CONNECT, 0xc020_6440);
+        assert_eq!(DRM_IOCTL_EVDI_GRABPIX, 0xc028_6442);
+    }
+
+    #[test]
+    fn test_parse_events() {
+        let mut bytes = event(DRM_EVDI_EVENT_UPDATE_READY, &[]);
+        bytes.extend(event(DRM_EVDI_EVENT_DPMS, &[&3i32.to_ne_bytes()]));
+        bytes.extend(event(
+            DRM_EVDI_EVENT_MODE_CHANGED,
+            &[
+                &1920i32.to_ne_bytes(),
+                &1080i32.to_ne_bytes(),
+                &60i32.to_ne_bytes(),
+                &32i32.to_ne_bytes(),
+                &0x3432_5258u32.to_ne_bytes(),
+            ],
+        ));
+        bytes.extend(event(
+            DRM_EVDI_EVENT_CURSOR_MOVE,
+            &[&10i32.to_ne_bytes(), &(-4i32).to_ne_bytes()],
+        ));
+
+        assert_eq!(
+            parse(&bytes),
+            vec![
+                EvdiEvent::UpdateReady(2),
+                EvdiEvent::Dpms(3),
+                EvdiEvent::ModeChanged(Mode {
+                    width: 1920,
+                    height: 1080,
+                    refresh_rate: 60,
+                    bits_per_pixel: 32,
+                    pixel_format: 0x3432_5258,
+                }),
+                EvdiEvent::CursorMove { x: 10, y: -4 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_cursor_and_ddcci_layout() {
+        let mut ddcci_buffer = [0u8; DDCCI_BUFFER_SIZE];
+        ddcci_buffer[..3].copy_from_slice(&[0x51, 0x82, 0x01]);
+        let mut bytes = event(
+            DRM_EVDI_EVENT_CURSOR_SET,
+            &[
+                &1i32.to_ne_bytes(),
+                &2i32.to_ne_bytes(),
+                &2u32.to_ne_bytes(),
+                &2u32.to_ne_bytes(),
+                &[1, 0, 0, 0],
+                &7u32.to_ne_bytes(),
+                &16u32.to_ne_bytes(),
+                &0x3432_5241u32.to_ne_bytes(),
+                &8u32.to_ne_bytes(),
+            ],
+        );
+        bytes.extend(event(
+            DRM_EVDI_EVENT_DDCCI_DATA,
+            &[
+                &ddcci_buffer,
+                &3u32.to_ne_bytes(),
+                &1u16.to_ne_bytes(),
+                &0x37u16.to_ne_bytes(),
+            ],
+        ));
+
+        assert_eq!(
+            parse(&bytes),
+            vec![
+                EvdiEvent::CursorSet(CursorSet {
+                    hot_x: 1,
+                    hot_y: 2,
+                    width: 2,
+                    height: 2,
+                    enabled: true,
+                    pixel_format: 0x3432_5241,
+                    stride: 8,
+                    pixels: vec![7; 4],
+                }),
+                EvdiEvent::Ddcci(DdcciData {
+                    address: 0x37,
+                    flags: 1,
+                    data: vec![0x51, 0x82, 0x01],
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_skips_unknown_and_truncated_events() {
+        let mut bytes = event(0x1234, &[&[0; 4]]);
+        bytes.extend(event(DRM_EVDI_EVENT_CRTC_STATE, &[&1i32.to_ne_bytes()]));
+        // Too short for its type
+        bytes.extend(event(DRM_EVDI_EVENT_DPMS, &[]));
+        // Claims more bytes than were read
+        bytes.extend(event(DRM_EVDI_EVENT_DPMS, &[&0i32.to_ne_bytes()]));
+        let len = bytes.len();
+        bytes.truncate(len - 2);
+
+        assert_eq!(parse(&bytes), vec![EvdiEvent::CrtcState(1)]);
+    }
+}
@@ -0,0 +1,377 @@
+// libevdi backend
+//
+// libevdi reports events through C callbacks; the trampolines here only copy
+// each event into the queue passed to handle_events. Its log messages arrive
+// printf-style and are formatted by csrc/evdi_log.c, then printed like the
+// driver's own: problems on stderr, chatter only in verbose mode.
+
+use super::{
+    to_rect, CursorSet, DdcciData, DeviceStatus, EvdiBuffer, EvdiEvent, Mode, MAX_DIRTY_RECTS,
+};
+use crate::encoder::Rect;
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::fmt;
+use std::io::{self, Write};
+use std::ptr;
+
+#[allow(
+    non_upper_case_globals,
+    non_camel_case_types,
+    non_snake_case,
+    dead_code
+)]
+mod ffi {
+    // Include auto-generated EVDI bindings
+    include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+}
+
+// bindgen doesn't handle C macros
+const EVDI_INVALID_HANDLE: ffi::evdi_handle = ptr::null_mut();
+
+/// libevdi version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LibVersion {
+    pub major: i32,
+    pub minor: i32,
+    pub patch: i32,
+}
+
+impl fmt::Display for LibVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Version of the linked libevdi
+pub fn lib_version() -> LibVersion {
+    let mut version = ffi::evdi_lib_version {
+        version_major: 0,
+        version_minor: 0,
+        version_patchlevel: 0,
+    };
+    unsafe { ffi::evdi_get_lib_version(&mut version) };
+    LibVersion {
+        major: version.version_major,
+        minor: version.version_minor,
+        patch: version.version_patchlevel,
+    }
+}
+
+pub fn describe() -> String {
+    format!("libevdi {}", lib_version())
+}
+
+extern "C" {
+    // csrc/evdi_log.c
+    fn displaylink_evdi_log(user_data: *mut c_void, fmt: *const c_char, ...);
+}
+
+/// Called by displaylink_evdi_log with the formatted message
+#[no_mangle]
+extern "C" fn displaylink_evdi_log_message(_user_data: *mut c_void, message: *const c_char) {
+    if message.is_null() {
+        return;
+    }
+    let message = unsafe { CStr::from_ptr(message) }.to_string_lossy();
+    // Not println!: a panic on a closed stdout must not unwind into C
+    let _ = match message_level(&message) {
+        Level::Error | Level::Warn => writeln!(io::stderr(), "[libevdi] {}", message),
+        Level::Info => writeln!(io::stdout(), "[libevdi] {}", message),
+        Level::Debug if crate::verbose_enabled() => {
+            writeln!(io::stdout(), "[libevdi] {}", message)
+        }
+        Level::Debug => Ok(()),
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+// libevdi has a single log call; infer the level from the wording
+fn message_level(message: &str) -> Level {
+    let lower = message.to_ascii_lowercase();
+    if lower.starts_with("error") {
+        Level::Error
+    } else if lower.starts_with("warning")
+        || ["failed", "error", "timed out", "doesn't match"]
+            .iter()
+            .any(|word| lower.contains(word))
+    {
+        Level::Warn
+    } else if ["using ", "creating card", "marking "]
+        .iter()
+        .any(|prefix| lower.starts_with(prefix))
+    {
+        // Card lifecycle
+        Level::Info
+    } else {
+        Level::Debug
+    }
+}
+
+pub fn install_logging() {
+    unsafe {
+        ffi::evdi_set_logging(ffi::evdi_logging {
+            function: Some(displaylink_evdi_log),
+            user_data: ptr::null_mut(),
+        })
+    };
+}
+
+/// Ask the kernel module for a new card
+pub fn add_device() -> bool {
+    // Returns the number of bytes written to the sysfs add file
+    unsafe { ffi::evdi_add_device() > 0 }
+}
+
+pub fn check_device(card_no: i32) -> DeviceStatus {
+    match unsafe { ffi::evdi_check_device(card_no) } {
+        ffi::evdi_device_status_AVAILABLE => DeviceStatus::Available,
+        ffi::evdi_device_status_UNRECOGNIZED => DeviceStatus::Unrecognized,
+        _ => DeviceStatus::NotPresent,
+    }
+}
+
+/// Open libevdi handle
+pub struct Device {
+    handle: ffi::evdi_handle,
+}
+
+impl Device {
+    pub fn open(card_no: i32) -> Result<Self, String> {
+        let handle = unsafe { ffi::evdi_open(card_no) };
+        if handle == EVDI_INVALID_HANDLE {
+            return Err(format!("Failed to open EVDI device card{}", card_no));
+        }
+        Ok(Device { handle })
+    }
+
+    pub fn open_attached_to(usb_path: &str, _in_use: &dyn Fn(i32) -> bool) -> Result<Self, String> {
+        // libevdi skips the cards this process already has open by itself
+        let handle = unsafe {
+            ffi::evdi_open_attached_to_fixed(usb_path.as_ptr() as *const c_char, usb_path.len())
+        };
+        if handle == EVDI_INVALID_HANDLE {
+            return Err(format!("Failed to open EVDI device for {}", usb_path));
+        }
+        Ok(Device { handle })
+    }
+
+    pub fn connect(&mut self, edid: &[u8], pixel_area_limit: u32, pixel_per_second_limit: u32) {
+        unsafe {
+            ffi::evdi_connect2(
+                self.handle,
+                edid.as_ptr(),
+                edid.len() as u32,
+                pixel_area_limit,
+                pixel_per_second_limit,
+            )
+        };
+    }
+
+    pub fn disconnect(&mut self) {
+        unsafe { ffi::evdi_disconnect(self.handle) };
+    }
+
+    pub fn enable_cursor_events(&mut self, enable: bool) {
+        unsafe { ffi::evdi_enable_cursor_events(self.handle, enable) };
+    }
+
+    pub fn register_buffer(&mut self, buffer: &mut EvdiBuffer) {
+        let buffer = ffi::evdi_buffer {
+            id: buffer.id,
+            buffer: buffer.data.as_mut_ptr() as *mut c_void,
+            width: buffer.width as c_int,
+            height: buffer.height as c_int,
+            stride: buffer.stride as c_int,
+            rects: ptr::null_mut(),
+            rect_count: 0,
+        };
+        unsafe { ffi::evdi_register_buffer(self.handle, buffer) };
+    }
+
+    pub fn unregister_buffer(&mut self, id: i32) {
+        unsafe { ffi::evdi_unregister_buffer(self.handle, id) };
+    }
+
+    pub fn request_update(&mut self, buffer_id: i32) -> bool {
+        unsafe { ffi::evdi_request_update(self.handle, buffer_id) }
+    }
+
+    /// libevdi grabs into the buffer registered under the requested ID
+    pub fn grab_pixels(&mut self, _buffer: &mut EvdiBuffer, damage: &mut Vec<Rect>) {
+        let mut rects = [ffi::evdi_rect {
+            x1: 0,
+            y1: 0,
+            x2: 0,
+            y2: 0,
+        }; MAX_DIRTY_RECTS];
+        let mut num_rects = 0;
+        unsafe { ffi::evdi_grab_pixels(self.handle, rects.as_mut_ptr(), &mut num_rects) };
+
+        damage.clear();
+        let count = (num_rects.max(0) as usize).min(MAX_DIRTY_RECTS);
+        damage.extend(
+            rects[..count]
+                .iter()
+                .map(|rect| to_rect(rect.x1, rect.y1, rect.x2, rect.y2)),
+        );
+    }
+
+    pub fn ddcci_response(&mut self, data: &[u8], success: bool) {
+        unsafe { ffi::evdi_ddcci_response(self.handle, data.as_ptr(), data.len() as u32, success) };
+    }
+
+    pub fn event_fd(&self) -> c_int {
+        unsafe { ffi::evdi_get_event_ready(self.handle) }
+    }
+
+    pub fn handle_events(&mut self, events: &mut Vec<EvdiEvent>) {
+        let mut context = ffi::evdi_event_context {
+            dpms_handler: Some(on_dpms),
+            mode_changed_handler: Some(on_mode_changed),
+            update_ready_handler: Some(on_update_ready),
+            crtc_state_handler: Some(on_crtc_state),
+            cursor_set_handler: Some(on_cursor_set),
+            cursor_move_handler: Some(on_cursor_move),
+            ddcci_data_handler: Some(on_ddcci_data),
+            user_data: events as *mut Vec<EvdiEvent> as *mut c_void,
+        };
+        unsafe { ffi::evdi_handle_events(self.handle, &mut context) };
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        unsafe { ffi::evdi_close(self.handle) };
+    }
+}
+
+// Trampolines: `user_data` is the event queue passed to handle_events, which
+// outlives the evdi_handle_events call that invokes them
+
+unsafe fn queue<'a>(user_data: *mut c_void) -> &'a mut Vec<EvdiEvent> {
+    &mut *(user_data as *mut Vec<EvdiEvent>)
+}
+
+unsafe extern "C" fn on_dpms(mode: c_int, user_data: *mut c_void) {
+    queue(user_data).push(EvdiEvent::Dpms(mode));
+}
+
+unsafe extern "C" fn on_mode_changed(mode: ffi::evdi_mode, user_data: *mut c_void) {
+    queue(user_data).push(EvdiEvent::ModeChanged(Mode {
+        width: mode.width,
+        height: mode.height,
+        refresh_rate: mode.refresh_rate,
+        bits_per_pixel: mode.bits_per_pixel,
+        pixel_format: mode.pixel_format,
+    }));
+}
+
+unsafe extern "C" fn on_update_ready(buffer_id: c_int, user_data: *mut c_void) {
+    queue(user_data).push(EvdiEvent::UpdateReady(buffer_id));
+}
+
+unsafe extern "C" fn on_crtc_state(state: c_int, user_data: *mut c_void) {
+    queue(user_data).push(EvdiEvent::CrtcState(state));
+}
+
+unsafe extern "C" fn on_cursor_set(cursor: ffi::evdi_cursor_set, user_data: *mut c_void) {
+    // libevdi mallocs the image and hands ownership to the handler
+    let pixels = if cursor.buffer.is_null() {
+        Vec::new()
+    } else {
+        let count = cursor.buffer_length as usize / std::mem::size_of::<u32>();
+        let pixels = std::slice::from_raw_parts(cursor.buffer, count).to_vec();
+        libc::free(cursor.buffer as *mut c_void);
+        pixels
+    };
+    queue(user_data).push(EvdiEvent::CursorSet(CursorSet {
+        hot_x: cursor.hot_x,
+        hot_y: cursor.hot_y,
+        width: cursor.width,
+        height: cursor.height,
+        enabled: cursor.enabled != 0,
+        pixel_format: cursor.pixel_format,
+        stride: cursor.stride,
+        pixels,
+    }));
+}
+
+unsafe extern "C" fn on_cursor_move(cursor: ffi::evdi_cursor_move, user_data: *mut c_void) {
+    queue(user_data).push(EvdiEvent::CursorMove {
+        x: cursor.x,
+        y: cursor.y,
+    });
+}
+
+unsafe extern "C" fn on_ddcci_data(ddcci: ffi::evdi_ddcci_data, user_data: *mut c_void) {
+    // The buffer points into libevdi's read buffer; copy it out
+    let data = if ddcci.buffer.is_null() {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts(ddcci.buffer, ddcci.buffer_length as usize).to_vec()
+    };
+    queue(user_data).push(EvdiEvent::Ddcci(DdcciData {
+        address: ddcci.address,
+        flags: ddcci.flags,
+        data,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_levels() {
+        assert_eq!(message_level("Error: Cursor buffer is null!"), Level::Error);
+        assert_eq!(message_level("Warning: Unhandled event"), Level::Warn);
+        assert_eq!(
+            message_level("Ioctl connect error: No such device"),
+            Level::Warn
+        );
+        assert_eq!(message_level("Using /dev/dri/card1"), Level::Info);
+        assert_eq!(
+            message_level("Dropped master on /dev/dri/card1"),
+            Level::Debug
+        );
+    }
+
+    #[test]
+    fn test_trampolines_queue_events() {
+        let mut events = Vec::new();
+        let user_data = &mut events as *mut Vec<EvdiEvent> as *mut c_void;
+        let mut payload = [0x10u8, 0x20];
+        unsafe {
+            on_update_ready(3, user_data);
+            on_cursor_move(ffi::evdi_cursor_move { x: 5, y: -2 }, user_data);
+            on_ddcci_data(
+                ffi::evdi_ddcci_data {
+                    address: 0x37,
+                    flags: 0,
+                    buffer_length: payload.len() as u32,
+                    buffer: payload.as_mut_ptr(),
+                },
+                user_data,
+            );
+        }
+        assert_eq!(
+            events,
+            vec![
+                EvdiEvent::UpdateReady(3),
+                EvdiEvent::CursorMove { x: 5, y: -2 },
+                EvdiEvent::Ddcci(DdcciData {
+                    address: 0x37,
+                    flags: 0,
+                    data: vec![0x10, 0x20],
+                }),
+            ]
+        );
+    }
+}
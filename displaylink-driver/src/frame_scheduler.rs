@@ -0,0 +1,111 @@
+// Frame update coalescing
+//
+// EVDI can report updates far faster than the USB link can carry them.
+// Instead of dropping updates that arrive inside the rate budget, their
+// damage is accumulated and a deferred flush is armed; the newest buffer
+// contents for the whole accumulated area go out once the budget allows.
+
+use crate::encoder::Rect;
+use std::time::{Duration, Instant};
+
+/// Latest-frame-wins update scheduler
+#[derive(Debug, Default)]
+pub struct FrameScheduler {
+    damage: Rect,
+    last_flush: Option<Instant>,
+    deadline: Option<Instant>,
+}
+
+impl FrameScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add damaged area reported by EVDI
+    pub fn add_damage(&mut self, rect: Rect) {
+        self.damage = self.damage.union(&rect);
+    }
+
+    /// Whether any damage is waiting to be sent
+    pub fn has_damage(&self) -> bool {
+        !self.damage.is_empty()
+    }
+
+    /// Time at which a deferred flush is due, if one is armed
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Take the accumulated damage if the rate budget allows sending now
+    ///
+    /// Otherwise arm the deferred flush for when the budget frees up.
+    pub fn poll(&mut self, now: Instant, interval: Duration) -> Option<Rect> {
+        if !self.has_damage() {
+            self.deadline = None;
+            return None;
+        }
+
+        let due = self.last_flush.map_or(now, |last| last + interval);
+        if now < due {
+            self.deadline = Some(due);
+            return None;
+        }
+
+        self.last_flush = Some(now);
+        self.deadline = None;
+        Some(std::mem::take(&mut self.damage))
+    }
+
+    /// Forget pending damage, e.g. after a mode change
+    pub fn reset(&mut self) {
+        self.damage = Rect::default();
+        self.deadline = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_millis(33);
+
+    #[test]
+    fn test_first_update_flushes_immediately() {
+        let mut scheduler = FrameScheduler::new();
+        scheduler.add_damage(Rect::new(0, 0, 10, 10));
+        let now = Instant::now();
+        assert_eq!(scheduler.poll(now, INTERVAL), Some(Rect::new(0, 0, 10, 10)));
+        assert!(!scheduler.has_damage());
+    }
+
+    #[test]
+    fn test_burst_is_coalesced_and_flushed_later() {
+        let mut scheduler = FrameScheduler::new();
+        let start = Instant::now();
+        scheduler.add_damage(Rect::new(0, 0, 10, 10));
+        assert!(scheduler.poll(start, INTERVAL).is_some());
+
+        // Two updates inside the budget: nothing sent, deadline armed
+        scheduler.add_damage(Rect::new(100, 100, 5, 5));
+        assert!(scheduler
+            .poll(start + Duration::from_millis(5), INTERVAL)
+            .is_none());
+        scheduler.add_damage(Rect::new(50, 0, 5, 5));
+        assert!(scheduler
+            .poll(start + Duration::from_millis(10), INTERVAL)
+            .is_none());
+        assert_eq!(scheduler.deadline(), Some(start + INTERVAL));
+
+        // Once the budget allows, the union of both goes out
+        let flushed = scheduler.poll(start + INTERVAL, INTERVAL);
+        assert_eq!(flushed, Some(Rect::new(50, 0, 55, 105)));
+        assert_eq!(scheduler.deadline(), None);
+    }
+
+    #[test]
+    fn test_no_damage_no_flush() {
+        let mut scheduler = FrameScheduler::new();
+        assert!(scheduler.poll(Instant::now(), INTERVAL).is_none());
+        assert_eq!(scheduler.deadline(), None);
+    }
+}
@@ -4,26 +4,71 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
+static VERBOSE_LOG: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+
+// Whether DISPLAYLINK_DRIVER_VERBOSE is set
+fn verbose_enabled() -> bool {
+    *VERBOSE_LOG.get_or_init(|| std::env::var("DISPLAYLINK_DRIVER_VERBOSE").is_ok())
+}
+
+// Defined ahead of the modules so they can use it too
+macro_rules! vprintln {
+    ($($arg:tt)*) => {
+        if $crate::verbose_enabled() {
+            println!($($arg)*);
+        }
+    };
+}
+
+mod band_compressor;
+mod capabilities;
+mod color;
+mod config;
+mod control;
+mod cursor;
+mod ddcci;
 mod displaylink_protocol;
+mod dither;
+mod encoder;
+mod evdi;
+mod frame_scheduler;
 mod network_adapter;
+mod pipeline;
+mod pixel_format;
+mod power;
+mod quality;
+mod scaler;
+mod snapshot;
+mod transform;
+mod usb_transfer;
 
 use rusb::{Device, DeviceDescriptor, DeviceHandle, UsbContext};
-use std::collections::HashSet;
-use std::env;
-use std::ffi::c_void;
-use std::ptr;
-use std::sync::{Arc, Mutex, OnceLock};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use band_compressor::BandCompressor;
+use capabilities::{DeviceCapabilities, ModeLimits};
+use color::ColorLut;
+use config::DriverConfig;
+use control::{DriverCommand, Request};
+use cursor::{HardwareCursor, SoftwareCursor};
+use ddcci::{read_edid, DdcError, DdcciBridge, UsbDdcChannel};
 use displaylink_protocol::*;
+use encoder::{select_encoder, Rect};
+use evdi::{CursorSet, DdcciData, EvdiCard, EvdiEvent, EvdiEventHandler, Mode};
+use frame_scheduler::FrameScheduler;
 use network_adapter::NetworkAdapter;
-
-// Include auto-generated EVDI bindings
-include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
-
-// Define EVDI_INVALID_HANDLE (bindgen doesn't handle C macros)
-const EVDI_INVALID_HANDLE: evdi_handle = ptr::null_mut();
+use pipeline::{FrameLayout, Pipeline};
+use pixel_format::PixelFormat;
+use power::{PowerManager, PowerState, PowerTransition};
+use quality::QualityController;
+use scaler::{NativeMode, Scaler};
+use transform::{transpose_edid, Transform};
+use usb_transfer::TransferEngine;
 
 // DisplayLink Vendor ID and Product ID (StarTech USB35DOCK)
 const DISPLAYLINK_VID: u16 = 0x17e9;
@@ -57,75 +102,260 @@ const DEFAULT_EDID: &[u8] = &[
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12,
 ];
 
-// Wrapper to make evdi_handle Send (EVDI is thread-safe in practice)
-struct SendEvdiHandle(evdi_handle);
-unsafe impl Send for SendEvdiHandle {}
-unsafe impl Sync for SendEvdiHandle {}
-
 // Multi-monitor manager with hot-plug support
-static VERBOSE_LOG: OnceLock<bool> = OnceLock::new();
-
-fn verbose_enabled() -> bool {
-    *VERBOSE_LOG.get_or_init(|| env::var("DISPLAYLINK_DRIVER_VERBOSE").is_ok())
-}
-
-macro_rules! vprintln {
-    ($($arg:tt)*) => {
-        if verbose_enabled() {
-            println!($($arg)*);
-        }
-    };
-}
-
 struct DisplayLinkManager {
-    drivers: Arc<Mutex<HashSet<String>>>,
+    drivers: Arc<Mutex<HashMap<String, ActiveDevice>>>, // Keyed by "bus:address"
     context: Arc<rusb::Context>,
 }
 
+// A dock with a running driver
+struct ActiveDevice {
+    card_no: i32,                          // EVDI card the dock's display is on
+    usb_path: Option<String>,              // Parent the card is attached to, e.g. "usb:1-2"
+    running: Arc<Mutex<bool>>,             // Cleared to stop the driver thread
+    commands: mpsc::Sender<DriverCommand>, // Control requests for the driver thread
+}
+
 // Driver state
 struct DisplayLinkDriver {
     device_id: String,
-    evdi_handle: SendEvdiHandle,
+    card: EvdiCard,
     usb_handle: Arc<Mutex<DeviceHandle<rusb::Context>>>,
-    current_mode: Option<evdi_mode>,
-    buffers: Vec<FrameBuffer>,
-    compressor: RLECompressor,
+    bulk_out: Arc<Mutex<()>>, // Held for whole command sequences on the bulk endpoint
+    capabilities: DeviceCapabilities,
+    mode_limits: ModeLimits, // Modes the USB link can carry
+    current_mode: Option<Mode>,
+    power: PowerManager,             // DPMS level of the output
+    transform: Transform,            // Rotation/reflection onto the monitor
+    native_mode: Option<NativeMode>, // Panel mode frames are scaled to, if scaling
+    pipeline: Pipeline,              // Encode and transfer stages fed by the event loop
+    quality: QualityController,
     cmd_builder: CommandBuilder,
     running: Arc<Mutex<bool>>,
     network_adapter: Option<NetworkAdapter>,
-    last_update: Instant, // Timestamp of last framebuffer update for rate limiting
+    scheduler: FrameScheduler, // Coalesces damage between rate-limited flushes
+    active_buffer: Option<ActiveBuffer>, // Buffer registered for the current mode
+    update_requested: bool,    // Waiting for an update_ready event
+    events: Vec<EvdiEvent>,    // Reused event queue
+    damage: Vec<Rect>,         // Reused dirty rectangle list
+    cursor: SoftwareCursor,    // Pointer image and position from EVDI
+    hw_cursor: Option<HardwareCursor>, // Device cursor plane, if used
+    ddcci: DdcciBridge<UsbDdcChannel>, // Monitor control passthrough
+    commands: mpsc::Receiver<DriverCommand>, // From the control socket
+    snapshot_dir: PathBuf,     // Default directory for snapshots
+    snapshot_signals: u64,     // SIGUSR1s already answered with a snapshot
 }
 
-struct FrameBuffer {
+// Send data via USB bulk transfer, split into device-sized chunks
+fn write_bulk_chunks(
+    usb_handle: &Mutex<DeviceHandle<rusb::Context>>,
+    bulk_out: &Mutex<()>,
+    data: &[u8],
+) -> Result<(), String> {
+    let _bulk_out = bulk_out.lock().unwrap();
+    let handle = usb_handle.lock().unwrap();
+
+    for chunk in data.chunks(DL_MAX_TRANSFER_SIZE) {
+        handle
+            .write_bulk(BULK_OUT_ENDPOINT, chunk, BULK_TIMEOUT)
+            .map_err(|e| format!("Bulk transfer failed: {}", e))?;
+    }
+
+    Ok(())
+}
+
+// Device timing for a mode requested by the compositor
+fn display_mode(mode: &Mode) -> DisplayMode {
+    // Calculate timing parameters based on resolution
+    let (pixel_clock, hsync_start, hsync_end, htotal, vsync_start, vsync_end, vtotal) =
+        match (mode.width, mode.height) {
+            (1920, 1080) => (
+                148500,
+                1920 + 88,
+                1920 + 88 + 44,
+                2200,
+                1080 + 4,
+                1080 + 4 + 5,
+                1125,
+            ),
+            (1280, 720) => (
+                74250,
+                1280 + 110,
+                1280 + 110 + 40,
+                1650,
+                720 + 5,
+                720 + 5 + 5,
+                750,
+            ),
+            (1024, 768) => (
+                65000,
+                1024 + 24,
+                1024 + 24 + 136,
+                1344,
+                768 + 3,
+                768 + 3 + 6,
+                806,
+            ),
+            _ => {
+                // Generic timing for other resolutions
+                let h_blank = (mode.width / 5) as u32;
+                let v_blank = (mode.height / 30) as u32;
+                let pixel_clock = (mode.width as u32 + h_blank)
+                    * (mode.height as u32 + v_blank)
+                    * mode.refresh_rate as u32
+                    / 1000;
+                (
+                    pixel_clock,
+                    mode.width as u32 + h_blank / 2,
+                    mode.width as u32 + h_blank / 2 + h_blank / 10,
+                    mode.width as u32 + h_blank,
+                    mode.height as u32 + v_blank / 2,
+                    mode.height as u32 + v_blank / 2 + v_blank / 10,
+                    mode.height as u32 + v_blank,
+                )
+            }
+        };
+
+    DisplayMode {
+        width: mode.width as u32,
+        height: mode.height as u32,
+        refresh_rate: mode.refresh_rate as u32,
+        pixel_clock,
+        hsync_start,
+        hsync_end,
+        htotal,
+        vsync_start,
+        vsync_end,
+        vtotal,
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ActiveBuffer {
     id: i32,
-    data: Vec<u8>,
-    width: i32,
-    height: i32,
-    stride: i32,
+    format: PixelFormat,
 }
 
 impl DisplayLinkDriver {
     fn new(
         device_id: String,
-        evdi_handle: evdi_handle,
+        card: EvdiCard,
         usb_handle: DeviceHandle<rusb::Context>,
+        capabilities: DeviceCapabilities,
+        commands: mpsc::Receiver<DriverCommand>,
     ) -> Self {
+        let mode_limits = capabilities.mode_limits(usb_handle.device().speed());
+        vprintln!(
+            "[{}] Mode limits: {} pixels, {} pixels/s",
+            device_id,
+            mode_limits.pixel_area,
+            mode_limits.pixels_per_second
+        );
         let usb_handle_arc = Arc::new(Mutex::new(usb_handle));
+        let bulk_out = Arc::new(Mutex::new(()));
+        let ddcci = DdcciBridge::new(UsbDdcChannel::new(usb_handle_arc.clone()));
 
         // Initialize network adapter
         let network_adapter = NetworkAdapter::new(usb_handle_arc.clone(), device_id.clone());
 
+        let config = DriverConfig::from_env();
+        let encoder_kind = select_encoder(&capabilities, config.encoder);
+        vprintln!(
+            "[{}] Using {} encoder with {} compression thread(s)",
+            device_id,
+            encoder_kind.name(),
+            config.encoder_threads
+        );
+        let transform = config::transform_for(&device_id);
+        if !transform.is_identity() {
+            println!("[{}] Output transform: {:?}", device_id, transform);
+        }
+        // The cursor plane can't follow a transformed image
+        let hw_cursor = capabilities
+            .hw_cursor_size
+            .filter(|_| config.hw_cursor && transform.is_identity())
+            .map(HardwareCursor::new);
+        vprintln!(
+            "[{}] Cursor: {}",
+            device_id,
+            if hw_cursor.is_some() {
+                "hardware plane"
+            } else {
+                "software"
+            }
+        );
+        let mut compressor = BandCompressor::new(config.encoder_threads, encoder_kind);
+        compressor.set_dither(config.dither);
+        let quality = QualityController::new(&capabilities, encoder_kind, config.adaptive_quality);
+        let mut pipeline = Pipeline::new(
+            device_id.clone(),
+            compressor,
+            TransferEngine::new(
+                usb_handle_arc.clone(),
+                bulk_out.clone(),
+                BULK_OUT_ENDPOINT,
+                BULK_TIMEOUT,
+                config.urb_size,
+                config.urb_depth,
+            ),
+        );
+        pipeline.set_transform(transform);
+        if let Some(color) = config::color_for(&device_id) {
+            println!("[{}] Color correction enabled", device_id);
+            pipeline.set_color(Some(Arc::new(color)));
+        }
+
+        let native_mode = config.scaling.and_then(|scaling| {
+            let native = config.native_mode.or_else(|| {
+                let mut channel = UsbDdcChannel::new(usb_handle_arc.clone());
+                read_edid(&mut channel)
+                    .ok()
+                    .and_then(|edid| NativeMode::from_edid(&edid))
+            });
+            let Some(native) = native else {
+                eprintln!(
+                    "[{}] Panel's native mode unknown, scaling disabled",
+                    device_id
+                );
+                return None;
+            };
+            println!(
+                "[{}] Scaling to native {}x{}@{}Hz ({:?})",
+                device_id, native.width, native.height, native.refresh_rate, scaling
+            );
+            pipeline.set_scaler(Some(Scaler::new(scaling, native)));
+            Some(native)
+        });
+        // Nor can it follow a scaled one
+        let hw_cursor = hw_cursor.filter(|_| native_mode.is_none());
+
         DisplayLinkDriver {
             device_id,
-            evdi_handle: SendEvdiHandle(evdi_handle),
+            card,
             usb_handle: usb_handle_arc,
+            bulk_out,
+            capabilities,
+            mode_limits,
             current_mode: None,
-            buffers: Vec::new(),
-            compressor: RLECompressor::new(),
+            power: PowerManager::new(),
+            transform,
+            native_mode,
+            pipeline,
+            quality,
             cmd_builder: CommandBuilder::new(),
             running: Arc::new(Mutex::new(true)),
             network_adapter: Some(network_adapter),
-            last_update: Instant::now(), // Initialize timestamp for rate limiting
+            scheduler: FrameScheduler::new(),
+            active_buffer: None,
+            update_requested: false,
+            events: Vec::new(),
+            damage: Vec::with_capacity(evdi::MAX_DIRTY_RECTS),
+            cursor: SoftwareCursor::new(),
+            hw_cursor,
+            ddcci,
+            commands,
+            snapshot_dir: config.snapshot_dir,
+            snapshot_signals: snapshot::signal_requests(),
         }
     }
 
@@ -164,6 +394,20 @@ impl DisplayLinkDriver {
         // Send initialization sequence to DisplayLink device
         self.send_init_sequence()?;
 
+        // Attach the virtual monitor; it stays connected until the driver
+        // stops, whatever the DPMS state. A monitor turned on its side
+        // advertises portrait modes.
+        let edid = if self.transform.swaps_axes() {
+            transpose_edid(DEFAULT_EDID)
+        } else {
+            DEFAULT_EDID.to_vec()
+        };
+        self.card.connect(
+            &edid,
+            self.mode_limits.pixel_area,
+            self.mode_limits.pixels_per_second,
+        );
+
         Ok(())
     }
 
@@ -227,42 +471,58 @@ impl DisplayLinkDriver {
         Err("Failed to initialize bulk endpoint (unknown error)".to_string())
     }
 
-    // Send framebuffer data to DisplayLink device
-    fn send_framebuffer(&mut self, buffer: &FrameBuffer) -> Result<(), String> {
-        println!(
-            "Compressing framebuffer: {}x{}",
-            buffer.width, buffer.height
+    // Capture a damaged region of a framebuffer into the pipeline
+    fn send_framebuffer(&mut self, active: ActiveBuffer, region: Rect) {
+        vprintln!(
+            "Capturing framebuffer region: {}x{} at ({}, {})",
+            region.width,
+            region.height,
+            region.x,
+            region.y
         );
 
-        // Compress framebuffer using RLE
-        let compressed = self
-            .compressor
-            .compress(&buffer.data, buffer.width as usize, buffer.height as usize)
-            .to_vec();
-
-        println!(
-            "  Compressed {} bytes -> {} bytes",
-            buffer.data.len(),
-            compressed.len()
+        let Some(buffer) = self.card.buffer(active.id) else {
+            return;
+        };
+        let layout = FrameLayout {
+            width: buffer.width(),
+            height: buffer.height(),
+            stride: buffer.stride(),
+            format: active.format,
+        };
+        // The cursor plane draws the pointer itself
+        let cursor = match &self.hw_cursor {
+            Some(plane) if plane.is_shown() => None,
+            _ => Some(&self.cursor),
+        };
+        self.pipeline.submit(
+            buffer.data(),
+            layout,
+            region,
+            self.quality.current().encoder,
+            cursor,
         );
+    }
 
-        // Set damage rectangle (full screen update)
-        let damage_cmd = self
-            .cmd_builder
-            .damage_rect(0, 0, buffer.width as u16, buffer.height as u16)
-            .to_vec();
-        self.send_bulk_data(&damage_cmd)?;
-
-        // Send compressed framebuffer data in chunks
-        self.send_bulk_data(&compressed)?;
-
-        // Sync/flush command
-        let sync_cmd = self.cmd_builder.sync().to_vec();
-        self.send_bulk_data(&sync_cmd)?;
-
-        println!("  ✓ Framebuffer sent");
-
-        Ok(())
+    // Feed link measurements from the transfer stage to the quality
+    // controller; the new codec applies from the next captured frame
+    fn collect_samples(&mut self) {
+        for sample in self.pipeline.samples() {
+            if let Some(change) = self.quality.record(sample) {
+                println!("[{}] {}", self.device_id, change);
+                println!(
+                    "[{}] Link stats: {} merged={}",
+                    self.device_id,
+                    self.quality.stats(),
+                    self.pipeline.merged_frames()
+                );
+                println!(
+                    "[{}] Transfer stats: {}",
+                    self.device_id,
+                    self.pipeline.transfer_stats()
+                );
+            }
+        }
     }
 
     // Send mode set command to DisplayLink device
@@ -275,6 +535,13 @@ impl DisplayLinkDriver {
         let mode_cmd = self.cmd_builder.set_mode(mode).to_vec();
         self.send_bulk_data(&mode_cmd)?;
 
+        // Match the device color depth to the active encoder
+        let depth_cmd = self
+            .cmd_builder
+            .set_color_depth(self.quality.current().encoder.bits_per_pixel())
+            .to_vec();
+        self.send_bulk_data(&depth_cmd)?;
+
         // Unblank the screen after mode set
         let unblank_cmd = self.cmd_builder.blank_screen(false).to_vec();
         self.send_bulk_data(&unblank_cmd)?;
@@ -284,251 +551,297 @@ impl DisplayLinkDriver {
         Ok(())
     }
 
-    // Send data via USB bulk transfer
-    fn send_bulk_data(&self, data: &[u8]) -> Result<(), String> {
-        let handle = self.usb_handle.lock().unwrap();
-
-        // Split into chunks if necessary
-        for chunk in data.chunks(DL_MAX_TRANSFER_SIZE) {
-            handle
-                .write_bulk(BULK_OUT_ENDPOINT, chunk, BULK_TIMEOUT)
-                .map_err(|e| format!("Bulk transfer failed: {}", e))?;
+    // Program the mode and repaint the whole screen
+    fn restore_output(&mut self, mode: Mode) {
+        // The monitor scans out in its own orientation, or always at its
+        // native mode when frames are scaled
+        let native = match self.native_mode {
+            Some(native) => Mode {
+                width: native.width as i32,
+                height: native.height as i32,
+                refresh_rate: native.refresh_rate as i32,
+                ..mode
+            },
+            None => {
+                let (width, height) = self
+                    .transform
+                    .output_size(mode.width as usize, mode.height as usize);
+                Mode {
+                    width: width as i32,
+                    height: height as i32,
+                    ..mode
+                }
+            }
+        };
+        if let Err(e) = self.send_mode_set(&display_mode(&native)) {
+            eprintln!("[{}] Failed to set DisplayLink mode: {}", self.device_id, e);
+            return;
         }
-
-        Ok(())
+        self.scheduler.reset();
+        self.scheduler
+            .add_damage(Rect::new(0, 0, mode.width as usize, mode.height as usize));
     }
 
-    // Register a framebuffer with EVDI
-    fn register_buffer(&mut self, width: i32, height: i32) -> Result<i32, String> {
-        let buffer_id = self.buffers.len() as i32;
-        let stride = width * 4; // 4 bytes per pixel (BGRA)
-        let buffer_size = (stride * height) as usize;
-
-        let mut framebuffer = FrameBuffer {
-            id: buffer_id,
-            data: vec![0u8; buffer_size],
-            width,
-            height,
-            stride,
-        };
+    // Blank the screen and stop sending frames before lowering output power
+    fn power_down(&mut self, state: PowerState) -> Result<(), String> {
+        let blank_cmd = self.cmd_builder.blank_screen(true).to_vec();
+        self.send_bulk_data(&blank_cmd)?;
 
-        let evdi_buf = evdi_buffer {
-            id: buffer_id,
-            buffer: framebuffer.data.as_mut_ptr() as *mut c_void,
-            width,
-            height,
-            stride,
-            rects: ptr::null_mut(),
-            rect_count: 0,
-        };
+        // Nothing captured before the screen went dark is worth sending
+        self.pipeline.reset();
+        self.scheduler.reset();
+
+        self.set_output_power(state)
+    }
+
+    fn set_output_power(&mut self, state: PowerState) -> Result<(), String> {
+        let power_cmd = self.cmd_builder.set_power(state.register_value()).to_vec();
+        self.send_bulk_data(&power_cmd)
+    }
 
-        unsafe {
-            evdi_register_buffer(self.evdi_handle.0, evdi_buf);
+    // Power the output back up; the mode set unblanks the screen
+    fn power_up(&mut self) -> Result<(), String> {
+        self.set_output_power(PowerState::On)?;
+        if !self.power.is_crtc_enabled() {
+            // Stays blank until the CRTC is enabled again
+            return Ok(());
+        }
+        match self.current_mode {
+            Some(mode) => self.restore_output(mode),
+            None => {
+                let unblank_cmd = self.cmd_builder.blank_screen(false).to_vec();
+                self.send_bulk_data(&unblank_cmd)?;
+            }
         }
+        Ok(())
+    }
 
-        self.buffers.push(framebuffer);
-        println!("Registered buffer {} ({}x{})", buffer_id, width, height);
+    // Stop streaming to a disabled CRTC and give its buffer back to EVDI
+    fn pause_crtc(&mut self) {
+        self.pipeline.reset();
+        self.scheduler.reset();
+        self.update_requested = false;
+        if let Some(old) = self.active_buffer.take() {
+            self.card.unregister_buffer(old.id);
+        }
 
-        Ok(buffer_id)
+        let blank_cmd = self.cmd_builder.blank_screen(true).to_vec();
+        if let Err(e) = self.send_bulk_data(&blank_cmd) {
+            eprintln!("[{}] Failed to blank screen: {}", self.device_id, e);
+        }
     }
 
-    // Handle EVDI events
-    fn handle_events(&mut self) {
-        unsafe extern "C" fn dpms_handler(dpms_mode: i32, user_data: *mut c_void) {
-            let driver = &mut *(user_data as *mut DisplayLinkDriver);
-            println!(
-                "[{}] DPMS mode changed: {} ({})",
-                driver.device_id,
-                dpms_mode,
-                match dpms_mode {
-                    0 => "ON",
-                    1 => "STANDBY",
-                    2 => "SUSPEND",
-                    3 => "OFF",
-                    _ => "UNKNOWN",
-                }
-            );
+    // Re-apply the current mode to a re-enabled CRTC and repaint everything
+    fn resume_crtc(&mut self) {
+        let Some(mode) = self.current_mode else {
+            return;
+        };
+        if self.active_buffer.is_none() {
+            let format = PixelFormat::from_mode(mode.pixel_format, mode.bits_per_pixel);
+            self.active_buffer = Some(self.register_buffer(mode.width, mode.height, format));
+        }
+        // A powered-down output gets its mode on wake-up
+        if self.power.is_on() {
+            self.restore_output(mode);
+        }
+    }
 
-            if dpms_mode == 0 { // ON
-                // Connect the virtual display
-                println!("[{}] DPMS ON: Connecting virtual display", driver.device_id);
-                evdi_connect(driver.evdi_handle.0, DEFAULT_EDID.as_ptr(), DEFAULT_EDID.len() as u32, 0);
+    // Send data via USB bulk transfer
+    fn send_bulk_data(&self, data: &[u8]) -> Result<(), String> {
+        write_bulk_chunks(&self.usb_handle, &self.bulk_out, data)
+    }
 
-                // Unblank the screen
-                let blank_cmd = driver.cmd_builder.blank_screen(false).to_vec();
-                if let Err(e) = driver.send_bulk_data(&blank_cmd) {
-                    eprintln!("[{}] Failed to unblank screen: {}", driver.device_id, e);
-                }
-            } else { // STANDBY, SUSPEND, or OFF
-                // Blank the screen first
-                println!("[{}] DPMS OFF/Standby: Blanking screen and disconnecting virtual display", driver.device_id);
-                let blank_cmd = driver.cmd_builder.blank_screen(true).to_vec();
-                if let Err(e) = driver.send_bulk_data(&blank_cmd) {
-                    eprintln!("[{}] Failed to blank screen: {}", driver.device_id, e);
-                }
+    // Grab the new frame contents from EVDI and schedule them for sending
+    fn on_update_ready(&mut self, buffer_id: i32) {
+        self.update_requested = false;
 
-                // Disconnect the virtual display
-                evdi_disconnect(driver.evdi_handle.0);
-            }
+        self.card.grab_pixels(&mut self.damage);
+        let Some(buffer) = self.card.buffer(buffer_id) else {
+            return;
+        };
+        let (width, height) = (buffer.width(), buffer.height());
+        for damage in &self.damage {
+            self.scheduler.add_damage(damage.clamp_to(width, height));
         }
 
-        unsafe extern "C" fn mode_changed_handler(mode: evdi_mode, user_data: *mut c_void) {
-            let driver = &mut *(user_data as *mut DisplayLinkDriver);
-            println!(
-                "[{}] Mode changed: {}x{}@{}Hz (dynamic resolution)",
-                driver.device_id, mode.width, mode.height, mode.refresh_rate
-            );
-            driver.current_mode = Some(mode);
-
-            // Calculate timing parameters based on resolution
-            let (pixel_clock, hsync_start, hsync_end, htotal, vsync_start, vsync_end, vtotal) =
-                match (mode.width, mode.height) {
-                    (1920, 1080) => (
-                        148500,
-                        1920 + 88,
-                        1920 + 88 + 44,
-                        2200,
-                        1080 + 4,
-                        1080 + 4 + 5,
-                        1125,
-                    ),
-                    (1280, 720) => (
-                        74250,
-                        1280 + 110,
-                        1280 + 110 + 40,
-                        1650,
-                        720 + 5,
-                        720 + 5 + 5,
-                        750,
-                    ),
-                    (1024, 768) => (
-                        65000,
-                        1024 + 24,
-                        1024 + 24 + 136,
-                        1344,
-                        768 + 3,
-                        768 + 3 + 6,
-                        806,
-                    ),
-                    _ => {
-                        // Generic timing for other resolutions
-                        let h_blank = (mode.width / 5) as u32;
-                        let v_blank = (mode.height / 30) as u32;
-                        let pixel_clock = (mode.width as u32 + h_blank)
-                            * (mode.height as u32 + v_blank)
-                            * mode.refresh_rate as u32
-                            / 1000;
-                        (
-                            pixel_clock,
-                            mode.width as u32 + h_blank / 2,
-                            mode.width as u32 + h_blank / 2 + h_blank / 10,
-                            mode.width as u32 + h_blank,
-                            mode.height as u32 + v_blank / 2,
-                            mode.height as u32 + v_blank / 2 + v_blank / 10,
-                            mode.height as u32 + v_blank,
-                        )
-                    }
-                };
+        self.flush_pending();
+    }
 
-            // Create DisplayLink mode configuration
-            let dl_mode = DisplayMode {
-                width: mode.width as u32,
-                height: mode.height as u32,
-                refresh_rate: mode.refresh_rate as u32,
-                pixel_clock,
-                hsync_start,
-                hsync_end,
-                htotal,
-                vsync_start,
-                vsync_end,
-                vtotal,
-            };
+    fn cursor_on_plane(&self) -> bool {
+        self.hw_cursor
+            .as_ref()
+            .is_some_and(|plane| plane.is_shown())
+    }
 
-            // Send mode to DisplayLink device
-            if let Err(e) = driver.send_mode_set(&dl_mode) {
-                eprintln!(
-                    "[{}] Failed to set DisplayLink mode: {}",
-                    driver.device_id, e
-                );
-                return;
-            }
+    // Drive the cursor plane; if the device rejects it, composite from now on
+    fn send_cursor_commands(&mut self, commands: &[u8]) {
+        if let Err(e) = self.send_bulk_data(commands) {
+            eprintln!(
+                "[{}] Hardware cursor failed, compositing instead: {}",
+                self.device_id, e
+            );
+            self.hw_cursor = None;
+            let damage = self.cursor.rect();
+            self.add_cursor_damage(&[damage]);
+        }
+    }
 
-            // Register new buffer for new mode
-            if let Err(e) = driver.register_buffer(mode.width, mode.height) {
-                eprintln!("[{}] Failed to register buffer: {}", driver.device_id, e);
-            }
+    // Repaint the areas the cursor left and entered
+    fn add_cursor_damage(&mut self, rects: &[Option<Rect>]) {
+        let Some(buffer) = self
+            .active_buffer
+            .and_then(|active| self.card.buffer(active.id))
+        else {
+            return;
+        };
+        let (width, height) = (buffer.width(), buffer.height());
+        for rect in rects.iter().flatten() {
+            self.scheduler.add_damage(rect.clamp_to(width, height));
         }
 
-        unsafe extern "C" fn update_ready_handler(buffer_id: i32, user_data: *mut c_void) {
-            let driver = &mut *(user_data as *mut DisplayLinkDriver);
+        self.flush_pending();
+    }
 
-            // Rate limit: Only update at most 30 fps (33ms between frames)
-            // This prevents USB bus saturation and kernel stress
-            const MIN_FRAME_INTERVAL: Duration = Duration::from_millis(33);
-            let now = Instant::now();
-            if now.duration_since(driver.last_update) < MIN_FRAME_INTERVAL {
-                // Skip this frame to avoid overwhelming the system
-                return;
-            }
-            driver.last_update = now;
-
-            // Request pixel data from EVDI
-            evdi_grab_pixels(driver.evdi_handle.0, ptr::null_mut(), ptr::null_mut());
-
-            // Send framebuffer to DisplayLink device
-            // Clone minimal data (pointer+metadata) not the 8MB buffer itself
-            if let Some(buffer) = driver.buffers.iter().find(|b| b.id == buffer_id) {
-                // Create a lightweight copy of buffer metadata (no data clone)
-                let temp_buffer = FrameBuffer {
-                    id: buffer.id,
-                    data: buffer.data.clone(), // Unfortunately needed due to borrow checker
-                    width: buffer.width,
-                    height: buffer.height,
-                    stride: buffer.stride,
-                };
-                if let Err(e) = driver.send_framebuffer(&temp_buffer) {
-                    eprintln!("Failed to send framebuffer: {}", e);
+    // Resend the whole screen, e.g. after the colors changed
+    fn repaint(&mut self) {
+        let Some(buffer) = self
+            .active_buffer
+            .and_then(|active| self.card.buffer(active.id))
+        else {
+            return;
+        };
+        let screen = Rect::new(0, 0, buffer.width(), buffer.height());
+        self.scheduler.add_damage(screen);
+        self.flush_pending();
+    }
+
+    // Carry out requests from the control socket and SIGUSR1
+    fn handle_commands(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                DriverCommand::SetColor(color) => self.set_color(color),
+                DriverCommand::Snapshot { dir, reply } => {
+                    let dir = dir.unwrap_or_else(|| self.snapshot_dir.clone());
+                    let result = self
+                        .snapshot(&dir)
+                        .map(|files| format!("{}: {}", self.device_id, files))
+                        .map_err(|e| format!("{}: {}", self.device_id, e));
+                    let _ = reply.send(result);
+                }
+                DriverCommand::Stats { reply } => {
+                    let stats = format!(
+                        "quality: {} merged={} transfer: {}",
+                        self.quality.stats(),
+                        self.pipeline.merged_frames(),
+                        self.pipeline.transfer_stats()
+                    );
+                    let _ = reply.send((self.device_id.clone(), stats));
                 }
             }
         }
 
-        unsafe extern "C" fn crtc_state_handler(state: i32, _user_data: *mut c_void) {
-            println!("CRTC state changed: {}", state);
+        let signals = snapshot::signal_requests();
+        if signals != self.snapshot_signals {
+            self.snapshot_signals = signals;
+            let dir = self.snapshot_dir.clone();
+            match self.snapshot(&dir) {
+                Ok(files) => println!("[{}] Snapshot saved: {}", self.device_id, files),
+                Err(e) => eprintln!("[{}] Snapshot failed: {}", self.device_id, e),
+            }
         }
+    }
 
-        unsafe extern "C" fn cursor_set_handler(cursor: evdi_cursor_set, _user_data: *mut c_void) {
-            println!(
-                "Cursor set: {}x{} at ({}, {})",
-                cursor.width, cursor.height, cursor.hot_x, cursor.hot_y
-            );
-            // Handle cursor updates
-        }
+    // Save what EVDI gave us and what the device was last sent as PNG files
+    fn snapshot(&self, dir: &Path) -> Result<String, String> {
+        let active = self.active_buffer.ok_or("no frame buffer registered")?;
+        let buffer = self
+            .card
+            .buffer(active.id)
+            .ok_or("frame buffer not available")?;
+        let layout = FrameLayout {
+            width: buffer.width(),
+            height: buffer.height(),
+            stride: buffer.stride(),
+            format: active.format,
+        };
+        let sent = self.pipeline.last_sent();
+        let files = snapshot::save(dir, &self.device_id, buffer.data(), &layout, sent.as_ref())?;
+        let files: Vec<String> = files
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        Ok(files.join(" "))
+    }
 
-        unsafe extern "C" fn cursor_move_handler(
-            cursor: evdi_cursor_move,
-            _user_data: *mut c_void,
-        ) {
-            println!("Cursor moved to ({}, {})", cursor.x, cursor.y);
-        }
+    fn set_color(&mut self, color: Option<Arc<ColorLut>>) {
+        println!(
+            "[{}] Color correction {}",
+            self.device_id,
+            if color.is_some() { "updated" } else { "off" }
+        );
+        self.pipeline.set_color(color);
+        self.repaint();
+    }
 
-        unsafe extern "C" fn ddcci_handler(_ddcci: evdi_ddcci_data, _user_data: *mut c_void) {
-            println!("DDC/CI data received");
+    // Send accumulated damage if the rate budget allows, otherwise leave the
+    // deferred flush armed so the newest contents go out later
+    fn flush_pending(&mut self) {
+        if !self.power.is_streaming() {
+            return;
         }
+        let Some(active) = self.active_buffer else {
+            return;
+        };
+        let Some(region) = self
+            .scheduler
+            .poll(Instant::now(), self.quality.frame_interval())
+        else {
+            return;
+        };
 
-        let mut event_context = evdi_event_context {
-            dpms_handler: Some(dpms_handler),
-            mode_changed_handler: Some(mode_changed_handler),
-            update_ready_handler: Some(update_ready_handler),
-            crtc_state_handler: Some(crtc_state_handler),
-            cursor_set_handler: Some(cursor_set_handler),
-            cursor_move_handler: Some(cursor_move_handler),
-            ddcci_data_handler: Some(ddcci_handler),
-            user_data: self as *mut _ as *mut c_void,
+        self.send_framebuffer(active, region);
+    }
+
+    // Ask EVDI for the next frame; grab right away if one is already pending
+    fn request_update(&mut self) {
+        if !self.power.is_streaming() {
+            return;
+        }
+        let Some(active) = self.active_buffer else {
+            return;
         };
+        if self.update_requested {
+            return;
+        }
+        if self.card.request_update(active.id) {
+            self.on_update_ready(active.id);
+        } else {
+            self.update_requested = true;
+        }
+    }
+
+    // Register a framebuffer with EVDI
+    fn register_buffer(&mut self, width: i32, height: i32, format: PixelFormat) -> ActiveBuffer {
+        let stride = width as usize * format.bytes_per_pixel();
+        let id = self
+            .card
+            .register_buffer(width as usize, height as usize, stride);
+        println!(
+            "Registered buffer {} ({}x{}, {:?}, stride {})",
+            id, width, height, format, stride
+        );
+
+        ActiveBuffer { id, format }
+    }
 
-        unsafe {
-            evdi_handle_events(self.evdi_handle.0, &mut event_context);
+    // Read pending EVDI events and dispatch them to the handler methods
+    fn handle_events(&mut self) {
+        let mut events = std::mem::take(&mut self.events);
+        self.card.handle_events(&mut events);
+        for event in events.drain(..) {
+            event.dispatch(self);
         }
+        self.events = events;
     }
 
     // Main event loop
@@ -548,16 +861,23 @@ impl DisplayLinkDriver {
                 }
             }
 
-            unsafe {
-                let event_fd = evdi_get_event_ready(self.evdi_handle.0);
-                if event_fd != -1 {
-                    self.handle_events();
-                }
+            self.request_update();
+
+            // Wait for EVDI events for up to 100ms, waking up earlier when a
+            // deferred flush is due
+            let mut timeout = Duration::from_millis(100);
+            if let Some(deadline) = self.scheduler.deadline() {
+                timeout = timeout.min(deadline.saturating_duration_since(Instant::now()));
+            }
+            if self.card.wait_for_event(timeout)? {
+                self.handle_events();
             }
 
-            // Sleep longer to reduce CPU usage and kernel stress
-            // 100ms is sufficient for display updates (10 fps event checking)
-            thread::sleep(Duration::from_millis(100));
+            self.handle_commands();
+
+            // Send coalesced damage whose deferred flush is due
+            self.flush_pending();
+            self.collect_samples();
         }
 
         Ok(())
@@ -569,15 +889,148 @@ impl DisplayLinkDriver {
     }
 }
 
+impl EvdiEventHandler for DisplayLinkDriver {
+    fn dpms(&mut self, dpms_mode: i32) {
+        let Some(transition) = self.power.set_dpms(dpms_mode) else {
+            vprintln!("[{}] DPMS mode {} ignored", self.device_id, dpms_mode);
+            return;
+        };
+        println!(
+            "[{}] DPMS mode changed: {}",
+            self.device_id,
+            self.power.state()
+        );
+
+        // The connector stays attached in every state, so the compositor
+        // never sees an unplug
+        let result = match transition {
+            PowerTransition::Sleep(state) => self.power_down(state),
+            PowerTransition::Lower(state) => self.set_output_power(state),
+            PowerTransition::Wake => self.power_up(),
+        };
+        if let Err(e) = result {
+            eprintln!(
+                "[{}] Failed to apply DPMS {}: {}",
+                self.device_id,
+                self.power.state(),
+                e
+            );
+        }
+    }
+
+    fn mode_changed(&mut self, mode: Mode) {
+        println!(
+            "[{}] Mode changed: {}x{}@{}Hz (dynamic resolution)",
+            self.device_id, mode.width, mode.height, mode.refresh_rate
+        );
+        self.current_mode = Some(mode);
+
+        // Frames captured in the old mode must not reach the device
+        self.pipeline.reset();
+
+        // Replace the old mode's buffer; EVDI stops writing to it once it is
+        // unregistered, and its ID becomes free again
+        if let Some(old) = self.active_buffer.take() {
+            self.card.unregister_buffer(old.id);
+        }
+        let format = PixelFormat::from_mode(mode.pixel_format, mode.bits_per_pixel);
+        self.active_buffer = Some(self.register_buffer(mode.width, mode.height, format));
+        self.update_requested = false;
+
+        // While powered down or with the CRTC disabled, the mode is set once
+        // the output comes back
+        if self.power.is_streaming() {
+            self.restore_output(mode);
+        }
+    }
+
+    fn update_ready(&mut self, buffer_id: i32) {
+        // Updates are never dropped: damage is coalesced and sent at most
+        // once per frame interval (33ms, more when the quality controller
+        // has lowered the update rate) to avoid USB bus saturation
+        self.on_update_ready(buffer_id);
+    }
+
+    fn crtc_state(&mut self, state: i32) {
+        let Some(enabled) = self.power.set_crtc_state(state) else {
+            return;
+        };
+        println!(
+            "[{}] CRTC {}",
+            self.device_id,
+            if enabled { "enabled" } else { "disabled" }
+        );
+        if enabled {
+            self.resume_crtc();
+        } else {
+            self.pause_crtc();
+        }
+    }
+
+    fn cursor_set(&mut self, cursor: CursorSet) {
+        vprintln!(
+            "Cursor set: {}x{} hotspot ({}, {}){}",
+            cursor.width,
+            cursor.height,
+            cursor.hot_x,
+            cursor.hot_y,
+            if cursor.enabled { "" } else { " (hidden)" }
+        );
+        let was_composited = !self.cursor_on_plane();
+        let damage = self.cursor.set(&cursor);
+        if let Some(plane) = self.hw_cursor.as_mut() {
+            // Images the plane can't hold are composited instead
+            if let Some(commands) = plane.show(&self.cursor).or_else(|| plane.hide()) {
+                self.send_cursor_commands(&commands);
+            }
+        }
+        // Repaint when frames carry the cursor now, or did until now
+        if was_composited || !self.cursor_on_plane() {
+            self.add_cursor_damage(&[damage]);
+        }
+    }
+
+    fn cursor_move(&mut self, x: i32, y: i32) {
+        vprintln!("Cursor moved to ({}, {})", x, y);
+        let damage = self.cursor.move_to(x, y);
+        if damage == [None, None] {
+            return;
+        }
+        let commands = self
+            .hw_cursor
+            .as_mut()
+            .and_then(|plane| plane.move_to(&self.cursor));
+        match commands {
+            Some(commands) => self.send_cursor_commands(&commands),
+            None => self.add_cursor_damage(&damage),
+        }
+    }
+
+    fn ddcci(&mut self, data: DdcciData) {
+        match self.ddcci.transfer(&data) {
+            Ok(reply) => self.card.ddcci_response(&reply, true),
+            Err(e) => {
+                // NAKs are routine while tools probe the bus
+                if e == DdcError::Nak {
+                    vprintln!("[{}] DDC/CI request: {}", self.device_id, e);
+                } else {
+                    eprintln!("[{}] DDC/CI request: {}", self.device_id, e);
+                }
+                self.card.ddcci_response(&[], false);
+            }
+        }
+    }
+}
+
 impl Drop for DisplayLinkDriver {
     fn drop(&mut self) {
         println!("[{}] Shutting down DisplayLink driver...", self.device_id);
 
+        // Let the frame in flight finish before the interface goes away
+        self.pipeline.shutdown();
+
         // Disconnect from EVDI
-        unsafe {
-            evdi_disconnect(self.evdi_handle.0);
-            evdi_close(self.evdi_handle.0);
-        }
+        self.card.disconnect();
 
         // Release USB interface
         if let Ok(handle) = self.usb_handle.lock() {
@@ -589,7 +1042,7 @@ impl Drop for DisplayLinkDriver {
 impl DisplayLinkManager {
     fn new(context: rusb::Context) -> Self {
         DisplayLinkManager {
-            drivers: Arc::new(Mutex::new(HashSet::new())),
+            drivers: Arc::new(Mutex::new(HashMap::new())),
             context: Arc::new(context),
         }
     }
@@ -609,7 +1062,7 @@ impl DisplayLinkManager {
         // Check if already initialized
         {
             let drivers = self.drivers.lock().unwrap();
-            if drivers.contains(&device_id) {
+            if drivers.contains_key(&device_id) {
                 return Ok(());
             }
         }
@@ -637,65 +1090,241 @@ impl DisplayLinkManager {
             .open()
             .map_err(|e| format!("Failed to open device: {}", e))?;
 
-        // Create EVDI device
-        let evdi_handle = unsafe {
-            let card_no = evdi_add_device();
-            if card_no < 0 {
-                return Err("Failed to add EVDI device".to_string());
-            }
-            println!("  Created EVDI device: /dev/dri/card{}", card_no);
-
-            let handle = evdi_open(card_no);
-            if handle == EVDI_INVALID_HANDLE {
-                return Err("Failed to open EVDI device".to_string());
-            }
-
-            // evdi_connect(handle, DEFAULT_EDID.as_ptr(), DEFAULT_EDID.len() as u32, 0);
-
-            evdi_enable_cursor_events(handle, true);
-            handle
+        // Attach the card to the dock so udev and compositors can tell
+        // which display belongs to which device
+        let usb_path = device
+            .port_numbers()
+            .ok()
+            .and_then(|ports| evdi::usb_parent_path(device.bus_number(), &ports));
+        // Skip cards already driving another dock
+        let claimed: Vec<i32> = {
+            let drivers = self.drivers.lock().unwrap();
+            drivers.values().map(|d| d.card_no).collect()
         };
 
-        // Create driver instance
-        let mut driver = DisplayLinkDriver::new(device_id.clone(), evdi_handle, handle);
-
-        // Initialize USB device
-        driver.initialize_device()?;
-
-        println!("  ✓ Device initialized successfully");
-
-        // Spawn event loop thread
-        let device_id_clone = device_id.clone();
+        // The EVDI card is opened on the driver's own thread and never
+        // leaves it; wait for initialization to finish before carrying on
+        let capabilities = DeviceCapabilities::for_product(device_desc.product_id());
+        let (init_tx, init_rx) = mpsc::channel();
+        let (commands, command_rx) = mpsc::channel();
+        let thread_device_id = device_id.clone();
+        let thread_usb_path = usb_path.clone();
         thread::spawn(move || {
+            let in_use = |card_no| claimed.contains(&card_no);
+            let card = match &thread_usb_path {
+                Some(path) => EvdiCard::open_attached_to(path, in_use),
+                None => evdi::claim_card(in_use).and_then(EvdiCard::open),
+            };
+            let card = match card {
+                Ok(mut card) => {
+                    card.enable_cursor_events(true);
+                    card
+                }
+                Err(e) => {
+                    let _ = init_tx.send(Err(e));
+                    return;
+                }
+            };
+            let card_no = card.card_no();
+
+            // Create driver instance and initialize USB device
+            let mut driver = DisplayLinkDriver::new(
+                thread_device_id.clone(),
+                card,
+                handle,
+                capabilities,
+                command_rx,
+            );
+            if let Err(e) = driver.initialize_device() {
+                let _ = init_tx.send(Err(e));
+                return;
+            }
+            let _ = init_tx.send(Ok((card_no, driver.running.clone())));
+
             if let Err(e) = driver.run() {
-                eprintln!("[{}] Driver error: {}", device_id_clone, e);
+                eprintln!("[{}] Driver error: {}", thread_device_id, e);
             }
         });
+        let (card_no, running) = init_rx
+            .recv()
+            .map_err(|_| "Driver thread exited during initialization".to_string())??;
+
+        println!(
+            "  ✓ Device initialized successfully on /dev/dri/card{}",
+            card_no
+        );
+        match &usb_path {
+            Some(path) => println!("  Card attached to {}", path),
+            None => println!("  Card not attached to a USB parent"),
+        }
 
         // Mark device as active
         {
             let mut drivers = self.drivers.lock().unwrap();
-            drivers.insert(device_id);
+            drivers.insert(
+                device_id,
+                ActiveDevice {
+                    card_no,
+                    usb_path,
+                    running,
+                    commands,
+                },
+            );
         }
 
         Ok(())
     }
 
+    // Stop the drivers of docks that were unplugged; the kernel removes the
+    // EVDI cards attached to them
+    fn remove_unplugged(&self, present: &HashSet<String>) {
+        let mut drivers = self.drivers.lock().unwrap();
+        drivers.retain(|device_id, active| {
+            if present.contains(device_id) {
+                return true;
+            }
+            println!(
+                "DisplayLink device {} removed; releasing /dev/dri/card{}{}",
+                device_id,
+                active.card_no,
+                active
+                    .usb_path
+                    .as_deref()
+                    .map(|path| format!(" (attached to {})", path))
+                    .unwrap_or_default()
+            );
+            *active.running.lock().unwrap() = false;
+            false
+        });
+    }
+
+    // Answer a control socket request
+    fn handle_control(
+        drivers: &Mutex<HashMap<String, ActiveDevice>>,
+        request: Request,
+    ) -> Result<String, String> {
+        match request {
+            Request::List => {
+                let (reply, replies) = mpsc::channel();
+                let mut devices: Vec<(String, i32)> = {
+                    let drivers = drivers.lock().unwrap();
+                    for active in drivers.values() {
+                        let _ = active.commands.send(DriverCommand::Stats {
+                            reply: reply.clone(),
+                        });
+                    }
+                    drivers
+                        .iter()
+                        .map(|(device_id, active)| (device_id.clone(), active.card_no))
+                        .collect()
+                };
+                devices.sort();
+
+                // Drivers answer between EVDI events; a busy one is listed
+                // without statistics
+                let deadline = Instant::now() + control::STATS_TIMEOUT;
+                let mut stats = HashMap::new();
+                while stats.len() < devices.len() {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match replies.recv_timeout(timeout) {
+                        Ok((device_id, line)) => {
+                            stats.insert(device_id, line);
+                        }
+                        Err(_) => break,
+                    }
+                }
+                let devices: Vec<String> = devices
+                    .iter()
+                    .map(|(device_id, card_no)| match stats.get(device_id) {
+                        Some(line) => format!("{} card{} {}", device_id, card_no, line),
+                        None => format!("{} card{}", device_id, card_no),
+                    })
+                    .collect();
+                Ok(devices.join(", "))
+            }
+            Request::Device(target, command) => {
+                let device_ids = Self::send_command(drivers, &target, || command.clone())?;
+                Ok(device_ids.join(", "))
+            }
+            Request::Snapshot(target, dir) => {
+                let (reply, replies) = mpsc::channel();
+                let device_ids =
+                    Self::send_command(drivers, &target, || DriverCommand::Snapshot {
+                        dir: dir.clone(),
+                        reply: reply.clone(),
+                    })?;
+                // Drivers answer between EVDI events
+                let mut results = Vec::new();
+                let mut failed = false;
+                for _ in &device_ids {
+                    match replies.recv_timeout(control::SNAPSHOT_TIMEOUT) {
+                        Ok(Ok(files)) => results.push(files),
+                        Ok(Err(e)) => {
+                            failed = true;
+                            results.push(e);
+                        }
+                        Err(_) => {
+                            failed = true;
+                            results.push("timed out waiting for snapshots".to_string());
+                            break;
+                        }
+                    }
+                }
+                let results = results.join("; ");
+                if failed {
+                    Err(results)
+                } else {
+                    Ok(results)
+                }
+            }
+        }
+    }
+
+    // Queue a command for each driver `target` names, returning their ids
+    fn send_command(
+        drivers: &Mutex<HashMap<String, ActiveDevice>>,
+        target: &control::Target,
+        command: impl Fn() -> DriverCommand,
+    ) -> Result<Vec<String>, String> {
+        let drivers = drivers.lock().unwrap();
+        let mut matched: Vec<(&String, &ActiveDevice)> = drivers
+            .iter()
+            .filter(|(device_id, _)| target.matches(device_id))
+            .collect();
+        if matched.is_empty() {
+            return Err(match target {
+                control::Target::Device(id) => format!("no device {}", id),
+                control::Target::All => "no devices".to_string(),
+            });
+        }
+        matched.sort_by_key(|(device_id, _)| *device_id);
+        for (device_id, active) in &matched {
+            active
+                .commands
+                .send(command())
+                .map_err(|_| format!("driver for {} has stopped", device_id))?;
+        }
+        Ok(matched.iter().map(|(id, _)| id.to_string()).collect())
+    }
+
     fn scan_devices(&self) -> Result<(), String> {
         let devices = self
             .context
             .devices()
             .map_err(|e| format!("Failed to list devices: {}", e))?;
 
+        let mut present = HashSet::new();
         for device in devices.iter() {
             if let Ok(desc) = device.device_descriptor() {
                 if desc.vendor_id() == DISPLAYLINK_VID && desc.product_id() == DISPLAYLINK_PID {
+                    present.insert(format!("{}:{}", device.bus_number(), device.address()));
                     if let Err(e) = self.initialize_device(device) {
                         eprintln!("Failed to initialize device: {}", e);
                     }
                 }
             }
         }
+        self.remove_unplugged(&present);
 
         Ok(())
     }
@@ -709,6 +1338,15 @@ impl DisplayLinkManager {
         );
         println!("Press Ctrl+C to exit\n");
 
+        let config = DriverConfig::from_env();
+        if let Some(path) = &config.control_socket {
+            let drivers = self.drivers.clone();
+            match control::spawn(path, move |request| Self::handle_control(&drivers, request)) {
+                Ok(()) => println!("Control socket: {}", path.display()),
+                Err(e) => eprintln!("Control socket disabled: {}", e),
+            }
+        }
+
         // Initial scan
         self.scan_devices()?;
 
@@ -732,18 +1370,9 @@ fn main() {
     println!();
 
     // Initialize EVDI library
-    unsafe {
-        let mut version = evdi_lib_version {
-            version_major: 0,
-            version_minor: 0,
-            version_patchlevel: 0,
-        };
-        evdi_get_lib_version(&mut version);
-        println!(
-            "EVDI library version: {}.{}.{}",
-            version.version_major, version.version_minor, version.version_patchlevel
-        );
-    }
+    evdi::install_logging();
+    snapshot::install_signal_handler();
+    println!("EVDI backend: {}", evdi::backend_description());
 
     // Initialize USB context and manager
     match rusb::Context::new() {
@@ -0,0 +1,853 @@
+// Pipelined frame path
+//
+// Capture, encode and USB transfer run as separate stages so frame N+1 is
+// compressed while frame N is still on the wire. Capture (the EVDI event
+// thread) copies the damaged region into a pooled frame and hands it to the
+// encode thread through a single latest-wins slot: if the encoder hasn't
+// picked up the previous frame yet, the new damage is merged into it and the
+// stale contents are overwritten. The encoder feeds the transfer thread
+// through a bounded queue, so a slow link backs up into the slot instead of
+// building a backlog. Frames and encoded streams are recycled between stages.
+
+use crate::band_compressor::BandCompressor;
+use crate::color::ColorLut;
+use crate::cursor::SoftwareCursor;
+use crate::displaylink_protocol::CommandBuilder;
+use crate::encoder::{EncoderKind, Rect};
+use crate::pixel_format::PixelFormat;
+use crate::quality::FrameSample;
+use crate::scaler::Scaler;
+use crate::transform::Transform;
+use crate::usb_transfer::{TransferReport, TransferStats};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryIter};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Encoded frames queued between the encode and transfer stages
+const TRANSFER_QUEUE_DEPTH: usize = 1;
+
+/// Link measurements buffered until the driver collects them
+const SAMPLE_QUEUE_DEPTH: usize = 16;
+
+/// Destination of the transfer stage
+pub trait FrameSink: Send + 'static {
+    fn send(&mut self, data: &[u8]) -> Result<TransferReport, String>;
+
+    /// Totals since the sink was created
+    fn stats(&self) -> TransferStats {
+        TransferStats::default()
+    }
+}
+
+/// Geometry of a framebuffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameLayout {
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    pub format: PixelFormat,
+}
+
+impl FrameLayout {
+    fn size(&self) -> usize {
+        self.stride * self.height
+    }
+}
+
+/// Frame contents captured for encoding
+struct CapturedFrame {
+    data: Vec<u8>,
+    layout: FrameLayout,
+    region: Rect,
+    encoder: EncoderKind,
+    color: Option<Arc<ColorLut>>,
+    epoch: u64,
+}
+
+/// Command stream ready for the transfer stage
+struct EncodedFrame {
+    stream: Vec<u8>,
+    epoch: u64,
+    encode_time: Duration,
+    width: usize,
+    height: usize,
+}
+
+/// The last command stream the device accepted, kept for snapshots
+#[derive(Debug, Clone)]
+pub struct SentFrame {
+    pub stream: Vec<u8>,
+    /// Size of the image the stream updates
+    pub width: usize,
+    pub height: usize,
+}
+
+struct Slot {
+    frame: Option<CapturedFrame>,
+    shutdown: bool,
+}
+
+struct Shared {
+    slot: Mutex<Slot>,
+    ready: Condvar,
+    free_frames: Mutex<Vec<CapturedFrame>>,
+    free_streams: Mutex<Vec<Vec<u8>>>,
+    last_sent: Mutex<Option<SentFrame>>,
+    transfer_stats: Mutex<TransferStats>,
+    // Bumped on mode changes; frames from an older epoch are discarded
+    epoch: AtomicU64,
+}
+
+impl Shared {
+    fn recycle_frame(&self, frame: CapturedFrame) {
+        self.free_frames.lock().unwrap().push(frame);
+    }
+
+    fn is_current(&self, epoch: u64) -> bool {
+        self.epoch.load(Ordering::Acquire) == epoch
+    }
+}
+
+/// Capture -> encode -> transfer pipeline for one device
+pub struct Pipeline {
+    device_id: String,
+    shared: Arc<Shared>,
+    samples: Receiver<FrameSample>,
+    encode_thread: Option<JoinHandle<()>>,
+    transfer_thread: Option<JoinHandle<()>>,
+    merged_frames: u64,
+    transform: Transform,
+    color: Option<Arc<ColorLut>>,
+    scaler: Option<Scaler>,
+    staging: Vec<u8>, // Oriented full-size image the scaler reads from
+    staging_layout: Option<FrameLayout>,
+}
+
+impl Pipeline {
+    /// Start the encode and transfer stages
+    pub fn new<S: FrameSink>(device_id: String, compressor: BandCompressor, sink: S) -> Self {
+        let shared = Arc::new(Shared {
+            slot: Mutex::new(Slot {
+                frame: None,
+                shutdown: false,
+            }),
+            ready: Condvar::new(),
+            free_frames: Mutex::new(Vec::new()),
+            free_streams: Mutex::new(Vec::new()),
+            last_sent: Mutex::new(None),
+            transfer_stats: Mutex::new(TransferStats::default()),
+            epoch: AtomicU64::new(0),
+        });
+        let (encoded_tx, encoded_rx) = sync_channel(TRANSFER_QUEUE_DEPTH);
+        let (sample_tx, samples) = sync_channel(SAMPLE_QUEUE_DEPTH);
+
+        let encode_shared = shared.clone();
+        let encode_id = device_id.clone();
+        let encode_thread =
+            thread::spawn(move || encode_stage(encode_id, encode_shared, compressor, encoded_tx));
+
+        let transfer_shared = shared.clone();
+        let transfer_id = device_id.clone();
+        let transfer_thread = thread::spawn(move || {
+            transfer_stage(transfer_id, transfer_shared, sink, encoded_rx, sample_tx)
+        });
+
+        Pipeline {
+            device_id,
+            shared,
+            samples,
+            encode_thread: Some(encode_thread),
+            transfer_thread: Some(transfer_thread),
+            merged_frames: 0,
+            transform: Transform::default(),
+            color: None,
+            scaler: None,
+            staging: Vec::new(),
+            staging_layout: None,
+        }
+    }
+
+    /// Rotate or reflect captured frames from the next one on
+    pub fn set_transform(&mut self, transform: Transform) {
+        self.transform = transform;
+    }
+
+    /// Color-correct frames from the next one on
+    pub fn set_color(&mut self, color: Option<Arc<ColorLut>>) {
+        self.color = color;
+    }
+
+    /// Resample captured frames to the panel's native mode, or stop doing so
+    pub fn set_scaler(&mut self, scaler: Option<Scaler>) {
+        self.scaler = scaler;
+        self.staging_layout = None;
+    }
+
+    /// Capture a damaged region of `source` and queue it for encoding
+    ///
+    /// If the previous frame is still waiting for the encoder, it is replaced
+    /// by this one and its damage is carried over.
+    pub fn submit(
+        &mut self,
+        source: &[u8],
+        layout: FrameLayout,
+        region: Rect,
+        encoder: EncoderKind,
+        cursor: Option<&SoftwareCursor>,
+    ) {
+        let region = region.clamp_to(layout.width, layout.height);
+        if region.is_empty() || source.len() < layout.size() {
+            return;
+        }
+        // From here on, geometry is in the monitor's orientation
+        let source_layout = layout;
+        let layout = self.transform.layout(&source_layout);
+        let region = self
+            .transform
+            .map_rect(&region, source_layout.width, source_layout.height);
+
+        // Resampled frames are staged at full size first, since the filter
+        // reads around the damage
+        let scaler = self
+            .scaler
+            .filter(|scaler| scaler.applies_to(layout.width, layout.height));
+        let (frame_layout, frame_region) = match scaler {
+            Some(scaler) => {
+                let frame_layout = scaler.layout(&layout);
+                let frame_region = if self.stage(source, &source_layout, &layout, region, cursor) {
+                    // Covers the black bars too
+                    Rect::new(0, 0, frame_layout.width, frame_layout.height)
+                } else {
+                    scaler.map_rect(&region, layout.width, layout.height)
+                };
+                (frame_layout, frame_region)
+            }
+            None => (layout, region),
+        };
+        let epoch = self.shared.epoch.load(Ordering::Acquire);
+
+        let pending = self.shared.slot.lock().unwrap().frame.take();
+        let (mut frame, region) = match pending {
+            Some(frame) if frame.epoch == epoch && frame.layout == frame_layout => {
+                self.merged_frames += 1;
+                let merged = frame.region.union(&frame_region);
+                (frame, merged)
+            }
+            stale => {
+                if let Some(frame) = stale {
+                    self.shared.recycle_frame(frame);
+                }
+                (self.take_free_frame(frame_layout), frame_region)
+            }
+        };
+
+        match scaler {
+            Some(scaler) => scaler.scale_region(
+                &mut frame.data,
+                &frame_layout,
+                &self.staging,
+                &layout,
+                &region,
+            ),
+            None => capture(
+                self.transform,
+                &mut frame.data,
+                &layout,
+                source,
+                &source_layout,
+                &region,
+                cursor,
+            ),
+        }
+        frame.region = region;
+        frame.encoder = encoder;
+        frame.color = self.color.clone();
+        frame.epoch = epoch;
+
+        let mut slot = self.shared.slot.lock().unwrap();
+        slot.frame = Some(frame);
+        self.shared.ready.notify_one();
+    }
+
+    // Bring the damaged part of the staged image up to date
+    //
+    // Returns true if the staging buffer was (re)started, in which case it
+    // now holds the whole image.
+    fn stage(
+        &mut self,
+        source: &[u8],
+        source_layout: &FrameLayout,
+        layout: &FrameLayout,
+        region: Rect,
+        cursor: Option<&SoftwareCursor>,
+    ) -> bool {
+        let fresh = self.staging_layout != Some(*layout);
+        let region = if fresh {
+            self.staging.clear();
+            self.staging.resize(layout.size(), 0);
+            self.staging_layout = Some(*layout);
+            Rect::new(0, 0, layout.width, layout.height)
+        } else {
+            region
+        };
+        capture(
+            self.transform,
+            &mut self.staging,
+            layout,
+            source,
+            source_layout,
+            &region,
+            cursor,
+        );
+        fresh
+    }
+
+    /// Discard queued and in-flight frames, e.g. before a mode change
+    pub fn reset(&mut self) {
+        self.shared.epoch.fetch_add(1, Ordering::AcqRel);
+        self.staging_layout = None;
+        if let Some(frame) = self.shared.slot.lock().unwrap().frame.take() {
+            self.shared.recycle_frame(frame);
+        }
+    }
+
+    /// Measurements of frames sent since the last call
+    pub fn samples(&self) -> TryIter<'_, FrameSample> {
+        self.samples.try_iter()
+    }
+
+    /// Copy of the last command stream sent to the device
+    pub fn last_sent(&self) -> Option<SentFrame> {
+        self.shared.last_sent.lock().unwrap().clone()
+    }
+
+    /// Totals of the transfer stage's sink
+    pub fn transfer_stats(&self) -> TransferStats {
+        *self.shared.transfer_stats.lock().unwrap()
+    }
+
+    /// Frames that were superseded before the encoder got to them
+    pub fn merged_frames(&self) -> u64 {
+        self.merged_frames
+    }
+
+    /// Stop the stages, letting the frame in flight finish
+    pub fn shutdown(&mut self) {
+        {
+            let mut slot = self.shared.slot.lock().unwrap();
+            slot.shutdown = true;
+            self.shared.ready.notify_all();
+        }
+        for handle in [self.encode_thread.take(), self.transfer_thread.take()]
+            .into_iter()
+            .flatten()
+        {
+            if handle.join().is_err() {
+                eprintln!("[{}] Pipeline stage panicked", self.device_id);
+            }
+        }
+    }
+
+    fn take_free_frame(&self, layout: FrameLayout) -> CapturedFrame {
+        let mut frame = self
+            .shared
+            .free_frames
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| CapturedFrame {
+                data: Vec::new(),
+                layout,
+                region: Rect::default(),
+                encoder: EncoderKind::Raw16,
+                color: None,
+                epoch: 0,
+            });
+        // Only grows after a mode change
+        frame.data.resize(layout.size(), 0);
+        frame.layout = layout;
+        frame
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Copy `region` of `source` into `dst` in the monitor's orientation, with the
+// cursor on top
+fn capture(
+    transform: Transform,
+    dst: &mut [u8],
+    layout: &FrameLayout,
+    source: &[u8],
+    source_layout: &FrameLayout,
+    region: &Rect,
+    cursor: Option<&SoftwareCursor>,
+) {
+    if transform.is_identity() {
+        copy_region(dst, source, layout, region);
+    } else {
+        transform.copy_region(dst, layout, source, source_layout, region);
+    }
+    if let Some(cursor) = cursor {
+        let source_size = (source_layout.width, source_layout.height);
+        cursor.blend(dst, layout, *region, transform, source_size);
+    }
+}
+
+// Copy the rows of `region` between two buffers with the same layout
+fn copy_region(dst: &mut [u8], src: &[u8], layout: &FrameLayout, region: &Rect) {
+    let bpp = layout.format.bytes_per_pixel();
+    for y in region.y..region.y + region.height {
+        let start = y * layout.stride + region.x * bpp;
+        let end = start + region.width * bpp;
+        dst[start..end].copy_from_slice(&src[start..end]);
+    }
+}
+
+fn encode_stage(
+    device_id: String,
+    shared: Arc<Shared>,
+    mut compressor: BandCompressor,
+    output: SyncSender<EncodedFrame>,
+) {
+    let mut cmd_builder = CommandBuilder::new();
+
+    loop {
+        let frame = {
+            let mut slot = shared.slot.lock().unwrap();
+            loop {
+                if slot.shutdown {
+                    return;
+                }
+                if let Some(frame) = slot.frame.take() {
+                    break frame;
+                }
+                slot = shared.ready.wait(slot).unwrap();
+            }
+        };
+        if !shared.is_current(frame.epoch) {
+            shared.recycle_frame(frame);
+            continue;
+        }
+
+        let encode_start = Instant::now();
+        let mut stream = shared
+            .free_streams
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_default();
+        stream.clear();
+
+        // Color depth changes travel in-band so they stay ordered with frames
+        let previous_bpp = compressor.kind().bits_per_pixel();
+        compressor.set_encoder(frame.encoder);
+        compressor.set_color(frame.color.clone());
+        let bpp = frame.encoder.bits_per_pixel();
+        if bpp != previous_bpp {
+            stream.extend_from_slice(cmd_builder.set_color_depth(bpp));
+        }
+
+        let region = frame.region;
+        let layout = frame.layout;
+        compressor.compress(&frame.data, layout.stride, layout.format, region);
+        stream.extend_from_slice(compressor.output());
+        stream.extend_from_slice(cmd_builder.sync());
+        let encode_time = encode_start.elapsed();
+
+        vprintln!(
+            "[{}] Compressed {}x{} at ({}, {}): {} bytes -> {} bytes ({}, {} threads)",
+            device_id,
+            region.width,
+            region.height,
+            region.x,
+            region.y,
+            region.width * region.height * layout.format.bytes_per_pixel(),
+            stream.len(),
+            compressor.kind().name(),
+            compressor.threads()
+        );
+
+        let epoch = frame.epoch;
+        shared.recycle_frame(frame);
+
+        // Blocks while the transfer queue is full; capture keeps merging
+        // newer damage into the slot meanwhile
+        let encoded = EncodedFrame {
+            stream,
+            epoch,
+            encode_time,
+            width: layout.width,
+            height: layout.height,
+        };
+        if output.send(encoded).is_err() {
+            return;
+        }
+    }
+}
+
+fn transfer_stage<S: FrameSink>(
+    device_id: String,
+    shared: Arc<Shared>,
+    mut sink: S,
+    input: Receiver<EncodedFrame>,
+    samples: SyncSender<FrameSample>,
+) {
+    for encoded in input {
+        let mut stream = encoded.stream;
+        if shared.is_current(encoded.epoch) {
+            let result = sink.send(&stream);
+            *shared.transfer_stats.lock().unwrap() = sink.stats();
+            match result {
+                Ok(report) => {
+                    vprintln!(
+                        "[{}] ✓ Framebuffer sent: {} bytes in {} URB(s), {:.1}ms",
+                        device_id,
+                        report.bytes,
+                        report.urbs,
+                        report.elapsed.as_secs_f64() * 1000.0
+                    );
+                    // Measurements are advisory; drop them if nobody reads
+                    let _ = samples.try_send(FrameSample {
+                        bytes: report.bytes,
+                        urbs: report.urbs,
+                        encode_time: encoded.encode_time,
+                        transfer_time: report.elapsed,
+                    });
+                    // Keep it for snapshots and recycle the one before
+                    let sent = SentFrame {
+                        stream,
+                        width: encoded.width,
+                        height: encoded.height,
+                    };
+                    let previous = shared.last_sent.lock().unwrap().replace(sent);
+                    stream = previous.map(|sent| sent.stream).unwrap_or_default();
+                }
+                Err(e) => eprintln!("[{}] Failed to send framebuffer: {}", device_id, e),
+            }
+        }
+        shared.free_streams.lock().unwrap().push(stream);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::band_compressor::MIN_BAND_ROWS;
+    use crate::displaylink_protocol::DL_CODEC_RAW16;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::process::Command;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::sync::mpsc::{channel, Sender};
+
+    // Counts heap allocations while COUNT_ALLOCATIONS is set
+    struct CountingAllocator;
+
+    static COUNT_ALLOCATIONS: AtomicBool = AtomicBool::new(false);
+    static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+    impl CountingAllocator {
+        fn count(&self) {
+            if COUNT_ALLOCATIONS.load(Ordering::Relaxed) {
+                ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.count();
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            self.count();
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            self.count();
+            System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    // Set in the child process that runs the allocation test on its own
+    const ALLOCATION_TEST_VAR: &str = "DISPLAYLINK_TEST_COUNT_ALLOCATIONS";
+
+    // Records every stream and reports it on `sent`. A gated sink holds the
+    // link busy: each send waits for a permit on the gate, and all sends go
+    // through once the gate's sender is dropped.
+    struct RecordingSink {
+        streams: Arc<Mutex<Vec<Vec<u8>>>>,
+        started: Sender<()>,
+        sent: Sender<()>,
+        gate: Option<Receiver<()>>,
+    }
+
+    impl FrameSink for RecordingSink {
+        fn send(&mut self, data: &[u8]) -> Result<TransferReport, String> {
+            let _ = self.started.send(());
+            if let Some(gate) = &self.gate {
+                let _ = gate.recv();
+            }
+            self.streams.lock().unwrap().push(data.to_vec());
+            let _ = self.sent.send(());
+            Ok(report(data))
+        }
+    }
+
+    struct Harness {
+        pipeline: Pipeline,
+        streams: Arc<Mutex<Vec<Vec<u8>>>>,
+        started: Receiver<()>,
+        sent: Receiver<()>,
+        gate: Option<Sender<()>>,
+    }
+
+    impl Harness {
+        fn new(gated: bool) -> Self {
+            let streams = Arc::new(Mutex::new(Vec::new()));
+            let (started_tx, started) = channel();
+            let (sent_tx, sent) = channel();
+            let (gate_tx, gate_rx) = channel();
+            let sink = RecordingSink {
+                streams: streams.clone(),
+                started: started_tx,
+                sent: sent_tx,
+                gate: gated.then_some(gate_rx),
+            };
+            let compressor = BandCompressor::new(1, EncoderKind::Raw16);
+            Harness {
+                pipeline: Pipeline::new("test".to_string(), compressor, sink),
+                streams,
+                started,
+                sent,
+                gate: gated.then_some(gate_tx),
+            }
+        }
+
+        fn submit(&mut self, frame: &[u8], region: Rect) {
+            self.pipeline
+                .submit(frame, layout(8, 8), region, EncoderKind::Raw16, None);
+        }
+
+        fn wait_started(&self) {
+            self.started
+                .recv_timeout(TIMEOUT)
+                .expect("no frame reached the sink");
+        }
+
+        fn wait_sent(&self, count: usize) {
+            for _ in 0..count {
+                self.sent
+                    .recv_timeout(TIMEOUT)
+                    .expect("timed out waiting for frames");
+            }
+        }
+
+        // Until the encoder has picked up the frame waiting in the slot
+        fn wait_slot_taken(&self) {
+            let deadline = Instant::now() + TIMEOUT;
+            while self.pipeline.shared.slot.lock().unwrap().frame.is_some() {
+                assert!(Instant::now() < deadline, "encoder never took the frame");
+                thread::yield_now();
+            }
+        }
+
+        fn open_gate(&mut self) {
+            self.gate.take();
+        }
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn report(data: &[u8]) -> TransferReport {
+        TransferReport {
+            bytes: data.len(),
+            urbs: 1,
+            elapsed: Duration::from_millis(1),
+        }
+    }
+
+    fn layout(width: usize, height: usize) -> FrameLayout {
+        FrameLayout {
+            width,
+            height,
+            stride: width * 4,
+            format: PixelFormat::Xrgb8888,
+        }
+    }
+
+    #[test]
+    fn test_frame_reaches_sink_with_sync() {
+        let mut harness = Harness::new(false);
+        let frame = vec![0xFFu8; 8 * 8 * 4];
+        harness.submit(&frame, Rect::new(0, 0, 8, 8));
+        harness.wait_sent(1);
+        harness.pipeline.shutdown();
+
+        let sync = CommandBuilder::new().sync().to_vec();
+        let streams = harness.streams.lock().unwrap();
+        assert!(streams[0].ends_with(&sync));
+        let samples: Vec<FrameSample> = harness.pipeline.samples().collect();
+        assert_eq!(samples.len(), 1);
+        assert_eq!((samples[0].bytes, samples[0].urbs), (streams[0].len(), 1));
+
+        let sent = harness.pipeline.last_sent().unwrap();
+        assert_eq!(sent.stream, streams[0]);
+        assert_eq!((sent.width, sent.height), (8, 8));
+    }
+
+    #[test]
+    fn test_slow_link_merges_stale_frames() {
+        let mut harness = Harness::new(true);
+        let frame = vec![0u8; 8 * 8 * 4];
+        for i in 0..20 {
+            harness.submit(&frame, Rect::new(i % 8, 0, 1, 1));
+        }
+        // Everything not merged away is still on its way to the sink
+        let merged = harness.pipeline.merged_frames() as usize;
+        harness.open_gate();
+        harness.wait_sent(20 - merged);
+        harness.pipeline.shutdown();
+
+        // At most one frame each in the sink, the transfer queue, the encoder
+        // and the slot; the rest were merged into a later frame
+        let sent = harness.streams.lock().unwrap().len();
+        assert!(sent <= 4);
+        assert_eq!(sent + merged, 20);
+    }
+
+    #[test]
+    fn test_merged_frame_covers_union_of_damage() {
+        let mut harness = Harness::new(true);
+        let frame = vec![0u8; 8 * 8 * 4];
+        let full = Rect::new(0, 0, 8, 8);
+        // First frame occupies the link
+        harness.submit(&frame, full);
+        harness.wait_started();
+        // Second waits in the transfer queue, third is being encoded and
+        // blocked on the queue
+        for _ in 0..2 {
+            harness.submit(&frame, full);
+            harness.wait_slot_taken();
+        }
+        // These two meet in the slot
+        harness.submit(&frame, Rect::new(0, 0, 2, 2));
+        harness.submit(&frame, Rect::new(6, 6, 2, 2));
+        harness.open_gate();
+        harness.wait_sent(4);
+        harness.pipeline.shutdown();
+
+        assert_eq!(harness.pipeline.merged_frames(), 1);
+        let header = CommandBuilder::new()
+            .damage_rect(DL_CODEC_RAW16, 0, 0, 8, 8)
+            .to_vec();
+        assert!(harness.streams.lock().unwrap()[3].starts_with(&header));
+    }
+
+    #[test]
+    fn test_reset_discards_pending_frame() {
+        let mut harness = Harness::new(false);
+        let frame = vec![0u8; 8 * 8 * 4];
+        harness.pipeline.shared.slot.lock().unwrap().frame = Some(CapturedFrame {
+            data: frame.clone(),
+            layout: layout(8, 8),
+            region: Rect::new(0, 0, 8, 8),
+            encoder: EncoderKind::Raw16,
+            color: None,
+            epoch: 0,
+        });
+        harness.pipeline.reset();
+        harness.submit(&frame, Rect::new(0, 0, 1, 1));
+        harness.wait_sent(1);
+        harness.pipeline.shutdown();
+
+        // Only the frame captured after the reset went out
+        let header = CommandBuilder::new()
+            .damage_rect(DL_CODEC_RAW16, 0, 0, 1, 1)
+            .to_vec();
+        let streams = harness.streams.lock().unwrap();
+        assert_eq!(streams.len(), 1);
+        assert!(streams[0].starts_with(&header));
+        assert_eq!(harness.pipeline.merged_frames(), 0);
+    }
+
+    // Sends nothing, just reports each frame
+    struct SignalSink(SyncSender<()>);
+
+    impl FrameSink for SignalSink {
+        fn send(&mut self, data: &[u8]) -> Result<TransferReport, String> {
+            let _ = self.0.send(());
+            Ok(report(data))
+        }
+    }
+
+    #[test]
+    fn test_steady_state_frames_do_not_allocate() {
+        // Other tests allocate concurrently, so count in a process that runs
+        // only this one
+        if std::env::var_os(ALLOCATION_TEST_VAR).is_none() {
+            let output = Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "pipeline::tests::test_steady_state_frames_do_not_allocate",
+                    "--exact",
+                    "--test-threads=1",
+                ])
+                .env(ALLOCATION_TEST_VAR, "1")
+                .env_remove("DISPLAYLINK_DRIVER_VERBOSE")
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return;
+        }
+
+        let (sent_tx, sent) = sync_channel(1);
+        let compressor = BandCompressor::new(3, EncoderKind::Rle);
+        let mut pipeline = Pipeline::new("test".to_string(), compressor, SignalSink(sent_tx));
+        let layout = layout(64, 3 * MIN_BAND_ROWS);
+        let frame: Vec<u8> = (0..layout.size()).map(|i| (i / 7) as u8).collect();
+        let mut send_frame = |region: Rect| {
+            pipeline.submit(&frame, layout, region, EncoderKind::Rle, None);
+            sent.recv_timeout(TIMEOUT)
+                .expect("frame never reached the sink");
+            // Until the transfer stage has recycled the previous stream
+            let deadline = Instant::now() + TIMEOUT;
+            while pipeline.shared.free_streams.lock().unwrap().is_empty() {
+                assert!(Instant::now() < deadline, "stream never recycled");
+                thread::yield_now();
+            }
+        };
+
+        // The first frames fill the frame and stream pools
+        let full = Rect::new(0, 0, layout.width, layout.height);
+        for _ in 0..4 {
+            send_frame(full);
+        }
+
+        COUNT_ALLOCATIONS.store(true, Ordering::SeqCst);
+        for i in 0..20 {
+            send_frame(Rect::new(0, i, layout.width, layout.height - i));
+        }
+        COUNT_ALLOCATIONS.store(false, Ordering::SeqCst);
+        assert_eq!(ALLOCATIONS.load(Ordering::SeqCst), 0);
+    }
+}
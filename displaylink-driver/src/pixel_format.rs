@@ -0,0 +1,180 @@
+// Framebuffer pixel formats
+//
+// EVDI reports the compositor's framebuffer layout as a DRM fourcc code plus
+// bits per pixel. These helpers decode each supported layout to 8-bit RGB so
+// the encoders never have to assume a packed BGRA buffer.
+
+/// Build a DRM fourcc code from its four characters
+pub const fn fourcc_code(a: u8, b: u8, c: u8, d: u8) -> u32 {
+    (a as u32) | ((b as u32) << 8) | ((c as u32) << 16) | ((d as u32) << 24)
+}
+
+/// DRM fourcc codes (see drm_fourcc.h)
+pub const DRM_FORMAT_XRGB8888: u32 = fourcc_code(b'X', b'R', b'2', b'4');
+pub const DRM_FORMAT_ARGB8888: u32 = fourcc_code(b'A', b'R', b'2', b'4');
+pub const DRM_FORMAT_XBGR8888: u32 = fourcc_code(b'X', b'B', b'2', b'4');
+pub const DRM_FORMAT_ABGR8888: u32 = fourcc_code(b'A', b'B', b'2', b'4');
+pub const DRM_FORMAT_RGB565: u32 = fourcc_code(b'R', b'G', b'1', b'6');
+
+/// Source framebuffer pixel layout
+///
+/// DRM formats are little-endian, so XRGB8888 is stored as B, G, R, X bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Xrgb8888,
+    Argb8888,
+    Xbgr8888,
+    Abgr8888,
+    Rgb565,
+}
+
+impl PixelFormat {
+    /// Map a DRM fourcc code to a supported format
+    pub fn from_fourcc(fourcc: u32) -> Option<Self> {
+        match fourcc {
+            DRM_FORMAT_XRGB8888 => Some(PixelFormat::Xrgb8888),
+            DRM_FORMAT_ARGB8888 => Some(PixelFormat::Argb8888),
+            DRM_FORMAT_XBGR8888 => Some(PixelFormat::Xbgr8888),
+            DRM_FORMAT_ABGR8888 => Some(PixelFormat::Abgr8888),
+            DRM_FORMAT_RGB565 => Some(PixelFormat::Rgb565),
+            _ => None,
+        }
+    }
+
+    /// Format for an EVDI mode, falling back on bits per pixel when the
+    /// fourcc is missing or unknown
+    pub fn from_mode(fourcc: u32, bits_per_pixel: i32) -> Self {
+        Self::from_fourcc(fourcc).unwrap_or(match bits_per_pixel {
+            16 => PixelFormat::Rgb565,
+            _ => PixelFormat::Xrgb8888,
+        })
+    }
+
+    pub fn fourcc(self) -> u32 {
+        match self {
+            PixelFormat::Xrgb8888 => DRM_FORMAT_XRGB8888,
+            PixelFormat::Argb8888 => DRM_FORMAT_ARGB8888,
+            PixelFormat::Xbgr8888 => DRM_FORMAT_XBGR8888,
+            PixelFormat::Abgr8888 => DRM_FORMAT_ABGR8888,
+            PixelFormat::Rgb565 => DRM_FORMAT_RGB565,
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb565 => 2,
+            _ => 4,
+        }
+    }
+
+    /// Decode one pixel to 8-bit R, G, B
+    ///
+    /// Alpha is ignored; the display is always opaque.
+    #[inline]
+    pub fn read_rgb(self, px: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::Xrgb8888 | PixelFormat::Argb8888 => [px[2], px[1], px[0]],
+            PixelFormat::Xbgr8888 | PixelFormat::Abgr8888 => [px[0], px[1], px[2]],
+            PixelFormat::Rgb565 => {
+                let value = u16::from_le_bytes([px[0], px[1]]);
+                let r = ((value >> 11) & 0x1F) as u8;
+                let g = ((value >> 5) & 0x3F) as u8;
+                let b = (value & 0x1F) as u8;
+                [
+                    (r << 3) | (r >> 2),
+                    (g << 2) | (g >> 4),
+                    (b << 3) | (b >> 2),
+                ]
+            }
+        }
+    }
+
+    /// Encode 8-bit R, G, B into one pixel, leaving any alpha byte alone
+    #[inline]
+    pub fn write_rgb(self, px: &mut [u8], [r, g, b]: [u8; 3]) {
+        match self {
+            PixelFormat::Xrgb8888 | PixelFormat::Argb8888 => px[..3].copy_from_slice(&[b, g, r]),
+            PixelFormat::Xbgr8888 | PixelFormat::Abgr8888 => px[..3].copy_from_slice(&[r, g, b]),
+            PixelFormat::Rgb565 => {
+                px[..2].copy_from_slice(&rgb888_to_rgb565(r, g, b).to_le_bytes());
+            }
+        }
+    }
+
+    /// Decode one pixel straight to RGB565
+    #[inline]
+    pub fn read_rgb565(self, px: &[u8]) -> u16 {
+        match self {
+            PixelFormat::Rgb565 => u16::from_le_bytes([px[0], px[1]]),
+            _ => {
+                let [r, g, b] = self.read_rgb(px);
+                rgb888_to_rgb565(r, g, b)
+            }
+        }
+    }
+}
+
+/// Pack 8-bit channels into RGB565
+#[inline]
+pub fn rgb888_to_rgb565(r: u8, g: u8, b: u8) -> u16 {
+    ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fourcc_values() {
+        // Values from drm_fourcc.h
+        assert_eq!(DRM_FORMAT_XRGB8888, 0x3432_5258);
+        assert_eq!(DRM_FORMAT_RGB565, 0x3631_4752);
+        assert_eq!(
+            PixelFormat::from_fourcc(DRM_FORMAT_XBGR8888),
+            Some(PixelFormat::Xbgr8888)
+        );
+        assert_eq!(PixelFormat::from_fourcc(0), None);
+    }
+
+    #[test]
+    fn test_from_mode_fallback() {
+        assert_eq!(PixelFormat::from_mode(0, 32), PixelFormat::Xrgb8888);
+        assert_eq!(PixelFormat::from_mode(0, 16), PixelFormat::Rgb565);
+        assert_eq!(
+            PixelFormat::from_mode(DRM_FORMAT_ARGB8888, 32),
+            PixelFormat::Argb8888
+        );
+    }
+
+    #[test]
+    fn test_read_rgb_per_format() {
+        // Pure red in each layout
+        assert_eq!(PixelFormat::Xrgb8888.read_rgb(&[0, 0, 255, 0]), [255, 0, 0]);
+        assert_eq!(
+            PixelFormat::Argb8888.read_rgb(&[0, 0, 255, 128]),
+            [255, 0, 0]
+        );
+        assert_eq!(PixelFormat::Xbgr8888.read_rgb(&[255, 0, 0, 0]), [255, 0, 0]);
+        assert_eq!(PixelFormat::Rgb565.read_rgb(&[0x00, 0xF8]), [255, 0, 0]);
+    }
+
+    #[test]
+    fn test_write_rgb_round_trips() {
+        for format in [
+            PixelFormat::Xrgb8888,
+            PixelFormat::Abgr8888,
+            PixelFormat::Rgb565,
+        ] {
+            let mut px = [0u8; 4];
+            format.write_rgb(&mut px, [255, 0, 255]);
+            assert_eq!(format.read_rgb(&px), [255, 0, 255]);
+        }
+    }
+
+    #[test]
+    fn test_read_rgb565_per_format() {
+        assert_eq!(PixelFormat::Xrgb8888.read_rgb565(&[0, 255, 0, 0]), 0x07E0);
+        assert_eq!(PixelFormat::Xbgr8888.read_rgb565(&[0, 0, 255, 0]), 0x001F);
+        assert_eq!(PixelFormat::Rgb565.read_rgb565(&[0x1F, 0x00]), 0x001F);
+    }
+}
@@ -0,0 +1,181 @@
+// Display power management
+//
+// DPMS changes from the compositor only change the output's power level. The
+// EVDI connector stays attached throughout, so screen blanking never looks
+// like an unplug and windows stay where they are. Leaving ON blanks the
+// screen, stops frame transfer and lowers the output power; returning to ON
+// restores the mode and repaints everything.
+//
+// The compositor can also disable the CRTC while the output stays powered.
+// Nothing is streamed then either, and the frame buffer goes back to EVDI.
+
+use std::fmt;
+
+/// DPMS levels as reported by EVDI
+pub const DPMS_ON: i32 = 0;
+pub const DPMS_STANDBY: i32 = 1;
+pub const DPMS_SUSPEND: i32 = 2;
+pub const DPMS_OFF: i32 = 3;
+
+/// Output power level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PowerState {
+    On,
+    Standby,
+    Suspend,
+    Off,
+}
+
+impl PowerState {
+    pub fn from_dpms(mode: i32) -> Option<Self> {
+        match mode {
+            DPMS_ON => Some(PowerState::On),
+            DPMS_STANDBY => Some(PowerState::Standby),
+            DPMS_SUSPEND => Some(PowerState::Suspend),
+            DPMS_OFF => Some(PowerState::Off),
+            _ => None,
+        }
+    }
+
+    /// Value of the device power register
+    pub fn register_value(self) -> u16 {
+        match self {
+            PowerState::On => 0,
+            PowerState::Standby => 1,
+            PowerState::Suspend => 2,
+            PowerState::Off => 3,
+        }
+    }
+}
+
+impl fmt::Display for PowerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PowerState::On => "ON",
+            PowerState::Standby => "STANDBY",
+            PowerState::Suspend => "SUSPEND",
+            PowerState::Off => "OFF",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What the driver has to do for a DPMS change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerTransition {
+    /// Leave ON: blank, stop sending frames, lower output power
+    Sleep(PowerState),
+    /// Move between low-power levels: only the output power changes
+    Lower(PowerState),
+    /// Back to ON: restore power and mode, then repaint
+    Wake,
+}
+
+/// Tracks the output power level and CRTC state across EVDI events
+#[derive(Debug)]
+pub struct PowerManager {
+    state: PowerState,
+    crtc_enabled: bool,
+}
+
+impl PowerManager {
+    pub fn new() -> Self {
+        PowerManager {
+            state: PowerState::On,
+            crtc_enabled: true,
+        }
+    }
+
+    pub fn state(&self) -> PowerState {
+        self.state
+    }
+
+    /// Whether the output is powered
+    pub fn is_on(&self) -> bool {
+        self.state == PowerState::On
+    }
+
+    pub fn is_crtc_enabled(&self) -> bool {
+        self.crtc_enabled
+    }
+
+    /// Whether frames should be captured and sent
+    pub fn is_streaming(&self) -> bool {
+        self.is_on() && self.crtc_enabled
+    }
+
+    /// Apply a CRTC state from EVDI, which reports it as a DPMS level: ON
+    /// when the CRTC is enabled, anything else when it is disabled
+    ///
+    /// Returns whether the CRTC is now enabled, or None if nothing changed.
+    pub fn set_crtc_state(&mut self, state: i32) -> Option<bool> {
+        let enabled = state == DPMS_ON;
+        if enabled == self.crtc_enabled {
+            return None;
+        }
+        self.crtc_enabled = enabled;
+        Some(enabled)
+    }
+
+    /// Apply a DPMS level from EVDI
+    ///
+    /// Returns None for unknown levels and repeats of the current one.
+    pub fn set_dpms(&mut self, mode: i32) -> Option<PowerTransition> {
+        let next = PowerState::from_dpms(mode)?;
+        let transition = match (self.state, next) {
+            (current, next) if current == next => return None,
+            (_, PowerState::On) => PowerTransition::Wake,
+            (PowerState::On, next) => PowerTransition::Sleep(next),
+            (_, next) => PowerTransition::Lower(next),
+        };
+        self.state = next;
+        Some(transition)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dpms_transitions() {
+        let mut power = PowerManager::new();
+        assert!(power.is_on());
+        assert_eq!(power.set_dpms(DPMS_ON), None);
+
+        assert_eq!(
+            power.set_dpms(DPMS_STANDBY),
+            Some(PowerTransition::Sleep(PowerState::Standby))
+        );
+        assert!(!power.is_on());
+        assert_eq!(
+            power.set_dpms(DPMS_OFF),
+            Some(PowerTransition::Lower(PowerState::Off))
+        );
+        assert_eq!(power.set_dpms(DPMS_OFF), None);
+        // Unknown levels leave the state alone
+        assert_eq!(power.set_dpms(7), None);
+        assert_eq!(power.state(), PowerState::Off);
+
+        assert_eq!(power.set_dpms(DPMS_ON), Some(PowerTransition::Wake));
+        assert!(power.is_on());
+    }
+
+    #[test]
+    fn test_disabled_crtc_pauses_streaming() {
+        let mut power = PowerManager::new();
+        assert!(power.is_streaming());
+        assert_eq!(power.set_crtc_state(DPMS_OFF), Some(false));
+        assert_eq!(power.set_crtc_state(DPMS_OFF), None);
+        assert!(power.is_on());
+        assert!(!power.is_streaming());
+
+        // Waking the output doesn't resume a disabled CRTC
+        power.set_dpms(DPMS_STANDBY);
+        power.set_dpms(DPMS_ON);
+        assert!(!power.is_streaming());
+
+        assert_eq!(power.set_crtc_state(DPMS_ON), Some(true));
+        assert!(power.is_streaming());
+    }
+}
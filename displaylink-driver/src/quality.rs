@@ -0,0 +1,334 @@
+// Adaptive quality control
+//
+// Measures how long each frame takes to encode and push over the bulk
+// endpoint, and walks a ladder of quality levels: first cheaper color depth,
+// then lower update rates. When the link has been idle for a while the
+// controller climbs back up one level at a time. The ladder never switches
+// to the Huffman codec; that is only used when DISPLAYLINK_ENCODER asks for
+// it.
+
+use crate::capabilities::DeviceCapabilities;
+use crate::encoder::EncoderKind;
+use std::fmt;
+use std::time::Duration;
+
+/// Default interval between frames at full quality (~30 fps)
+pub const BASE_FRAME_INTERVAL: Duration = Duration::from_millis(33);
+
+/// Reduced update rates tried after the color depth options are exhausted
+const REDUCED_FRAME_INTERVALS: [Duration; 2] =
+    [Duration::from_millis(50), Duration::from_millis(100)];
+
+/// Fraction of the frame budget above which the link counts as overloaded
+const OVERLOAD_LOAD: f64 = 0.9;
+/// Fraction of the frame budget below which the link counts as idle
+const IDLE_LOAD: f64 = 0.4;
+/// Consecutive overloaded frames before degrading
+const DEGRADE_AFTER: u32 = 5;
+/// Consecutive idle frames before restoring
+const RESTORE_AFTER: u32 = 60;
+/// Smoothing factor for the moving averages
+const EWMA_ALPHA: f64 = 0.2;
+
+/// One rung of the quality ladder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QualityLevel {
+    pub encoder: EncoderKind,
+    pub frame_interval: Duration,
+}
+
+/// Measurements for one sent frame
+#[derive(Debug, Clone, Copy)]
+pub struct FrameSample {
+    pub bytes: usize,
+    /// Bulk transfers the frame was split into
+    pub urbs: usize,
+    pub encode_time: Duration,
+    pub transfer_time: Duration,
+}
+
+/// Level change made by the controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QualityChange {
+    pub from: QualityLevel,
+    pub to: QualityLevel,
+    pub degraded: bool,
+}
+
+impl fmt::Display for QualityChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} quality: {} @ {}ms -> {} @ {}ms",
+            if self.degraded {
+                "Lowering"
+            } else {
+                "Restoring"
+            },
+            self.from.encoder.name(),
+            self.from.frame_interval.as_millis(),
+            self.to.encoder.name(),
+            self.to.frame_interval.as_millis()
+        )
+    }
+}
+
+/// Snapshot of the controller's measurements
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QualityStats {
+    pub frames: u64,
+    /// Smoothed bulk throughput in bytes per second
+    pub throughput_bps: f64,
+    /// Smoothed encode time per frame in milliseconds
+    pub encode_ms: f64,
+    /// Smoothed transfer time per frame in milliseconds
+    pub transfer_ms: f64,
+    /// Smoothed number of URBs per frame
+    pub urbs: f64,
+    /// Current ladder position (0 = best quality)
+    pub level: usize,
+    pub degrades: u64,
+    pub restores: u64,
+}
+
+impl fmt::Display for QualityStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frames={} throughput={:.1}MB/s encode={:.1}ms transfer={:.1}ms urbs={:.1} level={} degrades={} restores={}",
+            self.frames,
+            self.throughput_bps / 1_000_000.0,
+            self.encode_ms,
+            self.transfer_ms,
+            self.urbs,
+            self.level,
+            self.degrades,
+            self.restores
+        )
+    }
+}
+
+/// Adaptive quality controller
+pub struct QualityController {
+    levels: Vec<QualityLevel>,
+    level: usize,
+    enabled: bool,
+    overloaded_frames: u32,
+    idle_frames: u32,
+    stats: QualityStats,
+}
+
+impl QualityController {
+    /// Build the quality ladder starting from the device's selected codec
+    pub fn new(caps: &DeviceCapabilities, base: EncoderKind, enabled: bool) -> Self {
+        let mut encoders = vec![base];
+        // Lower color depth first
+        if base.bits_per_pixel() > 16 {
+            encoders.push(if caps.supports_rle {
+                EncoderKind::Rle
+            } else {
+                EncoderKind::Raw16
+            });
+        }
+
+        let mut levels: Vec<QualityLevel> = encoders
+            .iter()
+            .map(|&encoder| QualityLevel {
+                encoder,
+                frame_interval: BASE_FRAME_INTERVAL,
+            })
+            .collect();
+        let cheapest = *encoders.last().unwrap();
+        levels.extend(
+            REDUCED_FRAME_INTERVALS
+                .iter()
+                .map(|&frame_interval| QualityLevel {
+                    encoder: cheapest,
+                    frame_interval,
+                }),
+        );
+
+        QualityController {
+            levels,
+            level: 0,
+            enabled,
+            overloaded_frames: 0,
+            idle_frames: 0,
+            stats: QualityStats::default(),
+        }
+    }
+
+    /// Active quality level
+    pub fn current(&self) -> QualityLevel {
+        self.levels[self.level]
+    }
+
+    /// Minimum time between frames at the active level
+    pub fn frame_interval(&self) -> Duration {
+        self.current().frame_interval
+    }
+
+    pub fn stats(&self) -> QualityStats {
+        self.stats
+    }
+
+    /// Record a sent frame and adapt the level if needed
+    pub fn record(&mut self, sample: FrameSample) -> Option<QualityChange> {
+        let encode_ms = sample.encode_time.as_secs_f64() * 1000.0;
+        let transfer_ms = sample.transfer_time.as_secs_f64() * 1000.0;
+        let transfer_secs = sample.transfer_time.as_secs_f64();
+        let urbs = sample.urbs as f64;
+
+        let stats = &mut self.stats;
+        if stats.frames == 0 {
+            stats.encode_ms = encode_ms;
+            stats.transfer_ms = transfer_ms;
+            stats.urbs = urbs;
+        } else {
+            stats.encode_ms += EWMA_ALPHA * (encode_ms - stats.encode_ms);
+            stats.transfer_ms += EWMA_ALPHA * (transfer_ms - stats.transfer_ms);
+            stats.urbs += EWMA_ALPHA * (urbs - stats.urbs);
+        }
+        if transfer_secs > 0.0 {
+            let throughput = sample.bytes as f64 / transfer_secs;
+            stats.throughput_bps += EWMA_ALPHA * (throughput - stats.throughput_bps);
+        }
+        stats.frames += 1;
+
+        if !self.enabled {
+            return None;
+        }
+
+        let budget_ms = self.frame_interval().as_secs_f64() * 1000.0;
+        let load = (self.stats.encode_ms + self.stats.transfer_ms) / budget_ms;
+
+        if load > OVERLOAD_LOAD {
+            self.idle_frames = 0;
+            self.overloaded_frames += 1;
+            if self.overloaded_frames >= DEGRADE_AFTER && self.level + 1 < self.levels.len() {
+                return Some(self.change_level(self.level + 1));
+            }
+        } else if load < IDLE_LOAD {
+            self.overloaded_frames = 0;
+            self.idle_frames += 1;
+            if self.idle_frames >= RESTORE_AFTER && self.level > 0 {
+                return Some(self.change_level(self.level - 1));
+            }
+        } else {
+            self.overloaded_frames = 0;
+            self.idle_frames = 0;
+        }
+
+        None
+    }
+
+    fn change_level(&mut self, level: usize) -> QualityChange {
+        let change = QualityChange {
+            from: self.current(),
+            to: self.levels[level],
+            degraded: level > self.level,
+        };
+        if change.degraded {
+            self.stats.degrades += 1;
+        } else {
+            self.stats.restores += 1;
+        }
+        self.level = level;
+        self.stats.level = level;
+        self.overloaded_frames = 0;
+        self.idle_frames = 0;
+        change
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::PID_DL3XXX;
+    use crate::config::DriverConfig;
+    use crate::encoder::select_encoder;
+
+    // A chip with every codec
+    fn full_caps() -> DeviceCapabilities {
+        DeviceCapabilities {
+            supports_huffman: true,
+            supports_24bpp: true,
+            ..DeviceCapabilities::for_product(PID_DL3XXX)
+        }
+    }
+
+    fn sample(total_ms: u64) -> FrameSample {
+        FrameSample {
+            bytes: 1_000_000,
+            urbs: 16,
+            encode_time: Duration::from_millis(total_ms / 2),
+            transfer_time: Duration::from_millis(total_ms - total_ms / 2),
+        }
+    }
+
+    #[test]
+    fn test_ladder_lowers_depth_then_rate() {
+        let caps = full_caps();
+        let controller = QualityController::new(&caps, EncoderKind::Raw24, true);
+        let encoders: Vec<_> = controller.levels.iter().map(|l| l.encoder).collect();
+        assert_eq!(&encoders[..2], &[EncoderKind::Raw24, EncoderKind::Rle]);
+        assert!(encoders[2..].iter().all(|&e| e == EncoderKind::Rle));
+        assert_eq!(controller.levels[1].frame_interval, BASE_FRAME_INTERVAL);
+        assert!(controller.levels.last().unwrap().frame_interval > BASE_FRAME_INTERVAL);
+    }
+
+    #[test]
+    fn test_default_config_never_switches_to_huffman() {
+        let config = DriverConfig::default();
+        for caps in [
+            DeviceCapabilities::for_product(PID_DL3XXX),
+            DeviceCapabilities::for_product(0x0001),
+            full_caps(),
+        ] {
+            let base = select_encoder(&caps, config.encoder);
+            let controller = QualityController::new(&caps, base, config.adaptive_quality);
+            for level in &controller.levels {
+                assert_ne!(level.encoder, EncoderKind::Huffman, "{:?}", caps);
+                assert!(level.encoder.is_supported(&caps), "{:?}", caps);
+            }
+        }
+    }
+
+    #[test]
+    fn test_degrades_under_load_and_restores_when_idle() {
+        let caps = full_caps();
+        let mut controller = QualityController::new(&caps, EncoderKind::Rle, true);
+
+        let change = (0..DEGRADE_AFTER)
+            .filter_map(|_| controller.record(sample(60)))
+            .last()
+            .expect("should degrade");
+        assert!(change.degraded);
+        assert_eq!(change.to.encoder, EncoderKind::Rle);
+        assert!(change.to.frame_interval > BASE_FRAME_INTERVAL);
+        assert_eq!(controller.stats().degrades, 1);
+
+        // A few idle frames are not enough to climb back
+        for _ in 0..10 {
+            assert!(controller.record(sample(2)).is_none());
+        }
+        let change = (0..RESTORE_AFTER)
+            .filter_map(|_| controller.record(sample(2)))
+            .last()
+            .expect("should restore");
+        assert!(!change.degraded);
+        assert_eq!(controller.current().encoder, EncoderKind::Rle);
+    }
+
+    #[test]
+    fn test_disabled_controller_only_measures() {
+        let caps = full_caps();
+        let mut controller = QualityController::new(&caps, EncoderKind::Rle, false);
+        for _ in 0..20 {
+            assert!(controller.record(sample(100)).is_none());
+        }
+        assert_eq!(controller.stats().frames, 20);
+        assert!(controller.stats().throughput_bps > 0.0);
+        assert_eq!(controller.stats().urbs, 16.0);
+    }
+}
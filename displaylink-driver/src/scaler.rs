@@ -0,0 +1,292 @@
+// Scaling to the panel's native mode
+//
+// Projectors and odd-resolution panels often can't display the mode the
+// compositor picked. With scaling enabled, the device always runs the
+// panel's native mode and frames are resampled to it with bilinear
+// filtering, either stretched to fill the panel or letterboxed to keep the
+// aspect ratio. The native mode comes from the monitor's EDID unless it is
+// configured.
+
+use crate::encoder::Rect;
+use crate::pipeline::FrameLayout;
+
+/// How the image is fitted to the panel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScalingMode {
+    /// Keep the aspect ratio, with black bars where it doesn't fill
+    Letterbox,
+    /// Fill the whole panel
+    Stretch,
+}
+
+impl ScalingMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "letterbox" | "aspect" => Some(ScalingMode::Letterbox),
+            "stretch" | "full" => Some(ScalingMode::Stretch),
+            _ => None,
+        }
+    }
+}
+
+/// Mode the panel displays natively
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NativeMode {
+    pub width: usize,
+    pub height: usize,
+    pub refresh_rate: u32,
+}
+
+impl NativeMode {
+    /// Parse "WIDTHxHEIGHT" or "WIDTHxHEIGHT@RATE"
+    pub fn parse(value: &str) -> Option<Self> {
+        let (size, refresh_rate) = match value.trim().split_once('@') {
+            Some((size, rate)) => (size, rate.trim().parse().ok()?),
+            None => (value.trim(), 60),
+        };
+        let (width, height) = size.split_once(['x', 'X'])?;
+        let mode = NativeMode {
+            width: width.trim().parse().ok()?,
+            height: height.trim().parse().ok()?,
+            refresh_rate,
+        };
+        (mode.width > 0 && mode.height > 0 && mode.refresh_rate > 0).then_some(mode)
+    }
+
+    /// Preferred timing from an EDID: its first detailed timing descriptor
+    pub fn from_edid(edid: &[u8]) -> Option<Self> {
+        if edid.len() < 128 || edid[..8] != EDID_HEADER {
+            return None;
+        }
+        let dtd = &edid[54..72];
+        let pixel_clock = u16::from_le_bytes([dtd[0], dtd[1]]) as u64 * 10_000;
+        if pixel_clock == 0 {
+            return None;
+        }
+        let width = dtd[2] as usize | (dtd[4] as usize >> 4) << 8;
+        let h_blank = dtd[3] as usize | (dtd[4] as usize & 0xF) << 8;
+        let height = dtd[5] as usize | (dtd[7] as usize >> 4) << 8;
+        let v_blank = dtd[6] as usize | (dtd[7] as usize & 0xF) << 8;
+        let total = ((width + h_blank) * (height + v_blank)) as u64;
+        if width == 0 || height == 0 {
+            return None;
+        }
+        Some(NativeMode {
+            width,
+            height,
+            refresh_rate: ((pixel_clock + total / 2) / total) as u32,
+        })
+    }
+}
+
+const EDID_HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+
+/// Resamples frames onto the native mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scaler {
+    mode: ScalingMode,
+    width: usize,
+    height: usize,
+}
+
+impl Scaler {
+    pub fn new(mode: ScalingMode, native: NativeMode) -> Self {
+        Scaler {
+            mode,
+            width: native.width,
+            height: native.height,
+        }
+    }
+
+    /// Whether a `width` × `height` image needs resampling at all
+    pub fn applies_to(&self, width: usize, height: usize) -> bool {
+        (width, height) != (self.width, self.height)
+    }
+
+    /// Layout of a scaled frame; rows are packed
+    pub fn layout(&self, source: &FrameLayout) -> FrameLayout {
+        FrameLayout {
+            width: self.width,
+            height: self.height,
+            stride: self.width * source.format.bytes_per_pixel(),
+            format: source.format,
+        }
+    }
+
+    /// Where a `width` × `height` image goes on the panel
+    pub fn content(&self, width: usize, height: usize) -> Rect {
+        if self.mode == ScalingMode::Stretch || width == 0 || height == 0 {
+            return Rect::new(0, 0, self.width, self.height);
+        }
+        let (content_width, content_height) = if width * self.height <= height * self.width {
+            ((width * self.height / height).max(1), self.height)
+        } else {
+            (self.width, (height * self.width / width).max(1))
+        };
+        Rect::new(
+            (self.width - content_width) / 2,
+            (self.height - content_height) / 2,
+            content_width,
+            content_height,
+        )
+    }
+
+    /// Panel area affected by a rectangle of a `width` × `height` image
+    ///
+    /// Includes the neighbours the filter blends with the rectangle.
+    pub fn map_rect(&self, rect: &Rect, width: usize, height: usize) -> Rect {
+        let rect = rect.clamp_to(width, height);
+        if rect.is_empty() {
+            return Rect::default();
+        }
+        let content = self.content(width, height);
+        let span = |start: usize, end: usize, len: usize, out: usize| {
+            let lo = start.saturating_sub(1) * out / len;
+            let hi = ((end + 1).min(len) * out).div_ceil(len).min(out);
+            (lo, hi)
+        };
+        let (x0, x1) = span(rect.x, rect.x + rect.width, width, content.width);
+        let (y0, y1) = span(rect.y, rect.y + rect.height, height, content.height);
+        Rect::new(content.x + x0, content.y + y0, x1 - x0, y1 - y0)
+    }
+
+    /// Fill `region` of the scaled frame `dst` from `src`
+    ///
+    /// Panel pixels outside the image are painted black.
+    pub fn scale_region(
+        &self,
+        dst: &mut [u8],
+        dst_layout: &FrameLayout,
+        src: &[u8],
+        src_layout: &FrameLayout,
+        region: &Rect,
+    ) {
+        let format = src_layout.format;
+        let bpp = format.bytes_per_pixel();
+        let content = self.content(src_layout.width, src_layout.height);
+        let pixel = |x: usize, y: usize| format.read_rgb(&src[y * src_layout.stride + x * bpp..]);
+
+        for v in region.y..region.y + region.height {
+            let inside_rows = v >= content.y && v < content.y + content.height;
+            let (y0, y1, fy) = sample(
+                v.saturating_sub(content.y),
+                content.height,
+                src_layout.height,
+            );
+            for u in region.x..region.x + region.width {
+                let offset = v * dst_layout.stride + u * bpp;
+                let px = &mut dst[offset..offset + bpp];
+                if !inside_rows || u < content.x || u >= content.x + content.width {
+                    format.write_rgb(px, [0, 0, 0]);
+                    continue;
+                }
+                let (x0, x1, fx) = sample(u - content.x, content.width, src_layout.width);
+                let top = lerp(pixel(x0, y0), pixel(x1, y0), fx);
+                let bottom = lerp(pixel(x0, y1), pixel(x1, y1), fx);
+                format.write_rgb(px, lerp(top, bottom, fy));
+            }
+        }
+    }
+}
+
+// Source pixels around the centre of output pixel `i` of `out`, resampling
+// `len` pixels, and the weight of the second one in 1/256ths
+fn sample(i: usize, out: usize, len: usize) -> (usize, usize, u32) {
+    let centre = ((2 * i + 1) * len * 256 / (2 * out)).saturating_sub(128);
+    let first = (centre >> 8).min(len - 1);
+    let second = (first + 1).min(len - 1);
+    (first, second, (centre & 0xFF) as u32)
+}
+
+fn lerp(a: [u8; 3], b: [u8; 3], weight: u32) -> [u8; 3] {
+    [0, 1, 2].map(|i| ((a[i] as u32 * (256 - weight) + b[i] as u32 * weight + 128) >> 8) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel_format::PixelFormat;
+
+    fn layout(width: usize, height: usize) -> FrameLayout {
+        FrameLayout {
+            width,
+            height,
+            stride: width * 4,
+            format: PixelFormat::Xrgb8888,
+        }
+    }
+
+    fn native(width: usize, height: usize) -> NativeMode {
+        NativeMode {
+            width,
+            height,
+            refresh_rate: 60,
+        }
+    }
+
+    #[test]
+    fn test_native_mode_sources() {
+        assert_eq!(
+            NativeMode::parse("1280x800@75"),
+            Some(NativeMode {
+                width: 1280,
+                height: 800,
+                refresh_rate: 75
+            })
+        );
+        assert_eq!(NativeMode::parse("1024x768"), Some(native(1024, 768)));
+        assert_eq!(NativeMode::parse("1024"), None);
+
+        let mut edid = vec![0u8; 128];
+        edid[..8].copy_from_slice(&EDID_HEADER);
+        // 1920x1080@60
+        edid[54..72].copy_from_slice(&[
+            0x02, 0x3A, 0x80, 0x18, 0x71, 0x38, 0x2D, 0x40, 0x58, 0x2C, 0x45, 0x00, 0x09, 0x25,
+            0x21, 0x00, 0x00, 0x1E,
+        ]);
+        assert_eq!(NativeMode::from_edid(&edid), Some(native(1920, 1080)));
+        assert_eq!(NativeMode::from_edid(&edid[..64]), None);
+    }
+
+    #[test]
+    fn test_letterbox_and_stretch_geometry() {
+        let letterbox = Scaler::new(ScalingMode::Letterbox, native(1920, 1080));
+        // 4:3 is pillarboxed, 21:9 letterboxed
+        assert_eq!(letterbox.content(1024, 768), Rect::new(240, 0, 1440, 1080));
+        assert_eq!(letterbox.content(2560, 1080), Rect::new(0, 135, 1920, 810));
+        assert!(!letterbox.applies_to(1920, 1080));
+
+        let stretch = Scaler::new(ScalingMode::Stretch, native(1920, 1080));
+        assert_eq!(stretch.content(1024, 768), Rect::new(0, 0, 1920, 1080));
+        // Doubling: a pixel covers two, plus a filter neighbour each side
+        let stretch = Scaler::new(ScalingMode::Stretch, native(8, 8));
+        assert_eq!(
+            stretch.map_rect(&Rect::new(1, 1, 1, 1), 4, 4),
+            Rect::new(0, 0, 6, 6)
+        );
+    }
+
+    #[test]
+    fn test_bilinear_upscale_and_black_bars() {
+        // Black and white columns, doubled and pillarboxed into 6x2
+        let src_layout = layout(2, 1);
+        let src: Vec<u8> = [[0u8, 0, 0, 0], [0xFF, 0xFF, 0xFF, 0]].concat();
+        let scaler = Scaler::new(ScalingMode::Letterbox, native(6, 2));
+        assert_eq!(scaler.content(2, 1), Rect::new(1, 0, 4, 2));
+
+        let dst_layout = scaler.layout(&src_layout);
+        let mut dst = vec![0x55; dst_layout.stride * dst_layout.height];
+        scaler.scale_region(
+            &mut dst,
+            &dst_layout,
+            &src,
+            &src_layout,
+            &Rect::new(0, 0, 6, 2),
+        );
+
+        let row: Vec<u8> = dst[..24].chunks(4).map(|px| px[0]).collect();
+        // Bars, edge pixels, then blends a quarter and three quarters across
+        assert_eq!(row, vec![0, 0, 64, 191, 255, 0]);
+        assert_eq!(&dst[..24], &dst[24..]);
+    }
+}
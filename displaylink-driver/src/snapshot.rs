@@ -0,0 +1,430 @@
+// Framebuffer snapshots
+//
+// To tell "what EVDI gave us" apart from "what we told the device", a
+// snapshot dumps the source frame buffer and the decoded result of the last
+// command stream the device accepted as PNG files. The stream is decoded with
+// the codec it was encoded with; pixels it didn't touch are left transparent.
+// Snapshots are taken on request from the control socket, or for every dock
+// on SIGUSR1.
+
+use crate::displaylink_protocol::{
+    DL_CMD_CURSOR_UPLOAD, DL_REG_DAMAGE_CODEC, DL_REG_DAMAGE_HEIGHT, DL_REG_DAMAGE_X,
+};
+use crate::encoder::{huffman_codes, EncoderKind, Rect, RLE_RAW_MARKER};
+use crate::pipeline::{FrameLayout, SentFrame};
+use crate::pixel_format::PixelFormat;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// RGBA image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 4]>,
+}
+
+impl Image {
+    /// Fully transparent image
+    pub fn new(width: usize, height: usize) -> Self {
+        Image {
+            width,
+            height,
+            pixels: vec![[0; 4]; width * height],
+        }
+    }
+
+    /// Opaque copy of a frame buffer
+    pub fn from_frame(data: &[u8], layout: &FrameLayout) -> Self {
+        let bpp = layout.format.bytes_per_pixel();
+        let mut image = Image::new(layout.width, layout.height);
+        for y in 0..layout.height {
+            for x in 0..layout.width {
+                let offset = y * layout.stride + x * bpp;
+                image.put(x, y, layout.format.read_rgb(&data[offset..offset + bpp]));
+            }
+        }
+        image
+    }
+
+    fn put(&mut self, x: usize, y: usize, [r, g, b]: [u8; 3]) {
+        self.pixels[y * self.width + x] = [r, g, b, 0xFF];
+    }
+}
+
+/// Replay a command stream onto a transparent `width` × `height` image
+///
+/// Each damage rectangle's pixel data is decoded with the codec its command
+/// selects. Register writes other than the damage rectangle are skipped.
+pub fn decode_stream(stream: &[u8], width: usize, height: usize) -> Result<Image, String> {
+    let mut image = Image::new(width, height);
+    let mut codec = None;
+    let mut damage = [0u16; 4];
+    let mut pos = 0;
+    while pos < stream.len() {
+        match stream[pos..] {
+            [0xAF, 0x20, a0, a1, v0, v1, ..] => {
+                let address = u16::from_le_bytes([a0, a1]);
+                pos += 6;
+                if address == DL_REG_DAMAGE_CODEC {
+                    let value = u16::from_le_bytes([v0, v1]);
+                    codec = Some(
+                        EncoderKind::from_codec(value)
+                            .ok_or_else(|| format!("unknown codec {} at byte {}", value, pos))?,
+                    );
+                }
+                if (DL_REG_DAMAGE_X..=DL_REG_DAMAGE_HEIGHT).contains(&address) {
+                    damage[(address - DL_REG_DAMAGE_X) as usize / 2] = u16::from_le_bytes([v0, v1]);
+                }
+                // Pixel data follows the last damage register
+                if address == DL_REG_DAMAGE_HEIGHT {
+                    let [x, y, w, h] = damage.map(|v| v as usize);
+                    let region = Rect::new(x, y, w, h);
+                    if region.clamp_to(width, height) != region {
+                        return Err(format!("damage {:?} outside {}x{}", region, width, height));
+                    }
+                    let encoder =
+                        codec.ok_or_else(|| format!("no codec selected at byte {}", pos))?;
+                    pos += decode_region(encoder, &stream[pos..], &region, &mut image)
+                        .map_err(|e| format!("{} at byte {}", e, pos))?;
+                }
+            }
+            [0xAF, DL_CMD_CURSOR_UPLOAD, c0, c1, ..] => {
+                pos += 4 + u16::from_le_bytes([c0, c1]) as usize * 4;
+            }
+            _ => return Err(format!("unknown command at byte {}", pos)),
+        }
+    }
+    Ok(image)
+}
+
+// Decode the pixel data of one damage rectangle, returning the bytes used
+fn decode_region(
+    encoder: EncoderKind,
+    data: &[u8],
+    region: &Rect,
+    image: &mut Image,
+) -> Result<usize, String> {
+    let count = region.width * region.height;
+    let mut pixels = Vec::with_capacity(count);
+    let used = match encoder {
+        EncoderKind::Raw24 => {
+            let payload = data.get(..count * 3).ok_or("truncated raw24 data")?;
+            for (i, rgb) in payload.chunks_exact(3).enumerate() {
+                let (x, y) = (region.x + i % region.width, region.y + i / region.width);
+                image.put(x, y, [rgb[0], rgb[1], rgb[2]]);
+            }
+            return Ok(payload.len());
+        }
+        EncoderKind::Raw16 => {
+            let payload = data.get(..count * 2).ok_or("truncated raw16 data")?;
+            pixels.extend(
+                payload
+                    .chunks_exact(2)
+                    .map(|px| u16::from_le_bytes([px[0], px[1]])),
+            );
+            payload.len()
+        }
+        EncoderKind::Rle => decode_rle(data, count, &mut pixels)?,
+        EncoderKind::Huffman => decode_huffman(data, count, &mut pixels)?,
+    };
+    for (i, pixel) in pixels.into_iter().enumerate() {
+        let (x, y) = (region.x + i % region.width, region.y + i / region.width);
+        image.put(x, y, PixelFormat::Rgb565.read_rgb(&pixel.to_le_bytes()));
+    }
+    Ok(used)
+}
+
+fn decode_rle(data: &[u8], count: usize, out: &mut Vec<u16>) -> Result<usize, String> {
+    let mut pos = 0;
+    let pixel_at = |pos: usize| {
+        data.get(pos..pos + 2)
+            .map(|px| u16::from_le_bytes([px[0], px[1]]))
+            .ok_or("truncated RLE data")
+    };
+    while out.len() < count {
+        // `[count] [pixel]` runs and `[0xAF] [count - 1] [pixels...]` spans
+        let (repeat, span, header) = match *data.get(pos).ok_or("truncated RLE data")? {
+            RLE_RAW_MARKER => {
+                let count = *data.get(pos + 1).ok_or("truncated RLE data")? as usize + 1;
+                (1, count, 2)
+            }
+            0 => return Err("empty RLE run".to_string()),
+            run => (run as usize, 1, 1),
+        };
+        pos += header;
+        for _ in 0..span {
+            let pixel = pixel_at(pos)?;
+            pos += 2;
+            out.extend(std::iter::repeat_n(pixel, repeat));
+        }
+    }
+    if out.len() > count {
+        return Err("RLE data runs past the damage rectangle".to_string());
+    }
+    Ok(pos)
+}
+
+fn decode_huffman(data: &[u8], count: usize, out: &mut Vec<u16>) -> Result<usize, String> {
+    let codes = huffman_codes();
+    let mut bit = 0;
+    let mut read = |bits: usize| -> Result<u32, String> {
+        let mut value = 0u32;
+        for _ in 0..bits {
+            let byte = data.get(bit / 8).ok_or("truncated Huffman data")?;
+            value = value << 1 | (byte >> (7 - bit % 8) & 1) as u32;
+            bit += 1;
+        }
+        Ok(value)
+    };
+
+    let mut prev = 0u16;
+    for _ in 0..count {
+        // Canonical codes are prefix-free; extend until one matches
+        let (mut code, mut len) = (0u32, 0u8);
+        let class = loop {
+            code = code << 1 | read(1)?;
+            len += 1;
+            if let Some(class) = codes.iter().position(|&c| c == (code as u16, len)) {
+                break class;
+            }
+            if len > 16 {
+                return Err("invalid Huffman code".to_string());
+            }
+        };
+        let bits = read(class)? as i32;
+        let delta = if class == 0 || bits >> (class - 1) == 1 {
+            bits
+        } else {
+            bits - (1 << class) + 1
+        };
+        prev = prev.wrapping_add(delta as u16);
+        out.push(prev);
+    }
+    // Regions are padded to a byte boundary
+    Ok(bit.div_ceil(8))
+}
+
+/// Encode an image as an RGBA PNG
+///
+/// The zlib stream uses stored deflate blocks, which keeps the writer small
+/// at the cost of file size.
+pub fn encode_png(image: &Image) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(image.height * (1 + image.width * 4));
+    for row in image
+        .pixels
+        .chunks_exact(image.width.max(1))
+        .take(image.height)
+    {
+        raw.push(0); // No filter
+        raw.extend(row.iter().flatten());
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(image.width as u32).to_be_bytes());
+    header.extend_from_slice(&(image.height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 6, 0, 0, 0]); // 8-bit RGBA
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (kind, data) in [(b"IHDR", &header), (b"IDAT", &zlib), (b"IEND", &Vec::new())] {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = png.len();
+        png.extend_from_slice(kind);
+        png.extend_from_slice(data);
+        let crc = crc32(&png[start..]);
+        png.extend_from_slice(&crc.to_be_bytes());
+    }
+    png
+}
+
+pub fn write_png(path: &Path, image: &Image) -> Result<(), String> {
+    fs::write(path, encode_png(image))
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        *entry = (0..8).fold(n as u32, |c, _| {
+            if c & 1 != 0 {
+                0xEDB8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            }
+        });
+    }
+    !data.iter().fold(!0u32, |crc, &byte| {
+        table[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+/// Write the source frame and the decoded last stream of a device to `dir`
+///
+/// Returns the files written. The source is saved even if the stream can't
+/// be decoded.
+pub fn save(
+    dir: &Path,
+    device_id: &str,
+    source: &[u8],
+    layout: &FrameLayout,
+    sent: Option<&SentFrame>,
+) -> Result<Vec<PathBuf>, String> {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_millis())
+        .unwrap_or_default();
+    let stem = format!("displaylink-{}-{}", device_id.replace(':', "_"), millis);
+
+    let source_path = dir.join(format!("{}-source.png", stem));
+    write_png(&source_path, &Image::from_frame(source, layout))?;
+    let mut saved = vec![source_path];
+
+    if let Some(sent) = sent {
+        let image = decode_stream(&sent.stream, sent.width, sent.height).map_err(|e| {
+            format!(
+                "saved {}, but the last stream didn't decode: {}",
+                saved[0].display(),
+                e
+            )
+        })?;
+        let sent_path = dir.join(format!("{}-sent.png", stem));
+        write_png(&sent_path, &image)?;
+        saved.push(sent_path);
+    }
+    Ok(saved)
+}
+
+static SIGNAL_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn on_sigusr1(_signal: libc::c_int) {
+    SIGNAL_REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count SIGUSR1s so driver threads can take snapshots
+pub fn install_signal_handler() {
+    let handler = on_sigusr1 as extern "C" fn(libc::c_int);
+    unsafe {
+        libc::signal(libc::SIGUSR1, handler as libc::sighandler_t);
+    }
+}
+
+/// Number of SIGUSR1s received; a driver snapshots when it changes
+pub fn signal_requests() -> u64 {
+    SIGNAL_REQUESTS.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::displaylink_protocol::{CommandBuilder, DL_CODEC_RAW16};
+    use crate::dither::DitherMode;
+    use crate::encoder::EncoderInput;
+
+    #[test]
+    fn test_png_structure() {
+        let mut image = Image::new(2, 1);
+        image.put(1, 0, [0x10, 0x20, 0x30]);
+        let png = encode_png(&image);
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..29], &[0, 0, 0, 2, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+        // IEND's CRC is fixed
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xAE\x42\x60\x82");
+
+        // One stored block holding the filtered row
+        let idat = &png[41..png.len() - 16];
+        let row = [0, 0, 0, 0, 0, 0x10, 0x20, 0x30, 0xFF];
+        assert_eq!(&idat[..7], &[0x78, 0x01, 1, 9, 0, 0xF6, 0xFF]);
+        assert_eq!(&idat[7..16], &row);
+        assert_eq!(&idat[16..], &adler32(&row).to_be_bytes());
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_streams_decode_for_every_codec() {
+        // 5x3 gradient, updated at (1, 2) on an 8x6 screen
+        let (width, height) = (5, 3);
+        let frame: Vec<u8> = (0..width * height)
+            .flat_map(|i| [(i * 17) as u8, (i * 5) as u8, 0xFF - (i * 9) as u8, 0])
+            .collect();
+        let region = Rect::new(1, 2, width, height);
+        let (screen_width, screen_height) = (8, 6);
+
+        for kind in [
+            EncoderKind::Raw16,
+            EncoderKind::Rle,
+            EncoderKind::Huffman,
+            EncoderKind::Raw24,
+        ] {
+            let input = EncoderInput {
+                data: &frame,
+                stride: width * 4,
+                format: PixelFormat::Xrgb8888,
+                region: Rect::new(0, 0, width, height),
+                dither: DitherMode::None,
+                color: None,
+            };
+            let mut payload = Vec::new();
+            kind.create().encode(&input, &mut payload);
+            // Re-address the region, with the framing the pipeline adds
+            let mut cmd = CommandBuilder::new();
+            let mut stream = cmd.set_color_depth(kind.bits_per_pixel()).to_vec();
+            let header = cmd.damage_rect(kind.codec(), 1, 2, width as u16, height as u16);
+            stream.extend_from_slice(header);
+            stream.extend_from_slice(&payload[header.len()..]);
+            stream.extend_from_slice(cmd.sync());
+
+            let image = decode_stream(&stream, screen_width, screen_height).unwrap();
+            for y in 0..screen_height {
+                for x in 0..screen_width {
+                    let pixel = image.pixels[y * screen_width + x];
+                    let inside = Rect::new(x, y, 1, 1).intersect(&region) != Rect::default();
+                    if !inside {
+                        assert_eq!(pixel[3], 0, "{:?} ({}, {})", kind, x, y);
+                        continue;
+                    }
+                    let px = &frame[((y - 2) * width + x - 1) * 4..];
+                    let expected = match kind {
+                        EncoderKind::Raw24 => PixelFormat::Xrgb8888.read_rgb(px),
+                        _ => PixelFormat::Rgb565
+                            .read_rgb(&PixelFormat::Xrgb8888.read_rgb565(px).to_le_bytes()),
+                    };
+                    assert_eq!(pixel[..3], expected, "{:?} ({}, {})", kind, x, y);
+                    assert_eq!(pixel[3], 0xFF);
+                }
+            }
+        }
+
+        assert!(decode_stream(&[0x12, 0x34], 8, 6).is_err());
+        let outside = CommandBuilder::new()
+            .damage_rect(DL_CODEC_RAW16, 6, 0, 4, 1)
+            .to_vec();
+        assert!(decode_stream(&outside, 8, 6).is_err());
+        let unknown_codec = CommandBuilder::new().damage_rect(0x7F, 0, 0, 1, 1).to_vec();
+        assert!(decode_stream(&unknown_codec, 8, 6).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+// Output rotation and reflection
+//
+// Some dock monitors are mounted in portrait, and rotation through EVDI
+// depends on the compositor. The driver can instead turn the image itself:
+// the compositor renders in the rotated orientation, and frames are
+// transformed into the monitor's native orientation while they are copied
+// for encoding. Damage rectangles map the same way, so only the transformed
+// dirty area is sent.
+//
+// A transform flips first and then rotates clockwise.
+
+use crate::encoder::Rect;
+use crate::pipeline::FrameLayout;
+
+/// Clockwise rotation of the image on the monitor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rotation {
+    #[default]
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+/// Rotation plus optional reflection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Transform {
+    pub rotation: Rotation,
+    /// Mirror left to right
+    pub flip_horizontal: bool,
+    /// Mirror top to bottom
+    pub flip_vertical: bool,
+}
+
+impl Transform {
+    /// Parse a comma-separated list such as "90", "flip-h" or "270,flip-v"
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut transform = Transform::default();
+        for part in value
+            .split(',')
+            .map(|part| part.trim().to_ascii_lowercase())
+        {
+            match part.as_str() {
+                "0" | "normal" => transform.rotation = Rotation::Rotate0,
+                "90" | "right" => transform.rotation = Rotation::Rotate90,
+                "180" | "inverted" => transform.rotation = Rotation::Rotate180,
+                "270" | "left" => transform.rotation = Rotation::Rotate270,
+                "flip-h" | "hflip" => transform.flip_horizontal = true,
+                "flip-v" | "vflip" => transform.flip_vertical = true,
+                _ => return None,
+            }
+        }
+        Some(transform)
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Transform::default()
+    }
+
+    /// Whether width and height trade places
+    pub fn swaps_axes(&self) -> bool {
+        matches!(self.rotation, Rotation::Rotate90 | Rotation::Rotate270)
+    }
+
+    /// Size on the monitor of a `width` × `height` image
+    pub fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        if self.swaps_axes() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    /// Where pixel (`x`, `y`) of a `width` × `height` image lands
+    pub fn map_point(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        let x = if self.flip_horizontal {
+            width - 1 - x
+        } else {
+            x
+        };
+        let y = if self.flip_vertical {
+            height - 1 - y
+        } else {
+            y
+        };
+        match self.rotation {
+            Rotation::Rotate0 => (x, y),
+            Rotation::Rotate90 => (height - 1 - y, x),
+            Rotation::Rotate180 => (width - 1 - x, height - 1 - y),
+            Rotation::Rotate270 => (y, width - 1 - x),
+        }
+    }
+
+    /// Which pixel of a `width` × `height` image ends up at (`x`, `y`) on
+    /// the monitor
+    pub fn source_point(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        let (x, y) = match self.rotation {
+            Rotation::Rotate0 => (x, y),
+            Rotation::Rotate90 => (y, height - 1 - x),
+            Rotation::Rotate180 => (width - 1 - x, height - 1 - y),
+            Rotation::Rotate270 => (width - 1 - y, x),
+        };
+        let x = if self.flip_horizontal {
+            width - 1 - x
+        } else {
+            x
+        };
+        let y = if self.flip_vertical {
+            height - 1 - y
+        } else {
+            y
+        };
+        (x, y)
+    }
+
+    /// Where a rectangle of a `width` × `height` image lands
+    pub fn map_rect(&self, rect: &Rect, width: usize, height: usize) -> Rect {
+        let rect = rect.clamp_to(width, height);
+        if rect.is_empty() {
+            return Rect::default();
+        }
+        let (x1, y1) = self.map_point(rect.x, rect.y, width, height);
+        let (x2, y2) = self.map_point(
+            rect.x + rect.width - 1,
+            rect.y + rect.height - 1,
+            width,
+            height,
+        );
+        Rect::new(
+            x1.min(x2),
+            y1.min(y2),
+            x1.abs_diff(x2) + 1,
+            y1.abs_diff(y2) + 1,
+        )
+    }
+
+    /// Layout of a transformed frame; rows are packed
+    pub fn layout(&self, source: &FrameLayout) -> FrameLayout {
+        if self.is_identity() {
+            return *source;
+        }
+        let (width, height) = self.output_size(source.width, source.height);
+        FrameLayout {
+            width,
+            height,
+            stride: width * source.format.bytes_per_pixel(),
+            format: source.format,
+        }
+    }
+
+    /// Copy `region` of `src` into `dst`, transformed
+    ///
+    /// `region` is in monitor coordinates, as returned by `map_rect`.
+    pub fn copy_region(
+        &self,
+        dst: &mut [u8],
+        dst_layout: &FrameLayout,
+        src: &[u8],
+        src_layout: &FrameLayout,
+        region: &Rect,
+    ) {
+        let bpp = src_layout.format.bytes_per_pixel();
+        for y in region.y..region.y + region.height {
+            for x in region.x..region.x + region.width {
+                let (sx, sy) = self.source_point(x, y, src_layout.width, src_layout.height);
+                let from = sy * src_layout.stride + sx * bpp;
+                let to = y * dst_layout.stride + x * bpp;
+                dst[to..to + bpp].copy_from_slice(&src[from..from + bpp]);
+            }
+        }
+    }
+}
+
+const EDID_BLOCK_SIZE: usize = 128;
+
+/// Turn an EDID's modes to portrait for a rotated monitor
+///
+/// Detailed timings get their horizontal and vertical parameters swapped.
+/// Established and standard timings and the CEA extension only describe
+/// landscape modes, so they are dropped.
+pub fn transpose_edid(edid: &[u8]) -> Vec<u8> {
+    if edid.len() < EDID_BLOCK_SIZE {
+        return edid.to_vec();
+    }
+    let mut out = edid[..EDID_BLOCK_SIZE].to_vec();
+    out.swap(21, 22); // Screen size in cm
+    out[35..38].fill(0); // Established timings
+    out[38..54].fill(0x01); // Standard timings: unused
+    for descriptor in out[54..126].chunks_mut(18) {
+        // A zero pixel clock marks a display descriptor
+        if descriptor[0] != 0 || descriptor[1] != 0 {
+            transpose_detailed_timing(descriptor);
+        }
+    }
+    out[126] = 0; // Extension count
+    let sum = out[..127].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    out[127] = sum.wrapping_neg();
+    out
+}
+
+fn transpose_detailed_timing(dtd: &mut [u8]) {
+    // Active and blanking: low bytes and shared high nibbles
+    dtd.swap(2, 5);
+    dtd.swap(3, 6);
+    dtd.swap(4, 7);
+
+    // Sync offset and width are 10 bits horizontally but 6 bits vertically
+    let h_offset = dtd[8] as u16 | ((dtd[11] as u16 >> 6) & 0x3) << 8;
+    let h_width = dtd[9] as u16 | ((dtd[11] as u16 >> 4) & 0x3) << 8;
+    let v_offset = (dtd[10] >> 4) as u16 | ((dtd[11] as u16 >> 2) & 0x3) << 4;
+    let v_width = (dtd[10] & 0xF) as u16 | (dtd[11] as u16 & 0x3) << 4;
+    let (h_offset, h_width, v_offset, v_width) =
+        (v_offset, v_width, h_offset.min(63), h_width.min(63));
+    dtd[8] = h_offset as u8;
+    dtd[9] = h_width as u8;
+    dtd[10] = ((v_offset & 0xF) << 4 | (v_width & 0xF)) as u8;
+    dtd[11] =
+        ((h_offset >> 8) << 6 | (h_width >> 8) << 4 | (v_offset >> 4) << 2 | (v_width >> 4)) as u8;
+
+    // Image size in mm, then borders
+    dtd.swap(12, 13);
+    dtd[14] = dtd[14].rotate_left(4);
+    dtd.swap(15, 16);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel_format::PixelFormat;
+
+    const ALL: [Rotation; 4] = [
+        Rotation::Rotate0,
+        Rotation::Rotate90,
+        Rotation::Rotate180,
+        Rotation::Rotate270,
+    ];
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Transform::parse("0"), Some(Transform::default()));
+        assert_eq!(
+            Transform::parse("270, flip-v"),
+            Some(Transform {
+                rotation: Rotation::Rotate270,
+                flip_horizontal: false,
+                flip_vertical: true,
+            })
+        );
+        assert_eq!(Transform::parse("45"), None);
+    }
+
+    #[test]
+    fn test_points_and_rects_round_trip() {
+        let (width, height) = (5, 3);
+        for rotation in ALL {
+            for (flip_horizontal, flip_vertical) in [(false, false), (true, false), (true, true)] {
+                let transform = Transform {
+                    rotation,
+                    flip_horizontal,
+                    flip_vertical,
+                };
+                let (out_w, out_h) = transform.output_size(width, height);
+                for y in 0..height {
+                    for x in 0..width {
+                        let (u, v) = transform.map_point(x, y, width, height);
+                        assert!(u < out_w && v < out_h);
+                        assert_eq!(transform.source_point(u, v, width, height), (x, y));
+                    }
+                }
+
+                // A rect maps onto exactly the points inside it
+                let rect = Rect::new(1, 0, 3, 2);
+                let mapped = transform.map_rect(&rect, width, height);
+                assert_eq!(mapped.width * mapped.height, 6);
+                let (u, v) = transform.map_point(3, 1, width, height);
+                assert!(u >= mapped.x && u < mapped.x + mapped.width);
+                assert!(v >= mapped.y && v < mapped.y + mapped.height);
+            }
+        }
+    }
+
+    #[test]
+    fn test_transpose_edid_timings() {
+        let mut edid = vec![0u8; 256];
+        edid[..8].copy_from_slice(&[0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]);
+        edid[126] = 1;
+        // 1920x1080@60
+        edid[54..72].copy_from_slice(&[
+            0x02, 0x3A, 0x80, 0x18, 0x71, 0x38, 0x2D, 0x40, 0x58, 0x2C, 0x45, 0x00, 0x09, 0x25,
+            0x21, 0x00, 0x00, 0x1E,
+        ]);
+
+        let portrait = transpose_edid(&edid);
+        assert_eq!(portrait.len(), 128);
+        assert_eq!(portrait[126], 0);
+        assert_eq!(portrait.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)), 0);
+
+        let dtd = &portrait[54..72];
+        let h_active = dtd[2] as u16 | (dtd[4] as u16 >> 4) << 8;
+        let v_active = dtd[5] as u16 | (dtd[7] as u16 >> 4) << 8;
+        assert_eq!((h_active, v_active), (1080, 1920));
+        // Totals trade places, so the refresh rate is unchanged
+        let h_blank = dtd[3] as u16 | (dtd[4] as u16 & 0xF) << 8;
+        let v_blank = dtd[6] as u16 | (dtd[7] as u16 & 0xF) << 8;
+        assert_eq!((h_active + h_blank, v_active + v_blank), (1125, 2200));
+        // 521 x 293 mm becomes 293 x 521 mm
+        assert_eq!((dtd[12], dtd[13], dtd[14]), (0x25, 0x09, 0x12));
+    }
+
+    #[test]
+    fn test_copy_rotates_90_clockwise() {
+        let source = FrameLayout {
+            width: 3,
+            height: 2,
+            stride: 3 * 4,
+            format: PixelFormat::Xrgb8888,
+        };
+        // Pixel value = its index
+        let src: Vec<u8> = (0..6u8).flat_map(|i| [i, 0, 0, 0]).collect();
+        let transform = Transform::parse("90").unwrap();
+        let layout = transform.layout(&source);
+        assert_eq!((layout.width, layout.height, layout.stride), (2, 3, 8));
+
+        let mut dst = vec![0xFF; layout.stride * layout.height];
+        let full = transform.map_rect(&Rect::new(0, 0, 3, 2), 3, 2);
+        transform.copy_region(&mut dst, &layout, &src, &source, &full);
+        let pixels: Vec<u8> = dst.chunks(4).map(|px| px[0]).collect();
+        // 0 1 2      3 0
+        // 3 4 5  ->  4 1
+        //            5 2
+        assert_eq!(pixels, vec![3, 0, 4, 1, 5, 2]);
+    }
+}
@@ -0,0 +1,536 @@
+// Asynchronous bulk transfer engine
+//
+// Synchronous write_bulk calls leave the endpoint idle between chunks while
+// the next URB is set up. The engine instead keeps up to `depth` libusb
+// transfers in flight per frame and refills them as they complete. URBs
+// point straight into the caller's stream (the pipeline recycles those
+// buffers), and a frame is only reported once every URB has been reaped, so
+// nothing outlives the borrowed data.
+//
+// The device handle is only locked while a URB is submitted, so DDC/CI
+// control transfers from the event thread go through between URBs. Other
+// bulk OUT writers share `bulk_out` with the engine, which keeps their
+// commands from landing in the middle of a frame.
+//
+// libusb itself sits behind `UrbQueue`, so the queueing and cancellation
+// logic can be exercised without a device.
+
+use crate::pipeline::FrameSink;
+use rusb::ffi::{self, constants::*};
+use rusb::{DeviceHandle, UsbContext};
+use std::ffi::{c_int, c_void, CStr};
+use std::fmt;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Default bytes per URB
+pub const DEFAULT_URB_SIZE: usize = 64 * 1024;
+/// Default number of URBs in flight
+pub const DEFAULT_URB_DEPTH: usize = 4;
+
+// Written by the completion callback, read once the URB is reaped
+#[derive(Default)]
+struct UrbState {
+    done: c_int,
+    status: c_int,
+    actual_length: c_int,
+}
+
+struct Urb {
+    transfer: *mut ffi::libusb_transfer,
+    // Heap-allocated so the address handed to libusb stays put; only
+    // accessed through this pointer since the callback writes to it
+    state: *mut UrbState,
+}
+
+impl Drop for Urb {
+    fn drop(&mut self) {
+        // Never in flight here: send_frame() reaps every URB it submits
+        unsafe {
+            ffi::libusb_free_transfer(self.transfer);
+            drop(Box::from_raw(self.state));
+        }
+    }
+}
+
+extern "system" fn urb_complete(transfer: *mut ffi::libusb_transfer) {
+    unsafe {
+        let state = &mut *((*transfer).user_data as *mut UrbState);
+        state.status = (*transfer).status;
+        state.actual_length = (*transfer).actual_length;
+        state.done = 1;
+    }
+}
+
+/// Outcome of one frame's transfer
+#[derive(Debug, Clone, Copy)]
+pub struct TransferReport {
+    pub bytes: usize,
+    pub urbs: usize,
+    pub elapsed: Duration,
+}
+
+/// Totals since the engine was created
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TransferStats {
+    pub frames: u64,
+    pub failed_frames: u64,
+    pub bytes: u64,
+    pub urbs: u64,
+}
+
+impl fmt::Display for TransferStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frames={} failed={} bytes={} urbs={}",
+            self.frames, self.failed_frames, self.bytes, self.urbs
+        )
+    }
+}
+
+/// Bulk OUT URB slots the engine cycles through
+///
+/// A submitted chunk must stay valid until its slot has been waited for;
+/// the engine waits for every slot it submits before returning.
+pub trait UrbQueue: Send + 'static {
+    /// Start sending `chunk` from `slot`
+    fn submit(&mut self, slot: usize, chunk: &[u8]) -> Result<(), String>;
+
+    /// Block until the URB in `slot` completes, returning the bytes written
+    fn wait(&mut self, slot: usize) -> Result<usize, String>;
+
+    /// Ask for the URB in `slot` to be cancelled; it still has to be waited for
+    fn cancel(&mut self, slot: usize);
+}
+
+/// libusb transfers on one bulk OUT endpoint
+pub struct LibusbQueue {
+    usb_handle: Arc<Mutex<DeviceHandle<rusb::Context>>>,
+    device: *mut ffi::libusb_device_handle,
+    context: *mut ffi::libusb_context,
+    endpoint: u8,
+    timeout_ms: u32,
+    urbs: Vec<Urb>,
+}
+
+// The raw transfers are only touched from the thread that owns the engine
+unsafe impl Send for LibusbQueue {}
+
+impl LibusbQueue {
+    pub fn new(
+        usb_handle: Arc<Mutex<DeviceHandle<rusb::Context>>>,
+        endpoint: u8,
+        timeout: Duration,
+    ) -> Self {
+        // Both stay valid for as long as we hold the handle
+        let (device, context) = {
+            let handle = usb_handle.lock().unwrap();
+            (handle.as_raw(), handle.context().as_raw())
+        };
+        LibusbQueue {
+            usb_handle,
+            device,
+            context,
+            endpoint,
+            timeout_ms: timeout.as_millis().min(u32::MAX as u128) as u32,
+            urbs: Vec::new(),
+        }
+    }
+
+    fn urb(&mut self, slot: usize) -> Result<&Urb, String> {
+        while self.urbs.len() <= slot {
+            let transfer = unsafe { ffi::libusb_alloc_transfer(0) };
+            if transfer.is_null() {
+                return Err("Failed to allocate USB transfer".to_string());
+            }
+            self.urbs.push(Urb {
+                transfer,
+                state: Box::into_raw(Box::default()),
+            });
+        }
+        Ok(&self.urbs[slot])
+    }
+}
+
+impl UrbQueue for LibusbQueue {
+    fn submit(&mut self, slot: usize, chunk: &[u8]) -> Result<(), String> {
+        let (device, endpoint, timeout_ms) = (self.device, self.endpoint, self.timeout_ms);
+        let urb = self.urb(slot)?;
+        let (transfer, state) = (urb.transfer, urb.state);
+        // DDC/CI control transfers get the handle between URBs
+        let _handle = self.usb_handle.lock().unwrap();
+        let rc = unsafe {
+            *state = UrbState::default();
+            // Bulk OUT: libusb only reads the buffer
+            ffi::libusb_fill_bulk_transfer(
+                transfer,
+                device,
+                endpoint,
+                chunk.as_ptr() as *mut u8,
+                chunk.len() as c_int,
+                urb_complete,
+                state as *mut c_void,
+                timeout_ms,
+            );
+            ffi::libusb_submit_transfer(transfer)
+        };
+        match rc {
+            0 => Ok(()),
+            rc => Err(error_name(rc)),
+        }
+    }
+
+    fn wait(&mut self, slot: usize) -> Result<usize, String> {
+        let state = unsafe {
+            let state = self.urbs[slot].state;
+            let done = ptr::addr_of_mut!((*state).done);
+            while done.read_volatile() == 0 {
+                let rc = ffi::libusb_handle_events_completed(self.context, done);
+                if rc < 0 && rc != LIBUSB_ERROR_INTERRUPTED {
+                    eprintln!("USB event handling failed: {}", error_name(rc));
+                }
+            }
+            &*state
+        };
+        if state.status != LIBUSB_TRANSFER_COMPLETED {
+            return Err(transfer_status_name(state.status).to_string());
+        }
+        Ok(state.actual_length as usize)
+    }
+
+    fn cancel(&mut self, slot: usize) {
+        unsafe { ffi::libusb_cancel_transfer(self.urbs[slot].transfer) };
+    }
+}
+
+/// Multi-URB bulk OUT engine for one endpoint
+pub struct TransferEngine<Q = LibusbQueue> {
+    queue: Q,
+    bulk_out: Arc<Mutex<()>>,
+    urb_size: usize,
+    depth: usize,
+    stats: TransferStats,
+}
+
+impl TransferEngine<LibusbQueue> {
+    pub fn new(
+        usb_handle: Arc<Mutex<DeviceHandle<rusb::Context>>>,
+        bulk_out: Arc<Mutex<()>>,
+        endpoint: u8,
+        timeout: Duration,
+        urb_size: usize,
+        depth: usize,
+    ) -> Self {
+        TransferEngine::with_queue(
+            LibusbQueue::new(usb_handle, endpoint, timeout),
+            bulk_out,
+            urb_size,
+            depth,
+        )
+    }
+}
+
+impl<Q: UrbQueue> TransferEngine<Q> {
+    pub fn with_queue(queue: Q, bulk_out: Arc<Mutex<()>>, urb_size: usize, depth: usize) -> Self {
+        TransferEngine {
+            queue,
+            bulk_out,
+            urb_size: urb_size.max(1),
+            depth: depth.max(1),
+            stats: TransferStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> TransferStats {
+        self.stats
+    }
+
+    /// Send one frame's command stream and wait for every URB to complete
+    ///
+    /// On the first failed URB the rest are cancelled, so the device never
+    /// sees a frame with a hole in the middle.
+    pub fn send_frame(&mut self, data: &[u8]) -> Result<TransferReport, String> {
+        let start = Instant::now();
+        let result = self.transfer(data);
+        match result {
+            Ok(urbs) => {
+                self.stats.frames += 1;
+                self.stats.bytes += data.len() as u64;
+                self.stats.urbs += urbs as u64;
+                Ok(TransferReport {
+                    bytes: data.len(),
+                    urbs,
+                    elapsed: start.elapsed(),
+                })
+            }
+            Err(e) => {
+                self.stats.failed_frames += 1;
+                Err(e)
+            }
+        }
+    }
+
+    // Returns the number of URBs used
+    fn transfer(&mut self, data: &[u8]) -> Result<usize, String> {
+        // Keeps register writes from other paths from landing in the middle
+        // of the frame
+        let _bulk_out = self.bulk_out.lock().unwrap();
+
+        let mut chunks = data.chunks(self.urb_size);
+        let mut submitted = 0;
+        let mut reaped = 0;
+        let mut error: Option<String> = None;
+
+        loop {
+            // Keep the queue full
+            while error.is_none() && submitted - reaped < self.depth {
+                let Some(chunk) = chunks.next() else {
+                    break;
+                };
+                match self.queue.submit(submitted % self.depth, chunk) {
+                    Ok(()) => submitted += 1,
+                    Err(e) => error = Some(format!("URB {} submit failed: {}", submitted, e)),
+                }
+            }
+            if reaped == submitted {
+                break;
+            }
+
+            // URBs complete in order on a bulk endpoint; wait for the oldest
+            let result = self.queue.wait(reaped % self.depth);
+            if error.is_none() {
+                let length = data.len().min((reaped + 1) * self.urb_size) - reaped * self.urb_size;
+                error = match result {
+                    Err(status) => Some(format!("URB {} failed: {}", reaped, status)),
+                    Ok(written) if written < length => Some(format!(
+                        "URB {} short write: {} of {} bytes",
+                        reaped, written, length
+                    )),
+                    Ok(_) => None,
+                };
+                if error.is_some() {
+                    // Cancel the rest; they are still reaped above
+                    for index in reaped + 1..submitted {
+                        self.queue.cancel(index % self.depth);
+                    }
+                }
+            }
+            reaped += 1;
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(submitted),
+        }
+    }
+}
+
+impl<Q: UrbQueue> FrameSink for TransferEngine<Q> {
+    fn send(&mut self, data: &[u8]) -> Result<TransferReport, String> {
+        self.send_frame(data)
+    }
+
+    fn stats(&self) -> TransferStats {
+        self.stats
+    }
+}
+
+fn error_name(code: c_int) -> String {
+    let name = unsafe { ffi::libusb_error_name(code) };
+    if name.is_null() {
+        return format!("error {}", code);
+    }
+    unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Human-readable libusb transfer status
+pub fn transfer_status_name(status: c_int) -> &'static str {
+    match status {
+        LIBUSB_TRANSFER_COMPLETED => "completed",
+        LIBUSB_TRANSFER_ERROR => "error",
+        LIBUSB_TRANSFER_TIMED_OUT => "timed out",
+        LIBUSB_TRANSFER_CANCELLED => "cancelled",
+        LIBUSB_TRANSFER_STALL => "endpoint stalled",
+        LIBUSB_TRANSFER_NO_DEVICE => "device disconnected",
+        LIBUSB_TRANSFER_OVERFLOW => "overflow",
+        _ => "unknown status",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // Records what the engine does; URBs complete in submission order
+    #[derive(Default)]
+    struct FakeQueue {
+        in_flight: VecDeque<(usize, Vec<u8>)>,
+        written: Vec<u8>,
+        slots: Vec<usize>,
+        max_in_flight: usize,
+        cancelled: Vec<usize>,
+        urbs: usize,
+        // URB numbers counted across frames
+        reject: Option<usize>,
+        fail: Option<usize>,
+        short: Option<usize>,
+    }
+
+    impl UrbQueue for FakeQueue {
+        fn submit(&mut self, slot: usize, chunk: &[u8]) -> Result<(), String> {
+            if self.reject == Some(self.urbs) {
+                return Err("no device".to_string());
+            }
+            assert!(
+                self.in_flight.iter().all(|&(s, _)| s != slot),
+                "slot {} reused while in flight",
+                slot
+            );
+            self.urbs += 1;
+            self.slots.push(slot);
+            self.in_flight.push_back((slot, chunk.to_vec()));
+            self.max_in_flight = self.max_in_flight.max(self.in_flight.len());
+            Ok(())
+        }
+
+        fn wait(&mut self, slot: usize) -> Result<usize, String> {
+            let urb = self.urbs - self.in_flight.len();
+            let (oldest, data) = self.in_flight.pop_front().expect("nothing in flight");
+            assert_eq!(oldest, slot, "reaped out of order");
+            if self.cancelled.contains(&urb) {
+                return Err("cancelled".to_string());
+            }
+            if self.fail == Some(urb) {
+                return Err("endpoint stalled".to_string());
+            }
+            let written = if self.short == Some(urb) {
+                data.len() / 2
+            } else {
+                data.len()
+            };
+            self.written.extend_from_slice(&data[..written]);
+            Ok(written)
+        }
+
+        fn cancel(&mut self, slot: usize) {
+            let first = self.urbs - self.in_flight.len();
+            let index = self.in_flight.iter().position(|&(s, _)| s == slot).unwrap();
+            self.cancelled.push(first + index);
+        }
+    }
+
+    fn engine(queue: FakeQueue, urb_size: usize, depth: usize) -> TransferEngine<FakeQueue> {
+        TransferEngine::with_queue(queue, Arc::new(Mutex::new(())), urb_size, depth)
+    }
+
+    fn stream(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_keeps_depth_urbs_in_flight_and_reuses_slots() {
+        let mut engine = engine(FakeQueue::default(), 16, 3);
+        let data = stream(16 * 7 + 5);
+        let report = engine.send_frame(&data).unwrap();
+        assert_eq!(report.urbs, 8);
+        assert_eq!(report.bytes, data.len());
+        assert_eq!(engine.queue.max_in_flight, 3);
+        assert_eq!(engine.queue.slots, vec![0, 1, 2, 0, 1, 2, 0, 1]);
+        // Every URB is reaped before the frame is reported, in stream order
+        assert!(engine.queue.in_flight.is_empty());
+        assert_eq!(engine.queue.written, data);
+    }
+
+    #[test]
+    fn test_short_frame_uses_fewer_urbs_than_depth() {
+        let mut engine = engine(FakeQueue::default(), 64, 4);
+        assert_eq!(engine.send_frame(&stream(100)).unwrap().urbs, 2);
+        assert_eq!(engine.queue.max_in_flight, 2);
+        assert_eq!(engine.send_frame(&[]).unwrap().urbs, 0);
+    }
+
+    #[test]
+    fn test_failed_urb_cancels_the_rest() {
+        let queue = FakeQueue {
+            fail: Some(1),
+            ..FakeQueue::default()
+        };
+        let mut engine = engine(queue, 16, 4);
+        let err = engine.send_frame(&stream(16 * 6)).unwrap_err();
+        assert_eq!(err, "URB 1 failed: endpoint stalled");
+        // URB 4 refilled URB 0's slot; nothing is submitted after the error
+        assert_eq!(engine.queue.cancelled, vec![2, 3, 4]);
+        assert_eq!(engine.queue.urbs, 5);
+        assert!(engine.queue.in_flight.is_empty());
+        assert_eq!(engine.queue.written, stream(16));
+    }
+
+    #[test]
+    fn test_short_write_fails_the_frame() {
+        let queue = FakeQueue {
+            short: Some(0),
+            ..FakeQueue::default()
+        };
+        let mut engine = engine(queue, 16, 2);
+        let err = engine.send_frame(&stream(40)).unwrap_err();
+        assert_eq!(err, "URB 0 short write: 8 of 16 bytes");
+        assert_eq!(engine.queue.cancelled, vec![1]);
+    }
+
+    #[test]
+    fn test_submit_error_reaps_submitted_urbs() {
+        let queue = FakeQueue {
+            reject: Some(2),
+            ..FakeQueue::default()
+        };
+        let mut engine = engine(queue, 16, 4);
+        let err = engine.send_frame(&stream(16 * 5)).unwrap_err();
+        assert_eq!(err, "URB 2 submit failed: no device");
+        assert!(engine.queue.in_flight.is_empty());
+        assert_eq!(engine.queue.written, stream(32));
+    }
+
+    #[test]
+    fn test_stats_count_frames_and_failures() {
+        let queue = FakeQueue {
+            fail: Some(3),
+            ..FakeQueue::default()
+        };
+        let mut engine = engine(queue, 16, 2);
+        engine.send_frame(&stream(48)).unwrap();
+        assert!(engine.send_frame(&stream(48)).is_err());
+        // The engine recovers on the next frame
+        engine.send_frame(&stream(20)).unwrap();
+        let stats = FrameSink::stats(&engine);
+        assert_eq!(stats.frames, 2);
+        assert_eq!(stats.failed_frames, 1);
+        assert_eq!(stats.bytes, 68);
+        assert_eq!(stats.urbs, 5);
+    }
+
+    #[test]
+    fn test_transfer_status_names() {
+        assert_eq!(transfer_status_name(LIBUSB_TRANSFER_COMPLETED), "completed");
+        assert_eq!(
+            transfer_status_name(LIBUSB_TRANSFER_NO_DEVICE),
+            "device disconnected"
+        );
+        assert_eq!(transfer_status_name(42), "unknown status");
+    }
+
+    #[test]
+    fn test_stats_display() {
+        let stats = TransferStats {
+            frames: 3,
+            failed_frames: 1,
+            bytes: 4096,
+            urbs: 5,
+        };
+        assert_eq!(stats.to_string(), "frames=3 failed=1 bytes=4096 urbs=5");
+    }
+}